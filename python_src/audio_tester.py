@@ -1,7 +1,8 @@
-import rust_audio_tester
+from rust_audio_tester import Analyser
 import kivy
 kivy.require('1.11.1')
 from kivy.app import App
+from kivy.clock import Clock
 from kivy.uix.tabbedpanel import TabbedPanel
 from kivy.properties import StringProperty
 from kivy.uix.textinput import TextInput
@@ -14,24 +15,41 @@ class Tabbed(TabbedPanel):
     recordedPeakFrequency = StringProperty("--- Hz")
 
     frequency = 1000
+    measurement = None
 
     def __init__(self, **kwargs):
         super(Tabbed, self).__init__(**kwargs)
 
     def doWork(self):
+        # Pressing the button again while it's measuring cancels the measurement
+        if self.measurement is not None:
+            self.measurement.cancel()
+            return
         testFrequency = 1000
         try:
             testFrequency = int(self.frequency)
         except:
             testFrequency = 1000
-        rust_audio_tester.set_frequency(testFrequency)
-        rust_audio_tester.process_audio()
-
-        self.rmsGain = '{:3.4f} dB'.format(rust_audio_tester.get_rms_gain())
-        self.generatedTHD = '{:3.4f} dB'.format(rust_audio_tester.get_generated_thd())
-        self.generatedPeakFrequency = '{:6.0f} Hz'.format(rust_audio_tester.get_generated_peak_frequency())
-        self.recordedTHD = '{:3.4f} dB'.format(rust_audio_tester.get_recorded_thd())
-        self.recordedPeakFrequency = '{:6.0f} Hz'.format(rust_audio_tester.get_recorded_peak_frequency())
+        analyser = Analyser(config_file='audio_analyser.toml', frequency=testFrequency)
+        self.measurement = analyser.start_thdn()
+        Clock.schedule_interval(self.checkWork, 0.1)
+
+    def checkWork(self, dt):
+        if not self.measurement.done():
+            return True
+        measurement, self.measurement = self.measurement, None
+        try:
+            result = measurement.result()
+        except Exception as e:
+            print('Measurement failed:', e)
+            return False
+
+        self.rmsGain = '{:3.4f} dB'.format(result['gain_db'])
+        self.generatedTHD = '{:3.4f} dB'.format(result['generated']['thd'])
+        self.generatedPeakFrequency = '{:6.0f} Hz'.format(result['generated']['peak_frequency'])
+        self.recordedTHD = '{:3.4f} dB'.format(result['thd'])
+        self.recordedPeakFrequency = '{:6.0f} Hz'.format(result['peak_frequency'])
+        return False
 
     def processFrequencyTest(self, text):
         self.frequency = text
@@ -1,11 +1,13 @@
-import rust_audio_tester
+from rust_audio_tester import Analyser
 
-rust_audio_tester.set_frequency(15000)
+analyser = Analyser(config_file='audio_analyser.toml', frequency=15000)
+result = analyser.thdn()
 
-rust_audio_tester.process_audio()
-
-print('Python: RMS Gain =                   ' + '{:.2f}'.format(rust_audio_tester.get_rms_gain()) + ' dB')
-print('Python: Generated THD =              ' + '{:.4f}'.format(rust_audio_tester.get_generated_thd()) + ' %')
-print('Python: Generated Peak Frequency =   ' + '{:.0f}'.format(rust_audio_tester.get_generated_peak_frequency()) + ' Hz')
-print('Python: Recorded THD =               ' + '{:.4f}'.format(rust_audio_tester.get_recorded_thd()) + ' %')
-print('Python: Recorded Peak Frequency =    ' + '{:.0f}'.format(rust_audio_tester.get_recorded_peak_frequency()) + ' Hz')
+print('Python: RMS Gain =                   ' + '{:.2f}'.format(result['gain_db']) + ' dB')
+print('Python: Generated THD =              ' + '{:.4f}'.format(result['generated']['thd']) + ' %')
+print('Python: Generated Peak Frequency =   ' + '{:.0f}'.format(result['generated']['peak_frequency']) + ' Hz')
+print('Python: Recorded THD =               ' + '{:.4f}'.format(result['thd']) + ' %')
+print('Python: Recorded Peak Frequency =    ' + '{:.0f}'.format(result['peak_frequency']) + ' Hz')
+for channel in result['channels']:
+    print('Python: Input {} harmonics =          '.format(channel['input'])
+          + ', '.join('{:.1f}'.format(level) for level in channel['harmonics_dbc']) + ' dBc')
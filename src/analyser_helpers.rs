@@ -0,0 +1,593 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::DeviceTrait;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::audio_helpers::{self, CaptureReport};
+use crate::config_helpers::{self, Config, SimulationSettings};
+use crate::crosstalk_helpers::{self, Crosstalk};
+use crate::device_helpers::{self, Direction};
+use crate::error_helpers::{Error, Result};
+use crate::job_helpers::{self, Measurement};
+use crate::level_helpers::{self, Level, LevelUnit};
+use crate::measurement_helpers::{self, FileAnalysis, ThdnResult};
+use crate::noise_helpers::{self, NoiseResult};
+use crate::plan_helpers::{self, TestPlan};
+use crate::report_helpers::{self, Reportable};
+use crate::response_helpers::ResponsePoint;
+use crate::signal_helpers::CustomStimulus;
+use crate::{array_helpers, artifact_helpers, calibration_helpers, loopback_helpers, range_helpers, response_helpers, result_helpers, signal_helpers};
+use crate::{stimulus_length, to_py_err};
+
+// An analyser with its own settings, given as keywords, e.g. Analyser(backend="simulated", frequency=1000, level="-10 dBV")
+// - It starts from the defaults, or from the config file given (the config file in use isn't read unless it's asked for)
+// - Its settings are applied before each measurement, so several analysers (or the module functions) don't get in each other's way
+// - Each measurement returns everything it found as a dict (or a list of them), rather than leaving it to be read back
+// - Measurements release the GIL while they run, and the start_ methods run them in the background (see Measurement)
+// The generator level can be a number (dBFS) or a string with its unit, e.g. "-6dBFS", "-10 dBV" or "4dBu"
+// The output calibration comes from the calibration file, unless output_full_scale_dbv is given
+#[pyclass(module = "rust_audio_tester")]
+pub struct Analyser {
+    settings: Settings,
+}
+
+// Everything a measurement needs from the analyser, copied for measurements that run in the background
+#[derive(Clone)]
+struct Settings {
+    config: Config,
+    frequency: usize,
+    level: Level,
+    output_full_scale_dbv: Option<f64>,
+    loopback_correction: bool,
+    stimulus: Option<CustomStimulus>,
+    // Each measurement gets its own run directory, unless the analyser is given a run ID
+    // The last one is kept (and shared with the background copies) so its files can be read back
+    last_run_id: Arc<Mutex<Option<String>>>,
+}
+
+#[pymethods]
+impl Analyser {
+    #[new]
+    #[args(config_file = "None", settings = "**")]
+    fn new(config_file: Option<&str>, settings: Option<&PyDict>) -> PyResult<Self> {
+        let config = match config_file {
+            Some(path) => Config::load(path).map_err(to_py_err)?,
+            None => Config::default(),
+        };
+        let mut analyser = Analyser {
+            settings: Settings {
+                config,
+                frequency: crate::DEFAULT_FREQUENCY,
+                level: Level::new(level_helpers::amplitude_to_dbfs(level_helpers::DEFAULT_AMPLITUDE), LevelUnit::Dbfs),
+                output_full_scale_dbv: None,
+                loopback_correction: false,
+                stimulus: None,
+                last_run_id: Arc::new(Mutex::new(None)),
+            },
+        };
+        if let Some(settings) = settings {
+            analyser.apply_settings(settings)?;
+        }
+        Ok(analyser)
+    }
+
+    // Change any of the settings given to the constructor
+    #[args(settings = "**")]
+    fn configure(&mut self, settings: Option<&PyDict>) -> PyResult<()> {
+        match settings {
+            Some(settings) => self.apply_settings(settings),
+            None => Ok(()),
+        }
+    }
+
+    // Store the analyser's settings in a config file, so they can be loaded again (or used by the command line)
+    #[args(path = "\"audio_analyser.toml\"")]
+    fn save_config(&self, path: &str) -> PyResult<()> {
+        self.settings.config.save(path).map_err(to_py_err)
+    }
+
+    // The full names of the input and output devices the analyser uses
+    fn device_names(&self, py: Python) -> PyResult<(String, String)> {
+        self.run(py, |_| audio_helpers::device_names())
+    }
+
+    // A single tone THD+N measurement
+    // Auto-ranges first and backs off on clipping, if those are set, and takes the loopback profile off if loopback_correction is set
+    fn thdn(&self, py: Python) -> PyResult<PyObject> {
+        let result = self.run(py, Settings::thdn)?;
+        thdn_dict(py, result)
+    }
+
+    // A stepped sine frequency response, at third octave frequencies unless others are given
+    // One dict per frequency, with the loopback profile taken off if loopback_correction is set
+    #[args(frequencies = "None")]
+    fn response(&self, py: Python, frequencies: Option<Vec<usize>>) -> PyResult<PyObject> {
+        let response = self.run(py, |settings| settings.response(frequencies))?;
+        response_list(py, response)
+    }
+
+    // Play the tone on each output in turn, one dict per output with the level it put on each input
+    fn crosstalk(&self, py: Python) -> PyResult<PyObject> {
+        let crosstalk = self.run(py, |_| crosstalk_helpers::measure_crosstalk())?;
+        crosstalk_list(py, crosstalk)
+    }
+
+    // The noise floor of each input, recorded with the generator silent
+    fn noise(&self, py: Python) -> PyResult<PyObject> {
+        let noise = self.run(py, |_| noise_helpers::measure_noise())?;
+        noise_dict(py, noise)
+    }
+
+    // Run a measurement ("thdn", "response", "crosstalk" or "noise") and return its JSON report, writing it to the file too if one is given
+    // A measurement that fails with an error is reported (with passed set to false) rather than raising it
+    #[args(path = "None")]
+    fn report(&self, py: Python, measurement: &str, path: Option<&str>) -> PyResult<String> {
+        self.run(py, |settings| match measurement {
+            "thdn" => report_json(|| settings.thdn(), path),
+            "response" => report_json(|| settings.response(None), path),
+            "crosstalk" => report_json(crosstalk_helpers::measure_crosstalk, path),
+            "noise" => report_json(noise_helpers::measure_noise, path),
+            _ => fail!(Format, "Unknown measurement '{}', expected thdn, response, crosstalk or noise", measurement),
+        })
+    }
+
+    // Run the steps of a TOML or YAML test plan on top of the analyser's settings, returning the combined JSON report
+    // (and writing it to the file too if one is given)
+    #[args(path = "None")]
+    fn run_plan(&self, py: Python, plan: &str, path: Option<&str>) -> PyResult<String> {
+        self.run(py, |_| plan_json(plan, path))
+    }
+
+    // Measure the interface's own response with its output looped back to its input, and save it for loopback_correction
+    fn capture_loopback(&self, py: Python) -> PyResult<PyObject> {
+        let profile = self.run(py, |_| loopback_helpers::capture_loopback())?;
+        response_list(py, profile.points)
+    }
+
+    // Find the generator level that puts the recording at the target headroom, and keep it as the analyser's level
+    fn auto_range(&mut self, py: Python) -> PyResult<PyObject> {
+        let result = self.run(py, |_| range_helpers::auto_range())?;
+        self.settings.level = Level::new(result.level_dbfs, LevelUnit::Dbfs);
+        let dict = PyDict::new(py);
+        dict.set_item("level_dbfs", result.level_dbfs)?;
+        dict.set_item("peak_dbfs", result.peak_dbfs)?;
+        dict.set_item("steps", result.steps)?;
+        Ok(dict.to_object(py))
+    }
+
+    // Calibrate the inputs against a reference of a known voltage, returning the offset of each input that saw it (in dB)
+    fn calibrate_input(&self, py: Python, reference_vrms: f64) -> PyResult<Vec<(u16, f64)>> {
+        self.run(py, |_| calibration_helpers::calibrate_input(reference_vrms))
+    }
+
+    // Calibrate an output (the first one the generator plays on, unless another is given) from the voltage measured
+    // while playing at the analyser's level, returning the offset (in dB)
+    #[args(channel = "None")]
+    fn calibrate_output(&self, py: Python, measured_vrms: f64, channel: Option<u16>) -> PyResult<f64> {
+        let channel = channel.unwrap_or_else(|| self.settings.config.channels.first_output());
+        self.run(py, |_| calibration_helpers::calibrate_output(channel, level_helpers::level_dbfs(), measured_vrms))
+    }
+
+    // Play the stimulus without recording, returning the playback's sample counts
+    #[args(seconds = "4.0")]
+    fn play(&self, py: Python, seconds: f64) -> PyResult<PyObject> {
+        let length = stimulus_length(seconds).map_err(to_py_err)?;
+        let report = self.run(py, |_| audio_helpers::play_stimulus(length))?;
+        capture_object(py, report)
+    }
+
+    // The same measurements started in the background, each returning a Measurement straight away
+    // Its result() is what the blocking method returns, so a UI can poll done() and stay responsive
+    fn start_thdn(&self) -> Measurement {
+        self.start(Settings::thdn, thdn_dict)
+    }
+
+    #[args(frequencies = "None")]
+    fn start_response(&self, frequencies: Option<Vec<usize>>) -> Measurement {
+        self.start(|settings| settings.response(frequencies), response_list)
+    }
+
+    fn start_crosstalk(&self) -> Measurement {
+        self.start(|_| crosstalk_helpers::measure_crosstalk(), crosstalk_list)
+    }
+
+    fn start_noise(&self) -> Measurement {
+        self.start(|_| noise_helpers::measure_noise(), noise_dict)
+    }
+
+    #[args(path = "None")]
+    fn start_plan(&self, plan: String, path: Option<String>) -> Measurement {
+        self.start(move |_| plan_json(&plan, path.as_deref()), |py, json| Ok(json.to_object(py)))
+    }
+
+    fn start_capture_loopback(&self) -> Measurement {
+        self.start(|_| loopback_helpers::capture_loopback().map(|profile| profile.points), response_list)
+    }
+
+    #[args(seconds = "4.0")]
+    fn start_play(&self, seconds: f64) -> Measurement {
+        self.start(move |_| audio_helpers::play_stimulus(stimulus_length(seconds)?), capture_object)
+    }
+
+    // Play an array of samples (at the given sample rate) in place of the generator's sine, or the sine again if it's None
+    #[args(samples = "None", sample_rate = "48000")]
+    fn set_stimulus(&mut self, py: Python, samples: Option<&PyAny>, sample_rate: u32) -> PyResult<()> {
+        self.settings.stimulus = array_helpers::to_stimulus(py, samples, sample_rate)?;
+        Ok(())
+    }
+
+    // The audio from the last capture as NumPy arrays, the recording is (channels, frames)
+    fn generated(&self, py: Python) -> PyResult<PyObject> {
+        self.read(|| array_helpers::generated(py))
+    }
+
+    fn recorded(&self, py: Python) -> PyResult<PyObject> {
+        self.read(|| array_helpers::recorded(py))
+    }
+
+    fn reference(&self, py: Python) -> PyResult<Option<PyObject>> {
+        self.read(|| array_helpers::reference(py))
+    }
+
+    // The spectra the last measurement was worked out from, as arrays of frequencies and amplitudes (relative to full scale)
+    fn spectra(&self, py: Python) -> PyResult<PyObject> {
+        self.read(|| array_helpers::spectra(py))
+    }
+
+    // Analyse an array of samples the same way as a measurement, with the THD+N window sized for the analyser's frequency
+    // The FFT window is the analyser's, not whichever was last applied to the shared config
+    fn analyse(&self, py: Python, samples: &PyAny, sample_rate: u32) -> PyResult<PyObject> {
+        array_helpers::analyse(py, samples, sample_rate, Some(self.settings.frequency), self.settings.config.analysis.window)
+    }
+
+    // Analyse every channel of a WAV file, with the THD+N window sized for the analyser's frequency
+    fn analyse_file(&self, py: Python, path: &str) -> PyResult<PyObject> {
+        let analysis = measurement_helpers::analyse_file(path, Some(self.settings.frequency), self.settings.config.analysis.window).map_err(to_py_err)?;
+        file_analysis_dict(py, analysis)
+    }
+
+    // Write the stimulus to a WAV file, returning the number of frames written
+    // The bit depth is "16", "24", "32" or "32f"
+    #[args(seconds = "4.0", bit_depth = "\"32f\"")]
+    fn render(&self, py: Python, path: &str, seconds: f64, bit_depth: &str) -> PyResult<usize> {
+        let bit_depth = bit_depth.parse().map_err(to_py_err)?;
+        let length = stimulus_length(seconds).map_err(to_py_err)?;
+        self.run(py, |settings| signal_helpers::render_file(path, &settings.config, bit_depth, length))
+    }
+}
+
+impl Analyser {
+    fn apply_settings(&mut self, settings: &PyDict) -> PyResult<()> {
+        for (key, value) in settings.iter() {
+            let key: &str = key.extract()?;
+            self.apply_setting(key, value)?;
+        }
+        Ok(())
+    }
+
+    // The settings are named as they are in the config file, with the section dropped
+    fn apply_setting(&mut self, key: &str, value: &PyAny) -> PyResult<()> {
+        let settings = &mut self.settings;
+        let config = &mut settings.config;
+        match key {
+            "host" => {
+                config.host = value.extract()?;
+                device_helpers::host(config).map_err(to_py_err)?;
+            },
+            "backend" => config.backend = value.extract::<&str>()?.parse().map_err(to_py_err)?,
+            "input_device" => config.input_device = device_name(config, value.extract()?, Direction::Input)?,
+            "output_device" => config.output_device = device_name(config, value.extract()?, Direction::Output)?,
+            "sample_rate" => config.stream.sample_rate = value.extract()?,
+            "sample_format" => {
+                let sample_format: Option<&str> = value.extract()?;
+                config.stream.sample_format = sample_format.map(|f| f.parse()).transpose().map_err(to_py_err)?;
+            },
+            "input_channels" => config.stream.input_channels = value.extract()?,
+            "output_channels" => config.stream.output_channels = value.extract()?,
+            "buffer_size" => config.stream.buffer_size = value.extract()?,
+            "outputs" => config.channels.outputs = value.extract()?,
+            "inputs" => config.channels.inputs = value.extract()?,
+            "reference" => config.channels.reference = value.extract()?,
+            "duration_s" => {
+                let duration_s: Option<f64> = value.extract()?;
+                config.capture.duration_s = duration_s.map(audio_helpers::check_duration).transpose().map_err(to_py_err)?;
+            },
+            "tail_ms" => config.capture.tail_ms = value.extract()?,
+            "on_clip" => {
+                let action: Option<&str> = value.extract()?;
+                config.capture.on_clip = action.map(|a| a.parse()).transpose().map_err(to_py_err)?;
+            },
+            "clip_back_off_db" => config.capture.clip_back_off_db = value.extract()?,
+            "capture_only" => config.capture.external = value.extract()?,
+            "stimulus_wav" => config.capture.stimulus_wav = value.extract()?,
+            "auto_range" => config.ranging.enabled = value.extract()?,
+            "target_headroom_db" => config.ranging.target_headroom_db = value.extract()?,
+            "min_level_dbfs" => config.ranging.min_level_dbfs = value.extract()?,
+            "max_level_dbfs" => config.ranging.max_level_dbfs = value.extract()?,
+            "window" => config.analysis.window = value.extract::<&str>()?.parse().map_err(to_py_err)?,
+            "output_directory" => config.output.directory = value.extract()?,
+            "run_id" => config.output.run_id = value.extract()?,
+            "in_memory" => config.output.in_memory = value.extract()?,
+            "golden_file" => config.golden.file = value.extract()?,
+            "compare_golden" => config.golden.compare = value.extract()?,
+            "save_golden" => config.golden.save = value.extract()?,
+            "jack_outputs" => config.jack.outputs = value.extract()?,
+            "jack_inputs" => config.jack.inputs = value.extract()?,
+            "simulation" => config.simulation = simulation_settings(value.downcast()?)?,
+            "frequency" => settings.frequency = value.extract()?,
+            "level" => {
+                settings.level = match value.extract::<&str>() {
+                    Ok(level) => level.parse().map_err(to_py_err)?,
+                    Err(_) => Level::new(value.extract()?, LevelUnit::Dbfs),
+                };
+            },
+            "output_full_scale_dbv" => settings.output_full_scale_dbv = value.extract()?,
+            "loopback_correction" => settings.loopback_correction = value.extract()?,
+            _ => return Err(to_py_err(Error::Format(format!("Unknown setting '{}'", key)))),
+        }
+        Ok(())
+    }
+
+    // Run a measurement with the analyser's settings, releasing the GIL until it's done
+    fn run<T: Send, F: Send + FnOnce(&Settings) -> Result<T>>(&self, py: Python, measurement: F) -> PyResult<T> {
+        let settings = &self.settings;
+        job_helpers::blocking(py, || settings.apply().and_then(|_| measurement(settings)))
+    }
+
+    // Start a measurement in the background, with a copy of the analyser's settings as they are now
+    fn start<T, F>(&self, measurement: F, convert: fn(Python, T) -> PyResult<PyObject>) -> Measurement
+    where
+        T: Send + 'static,
+        F: Send + 'static + FnOnce(&Settings) -> Result<T>,
+    {
+        let settings = self.settings.clone();
+        job_helpers::spawn(move || settings.apply().and_then(|_| measurement(&settings)), convert)
+    }
+
+    // Read back what the last measurement left, once it has finished, with the analyser's channel map in place
+    fn read<T, F: FnOnce() -> PyResult<T>>(&self, read: F) -> PyResult<T> {
+        job_helpers::exclusive(Default::default(), || {
+            let mut config = self.settings.config.clone();
+            config.output.automatic_run_id = self.settings.last_run_id.lock().unwrap().clone();
+            config_helpers::replace(config);
+            read()
+        })
+    }
+}
+
+impl Settings {
+    // Put the analyser's config, test frequency, stimulus and output calibration in place, then set its generator level
+    // A measurement without a run ID gets a new one, so it doesn't write over the last measurement's files
+    fn apply(&self) -> Result<()> {
+        let mut config = self.config.clone();
+        if config.output.run_id.is_none() {
+            let run_id = artifact_helpers::new_run_id();
+            *self.last_run_id.lock().unwrap() = Some(run_id.clone());
+            config.output.automatic_run_id = Some(run_id);
+        }
+        config_helpers::replace(config);
+        crate::FREQUENCY.store(self.frequency, Ordering::SeqCst);
+        signal_helpers::set_custom_stimulus(self.stimulus.clone());
+        match self.output_full_scale_dbv {
+            Some(full_scale_dbv) => level_helpers::set_output_full_scale_dbv(full_scale_dbv),
+            None => {
+                level_helpers::clear_output_full_scale();
+                calibration_helpers::load_calibration()?;
+            },
+        }
+        level_helpers::set_level(self.level)
+    }
+
+    fn thdn(&self) -> Result<ThdnResult> {
+        let profile = if self.loopback_correction { Some(loopback_helpers::load_loopback()?) } else { None };
+        measurement_helpers::measure_thdn(profile.as_ref())
+    }
+
+    fn response(&self, frequencies: Option<Vec<usize>>) -> Result<Vec<ResponsePoint>> {
+        let frequencies = frequencies.unwrap_or_else(|| response_helpers::THIRD_OCTAVE_FREQUENCIES.to_vec());
+        let profile = if self.loopback_correction { Some(loopback_helpers::load_loopback()?) } else { None };
+        let response = response_helpers::measure_response(&frequencies)?;
+        Ok(response.iter()
+            .map(|point| match &profile {
+                Some(profile) => profile.correct(point),
+                None => *point,
+            })
+            .collect())
+    }
+}
+
+fn report_json<T: Reportable, F: FnOnce() -> Result<T>>(measurement: F, path: Option<&str>) -> Result<String> {
+    let report = report_helpers::run(measurement)?;
+    if let Some(path) = path {
+        report.write(Some(path))?;
+    }
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+fn plan_json(plan: &str, path: Option<&str>) -> Result<String> {
+    let report = plan_helpers::run_plan(&TestPlan::load(plan)?)?;
+    if let Some(path) = path {
+        report_helpers::write(&report, Some(path))?;
+    }
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+// Devices are selected by index, name or part of the name on the analyser's host, and stored by their full name
+fn device_name(config: &Config, selector: Option<&str>, direction: Direction) -> PyResult<Option<String>> {
+    let find = |selector| -> Result<String> {
+        let host = device_helpers::host(config)?;
+        Ok(device_helpers::find_device(&host, selector, direction)?.name()?)
+    };
+    selector.map(find).transpose().map_err(to_py_err)
+}
+
+// The virtual DUT, with the same keys as the [simulation] section of the config file
+fn simulation_settings(settings: &PyDict) -> PyResult<SimulationSettings> {
+    let mut simulation = SimulationSettings::default();
+    for (key, value) in settings.iter() {
+        match key.extract::<&str>()? {
+            "gain_db" => simulation.gain_db = value.extract()?,
+            "delay_ms" => simulation.delay_ms = value.extract()?,
+            "lowpass_hz" => simulation.lowpass_hz = value.extract()?,
+            "highpass_hz" => simulation.highpass_hz = value.extract()?,
+            "polynomial" => simulation.polynomial = value.extract()?,
+            "noise_dbfs" => simulation.noise_dbfs = value.extract()?,
+            "crosstalk" => simulation.crosstalk = value.extract()?,
+            "seed" => simulation.seed = value.extract()?,
+            key => return Err(to_py_err(Error::Format(format!("Unknown simulation setting '{}'", key)))),
+        }
+    }
+    Ok(simulation)
+}
+
+// The sample counts (in frames) of a capture and any problems with it
+// Discontinuities are given as (input, frame)
+pub fn capture_dict<'py>(py: Python<'py>, report: &CaptureReport) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("sample_rate", report.sample_rate)?;
+    dict.set_item("stimulus_frames", report.stimulus_frames)?;
+    dict.set_item("tail_frames", report.tail_frames)?;
+    dict.set_item("played_frames", report.played_frames)?;
+    dict.set_item("recorded_frames", report.recorded_frames)?;
+    dict.set_item("input_xruns", report.input_xruns)?;
+    dict.set_item("output_xruns", report.output_xruns)?;
+    dict.set_item("dropped_samples", report.dropped_samples)?;
+    dict.set_item("stream_errors", &report.stream_errors)?;
+    dict.set_item("discontinuities", &report.discontinuities)?;
+    dict.set_item("valid", report.problems().is_empty())?;
+    Ok(dict)
+}
+
+// One dict per recorded channel, labelled with its physical input number
+// The level is given in dBFS, and in dBV and Vrms if the input has been calibrated (along with the THD+N residual in µV)
+pub fn channel_dicts(py: Python, results: &[result_helpers::ChannelResult], levels_dbv: &[Option<f64>]) -> PyResult<Vec<PyObject>> {
+    let mut channels = Vec::new();
+    for (result, &level_dbv) in results.iter().zip(levels_dbv) {
+        let dict = PyDict::new(py);
+        dict.set_item("input", result.input)?;
+        dict.set_item("level_dbfs", result.level_dbfs)?;
+        dict.set_item("level_dbv", level_dbv)?;
+        dict.set_item("level_vrms", level_dbv.map(level_helpers::dbv_to_volts))?;
+        dict.set_item("gain_db", result.gain_db)?;
+        dict.set_item("peak_frequency", result.peak_frequency)?;
+        dict.set_item("thd", result.thd)?;
+        dict.set_item("thdn_residual_uv", level_dbv.map(|level_dbv| level_helpers::residual_microvolts(level_dbv, result.thd)))?;
+        dict.set_item("harmonics_dbc", &result.harmonics)?;
+        dict.set_item("peak_dbfs", result.peak_dbfs)?;
+        dict.set_item("full_scale_run", result.full_scale_run)?;
+        dict.set_item("clipped", result.clipped)?;
+        channels.push(dict.to_object(py));
+    }
+    Ok(channels)
+}
+
+// The dict thdn() returns
+fn thdn_dict(py: Python, result: ThdnResult) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("frequency", result.frequency)?;
+    dict.set_item("level_dbfs", result.level_dbfs)?;
+    dict.set_item("level_dbv", result.level_dbv)?;
+    dict.set_item("gain_db", result.gain_db)?;
+    dict.set_item("thd", result.thd)?;
+    dict.set_item("peak_frequency", result.peak_frequency)?;
+
+    let generated = PyDict::new(py);
+    generated.set_item("thd", result.generated_thd)?;
+    generated.set_item("peak_frequency", result.generated_peak_frequency)?;
+    if let Some(check) = result.generated_clipping {
+        generated.set_item("peak_dbfs", check.peak_dbfs)?;
+        generated.set_item("full_scale_run", check.full_scale_run)?;
+        generated.set_item("clipped", check.clipped())?;
+    }
+    dict.set_item("generated", generated)?;
+    dict.set_item("channels", channel_dicts(py, &result.channels, &result.levels_dbv)?)?;
+    dict.set_item("capture", capture_dict(py, &result.capture)?)?;
+
+    let ranging = match result.ranging {
+        Some(ranging) => {
+            let ranging_dict = PyDict::new(py);
+            ranging_dict.set_item("level_dbfs", ranging.level_dbfs)?;
+            ranging_dict.set_item("peak_dbfs", ranging.peak_dbfs)?;
+            ranging_dict.set_item("steps", ranging.steps)?;
+            Some(ranging_dict)
+        },
+        None => None,
+    };
+    dict.set_item("auto_range", ranging)?;
+    let corrected = result.loopback_corrected.map(|point| response_dict(py, &point)).transpose()?;
+    dict.set_item("loopback_corrected", corrected)?;
+    Ok(dict.to_object(py))
+}
+
+fn capture_object(py: Python, report: CaptureReport) -> PyResult<PyObject> {
+    Ok(capture_dict(py, &report)?.to_object(py))
+}
+
+fn response_list(py: Python, points: Vec<ResponsePoint>) -> PyResult<PyObject> {
+    let dicts = points.iter().map(|point| response_dict(py, point).map(|dict| dict.to_object(py))).collect::<PyResult<Vec<_>>>()?;
+    Ok(dicts.to_object(py))
+}
+
+fn response_dict<'py>(py: Python<'py>, point: &ResponsePoint) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("frequency", point.frequency)?;
+    dict.set_item("gain_db", point.gain_db)?;
+    dict.set_item("thd", point.thd)?;
+    Ok(dict)
+}
+
+fn crosstalk_list(py: Python, crosstalk: Vec<Crosstalk>) -> PyResult<PyObject> {
+    let mut outputs = Vec::new();
+    for output in crosstalk {
+        let dict = PyDict::new(py);
+        dict.set_item("output", output.output)?;
+        dict.set_item("driven_input", output.driven_input)?;
+        let mut channels = Vec::new();
+        for channel in output.channels {
+            let channel_dict = PyDict::new(py);
+            channel_dict.set_item("input", channel.input)?;
+            channel_dict.set_item("level_dbfs", channel.level_dbfs)?;
+            channel_dict.set_item("crosstalk_db", channel.crosstalk_db)?;
+            channels.push(channel_dict.to_object(py));
+        }
+        dict.set_item("channels", channels)?;
+        outputs.push(dict.to_object(py));
+    }
+    Ok(outputs.to_object(py))
+}
+
+fn noise_dict(py: Python, noise: NoiseResult) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    let mut channels = Vec::new();
+    for channel in noise.channels {
+        let channel_dict = PyDict::new(py);
+        channel_dict.set_item("input", channel.input)?;
+        channel_dict.set_item("level_dbfs", channel.level_dbfs)?;
+        channel_dict.set_item("level_dbv", channel.level_dbv)?;
+        channel_dict.set_item("level_uv", channel.level_uv)?;
+        channels.push(channel_dict.to_object(py));
+    }
+    dict.set_item("channels", channels)?;
+    dict.set_item("capture", capture_dict(py, &noise.capture)?)?;
+    Ok(dict.to_object(py))
+}
+
+fn file_analysis_dict(py: Python, analysis: FileAnalysis) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("file", analysis.file)?;
+    dict.set_item("sample_rate", analysis.sample_rate)?;
+    let mut channels = Vec::new();
+    for channel in analysis.channels {
+        let channel_dict = PyDict::new(py);
+        channel_dict.set_item("level_dbfs", channel.level_dbfs)?;
+        channel_dict.set_item("peak_dbfs", channel.peak_dbfs)?;
+        channel_dict.set_item("peak_frequency", channel.peak_frequency)?;
+        channel_dict.set_item("thd", channel.thd)?;
+        channel_dict.set_item("harmonics_dbc", channel.harmonics)?;
+        channels.push(channel_dict.to_object(py));
+    }
+    dict.set_item("channels", channels)?;
+    Ok(dict.to_object(py))
+}
@@ -0,0 +1,149 @@
+use pyo3::buffer::PyBuffer;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::config_helpers::Window;
+use crate::error_helpers::{Error, Result};
+use crate::signal_helpers::{self, CustomStimulus};
+use crate::{config_helpers, fft_helpers, measurement_helpers, result_helpers, wav_helpers};
+use crate::to_py_err;
+
+// Audio is exchanged with Python as NumPy float64 arrays, with full scale at ±1.0
+// - A single channel is a 1-D array of samples
+// - Several channels are a 2-D array of (channels, frames)
+// Arrays are built from the samples' bytes in one go, rather than a Python float at a time
+
+pub fn to_array(py: Python, samples: &[f64]) -> PyResult<PyObject> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_ne_bytes()).collect();
+    let array = py.import("numpy")?.call1("frombuffer", (PyBytes::new(py, &bytes), "float64"))?;
+    // The array shares the (read only) bytes, so give it its own copy
+    Ok(array.call_method0("copy")?.to_object(py))
+}
+
+pub fn to_channels_array(py: Python, channels: &[Vec<f64>]) -> PyResult<PyObject> {
+    let frames = channels.first().map_or(0, |channel| channel.len());
+    let array = to_array(py, &channels.concat())?;
+    array.call_method1(py, "reshape", ((channels.len(), frames),))
+}
+
+// Anything NumPy can turn into an array of samples (a list works too), converted to float64 if it isn't already
+// NaN or infinite samples can't be played or analysed, so they're turned away here
+pub fn from_array(py: Python, array: &PyAny) -> PyResult<Vec<Vec<f64>>> {
+    let array = py.import("numpy")?.call1("ascontiguousarray", (array, "float64"))?;
+    let buffer = PyBuffer::<f64>::get(array)?;
+    let samples = buffer.to_vec(py)?;
+    if let Some(i) = samples.iter().position(|sample| !sample.is_finite()) {
+        return Err(to_py_err(Error::Format(format!("The samples must be finite, not {} (at {})", samples[i], i))));
+    }
+    match *buffer.shape() {
+        [_] => Ok(vec![samples]),
+        [channels, frames] => Ok((0..channels).map(|channel| samples[channel * frames..(channel + 1) * frames].to_vec()).collect()),
+        _ => Err(to_py_err(Error::Format(format!("Expected an array of samples or of channels, not {} dimensions", buffer.dimensions())))),
+    }
+}
+
+// A custom stimulus is a single channel, played on all the mapped outputs
+pub fn to_stimulus(py: Python, samples: Option<&PyAny>, sample_rate: u32) -> PyResult<Option<CustomStimulus>> {
+    let samples = match samples {
+        Some(samples) => from_array(py, samples)?,
+        None => return Ok(None),
+    };
+    if samples.len() != 1 {
+        return Err(to_py_err(Error::Format(format!("The stimulus must be a single channel, not {}", samples.len()))));
+    }
+    let samples = samples[0].iter().map(|&sample| sample as f32).collect();
+    Ok(Some(CustomStimulus { samples, sample_rate }))
+}
+
+pub fn set_stimulus(py: Python, samples: Option<&PyAny>, sample_rate: u32) -> PyResult<()> {
+    signal_helpers::set_custom_stimulus(to_stimulus(py, samples, sample_rate)?);
+    Ok(())
+}
+
+// The generated (or expected) stimulus from the last capture
+pub fn generated(py: Python) -> PyResult<PyObject> {
+    let (channels, _) = wav_helpers::read_artifact(crate::GENERATE_PATH).map_err(to_py_err)?;
+    to_array(py, &channels[0])
+}
+
+// The mapped inputs from the last capture, tail included
+pub fn recorded(py: Python) -> PyResult<PyObject> {
+    let (channels, _) = wav_helpers::read_artifact(crate::RECORD_PATH).map_err(to_py_err)?;
+    to_channels_array(py, &channels)
+}
+
+// The reference channel from the last capture, or None if no reference is mapped
+pub fn reference(py: Python) -> PyResult<Option<PyObject>> {
+    if config_helpers::current().map_err(to_py_err)?.channels.reference.is_none() {
+        return Ok(None);
+    }
+    let (channels, _) = wav_helpers::read_artifact(crate::REFERENCE_PATH).map_err(to_py_err)?;
+    to_array(py, &channels[0]).map(Some)
+}
+
+// The spectra the last measurement was worked out from, the generated one and one for each recorded input
+pub fn spectra(py: Python) -> PyResult<PyObject> {
+    let find = || -> Result<_> {
+        let generated = fft_helpers::recording_spectra(crate::GENERATE_PATH)?.remove(0);
+        let recorded = fft_helpers::recording_spectra(crate::RECORD_PATH)?;
+        let inputs = result_helpers::input_labels(recorded.len())?;
+        Ok((generated, recorded, inputs))
+    };
+    let (generated, recorded, inputs) = find().map_err(to_py_err)?;
+    let dict = PyDict::new(py);
+    dict.set_item("generated", spectrum_dict(py, &generated)?)?;
+    let channels = recorded.iter().zip(inputs)
+        .map(|(spectrum, input)| {
+            let channel = spectrum_dict(py, spectrum)?;
+            channel.set_item("input", input)?;
+            Ok(channel.to_object(py))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    dict.set_item("channels", channels)?;
+    Ok(dict.to_object(py))
+}
+
+// The spectrum of each channel of the samples, trimmed to whole cycles
+// A dict for a single channel, or a list of them for several
+pub fn spectrum(py: Python, samples: &PyAny, sample_rate: u32) -> PyResult<PyObject> {
+    let channels = from_array(py, samples)?;
+    let spectra = channels.iter()
+        .map(|channel| {
+            let spectrum = fft_helpers::signal_spectrum(channel, sample_rate).map_err(to_py_err)?;
+            Ok(spectrum_dict(py, &spectrum)?.to_object(py))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(one_or_many(py, &channels, spectra))
+}
+
+// Analyse the samples the same way as a recording, without touching the devices or the WAV files
+// The THD+N window is sized for the test frequency, or for the fundamental that's found if none is given
+// A dict for a single channel, or a list of them for several
+pub fn analyse(py: Python, samples: &PyAny, sample_rate: u32, frequency: Option<usize>, window: Window) -> PyResult<PyObject> {
+    let channels = from_array(py, samples)?;
+    let results = channels.iter()
+        .map(|channel| {
+            let analysis = measurement_helpers::analyse_channel(channel, sample_rate, frequency, window).map_err(to_py_err)?;
+            let dict = PyDict::new(py);
+            dict.set_item("level_dbfs", analysis.level_dbfs)?;
+            dict.set_item("peak_dbfs", analysis.peak_dbfs)?;
+            dict.set_item("peak_frequency", analysis.peak_frequency)?;
+            dict.set_item("thd", analysis.thd)?;
+            dict.set_item("harmonics_dbc", analysis.harmonics)?;
+            Ok(dict.to_object(py))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(one_or_many(py, &channels, results))
+}
+
+fn one_or_many(py: Python, channels: &[Vec<f64>], mut results: Vec<PyObject>) -> PyObject {
+    if channels.len() == 1 { results.remove(0) } else { results.to_object(py) }
+}
+
+fn spectrum_dict<'py>(py: Python<'py>, spectrum: &fft_helpers::Spectrum) -> PyResult<&'py PyDict> {
+    let frequencies: Vec<f64> = (0..spectrum.amplitudes.len()).map(|i| i as f64 * spectrum.bin).collect();
+    let dict = PyDict::new(py);
+    dict.set_item("frequencies", to_array(py, &frequencies)?)?;
+    dict.set_item("amplitudes", to_array(py, &spectrum.amplitudes)?)?;
+    Ok(dict)
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::Local;
+
+use crate::config_helpers::{self, OutputSettings};
+use crate::error_helpers::Result;
+
+// Where the runs go if a run ID is set without a directory
+const DEFAULT_RUNS_DIRECTORY: &str = "runs";
+
+// The WAV files of the last capture, when they're kept in memory rather than written out
+static MEMORY: Mutex<Option<HashMap<String, Vec<u8>>>> = Mutex::new(None);
+
+// A run ID from the time it starts, e.g. 20240131-142530.123 (to the millisecond, so runs started together don't collide)
+pub fn new_run_id() -> String {
+    Local::now().format("%Y%m%d-%H%M%S%.3f").to_string()
+}
+
+// The directory a run's files go in: <directory>/<run id>, with the directory defaulting to runs/
+// Without a run ID they go straight into the directory (or the working directory), replacing the last run's
+// None if the files are kept in memory
+pub fn run_directory(output: &OutputSettings) -> Option<PathBuf> {
+    if output.in_memory {
+        return None;
+    }
+    let run_id = output.current_run_id();
+    let directory = match (&output.directory, run_id) {
+        (Some(directory), _) => PathBuf::from(directory),
+        (None, Some(_)) => PathBuf::from(DEFAULT_RUNS_DIRECTORY),
+        (None, None) => PathBuf::new(),
+    };
+    Some(match run_id {
+        Some(run_id) => directory.join(run_id),
+        None => directory,
+    })
+}
+
+// Whether the files a measurement leaves behind are written out, the plots and CSV files are skipped if they aren't
+pub fn enabled() -> Result<bool> {
+    Ok(!config_helpers::current()?.output.in_memory)
+}
+
+// Where one of the files a measurement leaves behind is written (and read back from), e.g. path("recorded.wav")
+// The run directory is created if it doesn't exist yet
+pub fn path(name: &str) -> Result<String> {
+    let output = config_helpers::current()?.output;
+    let directory = match run_directory(&output) {
+        Some(directory) => directory,
+        None => fail!(File, "{} is kept in memory, it isn't written to a file", name),
+    };
+    if !directory.as_os_str().is_empty() {
+        std::fs::create_dir_all(&directory)?;
+    }
+    Ok(directory.join(name).to_string_lossy().into_owned())
+}
+
+// Start writing one of the capture's WAV files, to the run directory or to memory
+pub fn create_wav(name: &str, spec: hound::WavSpec) -> Result<hound::WavWriter<Artifact>> {
+    let artifact = if enabled()? {
+        Artifact::File(BufWriter::new(File::create(path(name)?)?))
+    } else {
+        Artifact::Memory(name.to_owned(), Cursor::new(Vec::new()))
+    };
+    Ok(hound::WavWriter::new(artifact, spec)?)
+}
+
+// Read back one of the capture's WAV files, from wherever create_wav put it
+pub fn open_wav(name: &str) -> Result<hound::WavReader<ArtifactReader>> {
+    let reader = if enabled()? {
+        ArtifactReader::File(BufReader::new(File::open(path(name)?)?))
+    } else {
+        let memory = MEMORY.lock().unwrap();
+        match memory.as_ref().and_then(|files| files.get(name)) {
+            Some(bytes) => ArtifactReader::Memory(Cursor::new(bytes.clone())),
+            None => fail!(File, "There's no {} in memory, nothing has been captured yet", name),
+        }
+    };
+    Ok(hound::WavReader::new(reader)?)
+}
+
+// One of the capture's WAV files being written
+// A file kept in memory is stored when it's dropped, which the WAV writer does once it has finished the header
+pub enum Artifact {
+    File(BufWriter<File>),
+    Memory(String, Cursor<Vec<u8>>),
+}
+
+impl Write for Artifact {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Artifact::File(file) => file.write(buf),
+            Artifact::Memory(_, bytes) => bytes.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Artifact::File(file) => file.flush(),
+            Artifact::Memory(_, bytes) => bytes.flush(),
+        }
+    }
+}
+
+impl Seek for Artifact {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Artifact::File(file) => file.seek(pos),
+            Artifact::Memory(_, bytes) => bytes.seek(pos),
+        }
+    }
+}
+
+impl Drop for Artifact {
+    fn drop(&mut self) {
+        if let Artifact::Memory(name, bytes) = self {
+            let mut memory = MEMORY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            memory.get_or_insert_with(HashMap::new).insert(mem::take(name), mem::take(bytes.get_mut()));
+        }
+    }
+}
+
+pub enum ArtifactReader {
+    File(BufReader<File>),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for ArtifactReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ArtifactReader::File(file) => file.read(buf),
+            ArtifactReader::Memory(bytes) => bytes.read(buf),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_run_gets_its_own_directory_unless_there_is_no_run_id() {
+        let mut output = OutputSettings { automatic_run_id: Some(String::from("20240131-142530.123")), ..Default::default() };
+        assert_eq!(run_directory(&output), Some(PathBuf::from("runs/20240131-142530.123")));
+        output.run_id = Some(String::from("golden"));
+        output.directory = Some(String::from("results"));
+        assert_eq!(run_directory(&output), Some(PathBuf::from("results/golden")));
+        output.in_memory = true;
+        assert_eq!(run_directory(&output), None);
+        assert_eq!(run_directory(&OutputSettings::default()), Some(PathBuf::new()));
+    }
+}
@@ -1,234 +1,841 @@
-use std::sync::atomic::{AtomicBool, Ordering};
-use cpal::traits::{DeviceTrait, EventLoopTrait, HostTrait};
-#[cfg(not(feature = "default_card"))]
-use cpal::platform::Device;
-use std::time::Duration;
-
-#[cfg( all(target_os = "linux", not(feature = "default_card")))]
-const INPUT_CARD: &'static str = "hw:CARD=Device,DEV=0";
-
-#[cfg(all(not(target_os = "linux"), not(feature = "default_card")))]
-const INPUT_CARD: &'static str = "Microphone (USB Advanced Audio Device)";
-#[cfg(all(not(target_os = "linux"), not(feature = "default_card")))]
-const OUTPUT_CARD: &'static str = "Speakers (USB Advanced Audio Device)";
-
-const RECORDING_TIME: Duration = std::time::Duration::from_secs(crate::SECONDS_TO_RECORD as u64);
-const GENERATED_WAVE_AMPLITUDE: f32 = 0.8;
-
-pub fn record_audio() {
-    // Use the default host for working with audio devices.
-    let host = cpal::default_host();
-
-    // Setup the default input device and stream with the default input format.
-#[cfg(feature = "default_card")]
-    let device = host.default_input_device().expect("Failed to get default input device");
-#[cfg(not(feature = "default_card"))]
-    let device = get_soundcard(INPUT_CARD).expect("Failed to get input device");
-
-    println!("Input device: {}", device.name().expect("Device name error"));
-    let format = device.default_input_format().expect("Failed to get default input format");
-    println!("Input format: {:?}", format);
-    let event_loop = host.event_loop();
-    let stream_id = event_loop.build_input_stream(&device, &format).expect("Input stream error");
-    event_loop.play_stream(stream_id).expect("Input Play stream error");
-
-    let spec = wav_spec_from_format(&format);
-    let writer = hound::WavWriter::create(crate::RECORD_PATH, spec).expect("Couldn't create file");
-    let writer = std::sync::Arc::new(std::sync::Mutex::new(Some(writer)));
-
-    let gen_writer = hound::WavWriter::create(crate::GENERATE_PATH, spec).expect("Couldn't create file");
-    let gen_writer = std::sync::Arc::new(std::sync::Mutex::new(Some(gen_writer)));
-
-    let event_loop_out = host.event_loop();
-#[cfg(target_os = "linux")]
-    {
-        let stream_id_out = event_loop_out.build_output_stream(&device, &format).expect("Output stream error");
-        event_loop_out.play_stream(stream_id_out).expect("Output Play stream error");
-    }
-#[cfg(not(target_os = "linux"))]
-    {
-#[cfg(feature = "default_card")]
-        let device_out = host.default_output_device().expect("Failed to get default output device");
-#[cfg(not(feature = "default_card"))]
-        let device_out = get_soundcard(OUTPUT_CARD).expect("Failed to get output device");
-        println!("Output device: {}", device_out.name().expect("Device name error"));
-        let format_out = device_out.default_output_format().expect("Failed to get output format");
-        println!("Output format: {:?}", format_out);
-        let stream_id_out = event_loop_out.build_output_stream(&device_out, &format_out).expect("Output stream error");
-        event_loop_out.play_stream(stream_id_out).expect("Output Play stream error");
-    }
-
-    // A flag to indicate that recording is in progress.
-    let playing = std::sync::Arc::new(AtomicBool::new(true));
-    let playing_2 = playing.clone();
-    let gen_writer_2 = gen_writer.clone();
-    let sample_rate = format.sample_rate.0 as f32;
-    let mut sample_clock = 0f32;
-
-    std::thread::spawn(move || {
-        event_loop_out.run(move |id, result| {
-            let data = match result {
-                Ok(data) => data,
-                Err(err) => {
-                    eprintln!("an error occurred on stream {:?}: {}", id, err);
-                    return;
-                }
-            };
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, Sample, SizedSample, StreamInstant};
+use rtrb::{Consumer, RingBuffer};
+use serde::Serialize;
 
-            // Produce a sinusoid
-            let mut next_value = || {
-                sample_clock = (sample_clock + 1.0) % sample_rate;
-                let frequency = crate::FREQUENCY.load(Ordering::Relaxed);
-                (sample_clock * frequency as f32 * 2.0 * 3.141592 / sample_rate).sin() * GENERATED_WAVE_AMPLITUDE
-            };
+use crate::artifact_helpers;
+use crate::backend_helpers::{self, AudioBackend};
+use crate::config_helpers::{self, Config};
+use crate::device_helpers::{self, Direction};
+use crate::error_helpers::{Error, Result};
+use crate::signal_helpers::{self, SignalSource};
+#[cfg(feature = "jack")]
+use crate::jack_helpers;
 
-            // If we're done playing, return early.
-            if !playing_2.load(std::sync::atomic::Ordering::Relaxed) {
-                return;
-            }
+// The recording carries on this long after the stimulus, unless the config says otherwise
+const DEFAULT_TAIL_MS: u32 = 100;
+// How much longer than expected the capture is allowed to take before giving up
+const CAPTURE_TIMEOUT_MARGIN: Duration = Duration::from_secs(2);
+// How much audio the ring buffer between the input callback and the writer thread can hold
+const RING_BUFFER_SECONDS: usize = 1;
+// How long the writer thread sleeps when it has caught up with the input
+const WRITER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+// How often a running capture checks whether it has been cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type WavFileWriter = hound::WavWriter<artifact_helpers::Artifact>;
+
+// The result of the last capture, kept so the sample counts can be reported afterwards
+static LAST_CAPTURE: Mutex<Option<CaptureReport>> = Mutex::new(None);
+// The cancel flag of the measurement that's running, if it can be cancelled
+static CANCEL_FLAG: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
+
+// The sample counts (in frames) of a capture, and anything that went wrong with it
+// - The stimulus is exactly the requested length (the measurement length for a measurement), followed by silence
+// - The recording starts with the first sample of the stimulus and runs for the stimulus plus the tail
+// - Xruns are buffers the device ran out of (output) or overwrote before they were read (input)
+// - Discontinuities are (input, frame) points where the recorded signal jumps
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CaptureReport {
+    pub sample_rate: u32,
+    pub stimulus_frames: usize,
+    pub tail_frames: usize,
+    pub played_frames: usize,
+    pub recorded_frames: usize,
+    pub input_xruns: usize,
+    pub output_xruns: usize,
+    pub dropped_samples: usize,
+    pub stream_errors: Vec<String>,
+    pub discontinuities: Vec<(u16, usize)>,
+}
+
+impl CaptureReport {
+    // Everything that makes the recording unfit to measure, empty if it's good
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.recorded_frames < self.stimulus_frames + self.tail_frames {
+            problems.push(format!("only {} of {} frames were recorded", self.recorded_frames, self.stimulus_frames + self.tail_frames));
+        }
+        if self.input_xruns > 0 {
+            problems.push(format!("{} input overrun(s)", self.input_xruns));
+        }
+        if self.output_xruns > 0 {
+            problems.push(format!("{} output underrun(s)", self.output_xruns));
+        }
+        if self.dropped_samples > 0 {
+            problems.push(format!("{} sample(s) couldn't be written to the recording", self.dropped_samples));
+        }
+        problems.extend(self.stream_errors.iter().cloned());
+        let mut inputs: Vec<u16> = self.discontinuities.iter().map(|&(input, _)| input).collect();
+        inputs.dedup();
+        for input in inputs {
+            let frames: Vec<usize> = self.discontinuities.iter().filter(|&&(i, _)| i == input).map(|&(_, frame)| frame).collect();
+            problems.push(format!("{} discontinuit{} on input {} (first at frame {})",
+                frames.len(), if frames.len() == 1 { "y" } else { "ies" }, input, frames[0]));
+        }
+        problems
+    }
+
+    // Everything that went wrong playing the stimulus, when nothing was recorded
+    pub fn playback_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.played_frames < self.stimulus_frames {
+            problems.push(format!("only {} of {} frames were played", self.played_frames, self.stimulus_frames));
+        }
+        if self.output_xruns > 0 {
+            problems.push(format!("{} output underrun(s)", self.output_xruns));
+        }
+        problems.extend(self.stream_errors.iter().cloned());
+        problems
+    }
+}
+
+// The Python module reads back the last capture's report, the binary prints each one as it's made
+#[allow(dead_code)]
+pub fn last_capture() -> Option<CaptureReport> {
+    LAST_CAPTURE.lock().unwrap().clone()
+}
+
+// Set (or clear) the flag another thread raises to cancel the running measurement
+// Every capture checks it before starting and while its streams run, so a measurement stops at its current capture
+// Only Python's background measurements are cancelled, the binary runs until it's done (or stopped with Ctrl-C)
+#[allow(dead_code)]
+pub fn set_cancel_flag(flag: Option<Arc<AtomicBool>>) {
+    *CANCEL_FLAG.lock().unwrap() = flag;
+}
+
+// Cancel whichever measurement is running, if any
+#[allow(dead_code)]
+pub fn cancel() {
+    if let Some(flag) = CANCEL_FLAG.lock().unwrap().as_ref() {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn check_cancelled() -> Result<()> {
+    if CANCEL_FLAG.lock().unwrap().as_ref().is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+        fail!(Cancelled, "The measurement was cancelled");
+    }
+    Ok(())
+}
+
+// Play the stimulus and record the response, through whichever backend is configured
+// - The backend writes the generated, recorded (and reference) WAV files and counts the samples
+// - In capture-only mode the stimulus comes from an external generator, so it's only recorded
+// - Then check the recording is continuous, an error means the measurement is invalid
+pub fn record_audio() -> Result<CaptureReport> {
+    record_stimulus(measurement_length(&config_helpers::current()?))
+}
+
+// A measurement's duration has to leave something to analyse, so it's checked wherever one is read in
+// (the command line, config files, test plans and the Python settings), before anything is measured
+pub fn check_duration(duration_s: f64) -> Result<f64> {
+    let minimum_s = crate::fft_helpers::MIN_ANALYSIS_SECONDS;
+    if !duration_s.is_finite() || duration_s <= minimum_s {
+        fail!(Format, "A measurement's duration has to be more than {} seconds, not {}", minimum_s, duration_s);
+    }
+    Ok(duration_s)
+}
+
+// How long the stimulus plays for a measurement
+pub fn measurement_length(config: &Config) -> Duration {
+    Duration::from_secs_f64(config.capture.duration_s.unwrap_or(crate::SECONDS_TO_RECORD as f64))
+}
+
+// The same as record_audio, with a stimulus of any length (e.g. a short probe tone)
+pub fn record_stimulus(length: Duration) -> Result<CaptureReport> {
+    check_cancelled()?;
+    let config = config_helpers::current()?;
+    crate::result_helpers::clear_channel_results();
+    let backend = backend_helpers::backend(&config);
+    let mut report = if config.capture.external {
+        backend.record(&config, length)?
+    } else {
+        backend.capture(&config, length)?
+    };
+    report.discontinuities = find_discontinuities()?;
+
+    if config.capture.external {
+        eprintln!("Recorded {} frames ({} stimulus + {} tail) of the external stimulus at {} Hz",
+            report.recorded_frames, report.stimulus_frames, report.tail_frames, report.sample_rate);
+    } else {
+        eprintln!("Played {} stimulus frames, recorded {} frames ({} stimulus + {} tail) at {} Hz",
+            report.played_frames, report.recorded_frames, report.stimulus_frames, report.tail_frames, report.sample_rate);
+    }
+    *LAST_CAPTURE.lock().unwrap() = Some(report.clone());
+
+    let problems = report.problems();
+    if !problems.is_empty() {
+        fail!(Stream, "Measurement invalid: {}", problems.join(", "));
+    }
+    Ok(report)
+}
+
+// Play the generator's stimulus without recording anything, for DUTs that can't be looped back
+// Only the playback can go wrong, an error means it didn't all get played
+pub fn play_stimulus(length: Duration) -> Result<CaptureReport> {
+    check_cancelled()?;
+    let config = config_helpers::current()?;
+    let report = backend_helpers::backend(&config).play(&config, length)?;
+    eprintln!("Played {} of {} stimulus frames at {} Hz", report.played_frames, report.stimulus_frames, report.sample_rate);
+    *LAST_CAPTURE.lock().unwrap() = Some(report.clone());
+
+    let problems = report.playback_problems();
+    if !problems.is_empty() {
+        fail!(Stream, "Playback failed: {}", problems.join(", "));
+    }
+    Ok(report)
+}
+
+// How many frames to keep recording for after the stimulus
+pub fn tail_frames(config: &Config, sample_rate: u32) -> usize {
+    let tail_ms = config.capture.tail_ms.unwrap_or(DEFAULT_TAIL_MS);
+    (sample_rate as u64 * tail_ms as u64 / 1000) as usize
+}
+
+// Plays and records through real devices with cpal
+pub struct CpalBackend;
+
+impl AudioBackend for CpalBackend {
+    fn device_names(&self, config: &Config) -> Result<(String, String)> {
+        let host = device_helpers::host(config)?;
+        let input_name = device_helpers::input_device(&host, config)?.name()?;
+        let output_name = device_helpers::output_device(&host, config)?.name()?;
+        Ok((input_name, output_name))
+    }
+
+    fn capture(&self, config: &Config, length: Duration) -> Result<CaptureReport> {
+        capture_cpal(config, length)
+    }
+
+    fn record(&self, config: &Config, length: Duration) -> Result<CaptureReport> {
+        record_cpal(config, length)
+    }
+
+    fn play(&self, config: &Config, length: Duration) -> Result<CaptureReport> {
+        play_cpal(config, length)
+    }
+}
+
+// Play the stimulus and record the response on the selected devices
+// - Render the whole stimulus up front, so exactly the same buffer is played and written to the generated WAV file
+// - Start the input stream first, so it's already running when the stimulus starts
+// - Both streams timestamp their buffers, which lets the input work out which frame the stimulus starts on
+// - The input records exactly the stimulus length plus the tail from there
+// - The audio callbacks never lock or touch a file, the input hands its samples to a writer thread through a ring buffer
+//      and the writer thread signals when everything has been written
+// - Finally collect anything that went wrong with the streams
+fn capture_cpal(config: &Config, length: Duration) -> Result<CaptureReport> {
+    // Use the selected host (the platform's default unless one has been chosen)
+    let host = device_helpers::host(config)?;
+
+    // Setup the selected input and output devices
+    let device = device_helpers::input_device(&host, config)?;
+    eprintln!("Input device: {}", device.name()?);
+    let device_out = device_helpers::output_device(&host, config)?;
+    eprintln!("Output device: {}", device_out.name()?);
+
+    // Work out both stream configs before starting anything, so a mismatch is reported up front
+    let (stream_config, format) = device_helpers::stream_config(&device, Direction::Input, &config.stream)?;
+    eprintln!("Input format: {:?}, {:?}", stream_config, format);
+    let (stream_config_out, format_out) = device_helpers::stream_config(&device_out, Direction::Output, &config.stream)?;
+    eprintln!("Output format: {:?}, {:?}", stream_config_out, format_out);
+    if stream_config.sample_rate != stream_config_out.sample_rate {
+        fail!(Format, "Input sample rate ({} Hz) doesn't match the output sample rate ({} Hz)",
+            stream_config.sample_rate.0, stream_config_out.sample_rate.0);
+    }
+
+    // Work out which channels to play on and record from
+    let outputs = config.channels.output_indices(stream_config_out.channels)?;
+    let inputs = config.channels.input_indices(stream_config.channels)?;
+    let reference = config.channels.reference_index(stream_config.channels)?;
+
+    let sample_rate = stream_config.sample_rate.0;
+    let stimulus = render_stimulus(sample_rate, length)?;
+    let tail_frames = tail_frames(config, sample_rate);
+    let stimulus_frames = stimulus.len();
+
+    let (writer, ref_writer) = create_recording(inputs.len(), reference, &stream_config, format)?;
+    write_generated(&stimulus, &stream_config_out, format_out)?;
+
+    // Both streams share a clock, so the input can line its frames up with the stimulus
+    let sync = Arc::new(StreamSync::new());
+    let (done_sender, done_receiver) = mpsc::sync_channel(1);
+
+    // JACK streams run as soon as they're built, so the graph is noted first to find their ports to patch
+    #[cfg(feature = "jack")]
+    let patch = jack_helpers::Patch::open(&host, &config.jack)?;
+    let output = Output { outputs, source: Box::new(stimulus.into_iter()), sync: sync.clone(), done: None };
+    let stream_out = build_output(&device_out, &stream_config_out, format_out, output)?;
+    let input = Input {
+        inputs,
+        reference,
+        writer,
+        ref_writer,
+        target_frames: stimulus_frames + tail_frames,
+        sync: sync.clone(),
+        done: done_sender,
+    };
+    let (stream, writer_thread) = build_input(&device, &stream_config, format, input)?;
+    // Hold them until they're patched into the graph
+    #[cfg(feature = "jack")]
+    if let Some(patch) = &patch {
+        stream_out.pause().ok();
+        stream.pause().ok();
+        patch.connect(Some(&device.name()?), Some(&device_out.name()?))?;
+    }
+    stream.play()?;
+    stream_out.play()?;
+
+    let expected = Duration::from_millis((stimulus_frames + tail_frames) as u64 * 1000 / sample_rate as u64);
+    let stream_errors = wait_for_streams(vec![stream_out, stream], Some(writer_thread), done_receiver, &sync, expected)?;
+    Ok(sync.report(sample_rate, stimulus_frames, tail_frames, stream_errors))
+}
+
+// Record the response to an external generator on the selected input device
+// - Work out the stream config and channels the same as for a capture
+// - Nothing is played, so the recording starts with the first buffer and runs for the expected stimulus plus the tail
+// - The expected stimulus is written as the generated audio, in the input's sample format
+fn record_cpal(config: &Config, length: Duration) -> Result<CaptureReport> {
+    let host = device_helpers::host(config)?;
+    let device = device_helpers::input_device(&host, config)?;
+    eprintln!("Input device: {}", device.name()?);
+    let (stream_config, format) = device_helpers::stream_config(&device, Direction::Input, &config.stream)?;
+    eprintln!("Input format: {:?}, {:?}", stream_config, format);
+    let inputs = config.channels.input_indices(stream_config.channels)?;
+    let reference = config.channels.reference_index(stream_config.channels)?;
+
+    let sample_rate = stream_config.sample_rate.0;
+    let stimulus = expected_stimulus(config, sample_rate, length)?;
+    let tail_frames = tail_frames(config, sample_rate);
+    let stimulus_frames = stimulus.len();
+    let (writer, ref_writer) = create_recording(inputs.len(), reference, &stream_config, format)?;
+    write_generated(&stimulus, &stream_config, format)?;
+
+    // Without an output the stimulus starts as soon as the clock does, so the input records from its first buffer
+    let sync = Arc::new(StreamSync::new());
+    sync.stimulus_start.store(1, Ordering::SeqCst);
+    let (done_sender, done_receiver) = mpsc::sync_channel(1);
+    let input = Input {
+        inputs,
+        reference,
+        writer,
+        ref_writer,
+        target_frames: stimulus_frames + tail_frames,
+        sync: sync.clone(),
+        done: done_sender,
+    };
+    #[cfg(feature = "jack")]
+    let patch = jack_helpers::Patch::open(&host, &config.jack)?;
+    let (stream, writer_thread) = build_input(&device, &stream_config, format, input)?;
+    #[cfg(feature = "jack")]
+    if let Some(patch) = &patch {
+        stream.pause().ok();
+        patch.connect(Some(&device.name()?), None)?;
+    }
+    stream.play()?;
+
+    let expected = Duration::from_millis((stimulus_frames + tail_frames) as u64 * 1000 / sample_rate as u64);
+    let stream_errors = wait_for_streams(vec![stream], Some(writer_thread), done_receiver, &sync, expected)?;
+    Ok(sync.report(sample_rate, stimulus_frames, tail_frames, stream_errors))
+}
+
+// Play the generator's stimulus on the selected output device, without recording anything
+// The stimulus is generated as it's played, so it can be any length
+fn play_cpal(config: &Config, length: Duration) -> Result<CaptureReport> {
+    let host = device_helpers::host(config)?;
+    let device_out = device_helpers::output_device(&host, config)?;
+    eprintln!("Output device: {}", device_out.name()?);
+    let (stream_config_out, format_out) = device_helpers::stream_config(&device_out, Direction::Output, &config.stream)?;
+    eprintln!("Output format: {:?}, {:?}", stream_config_out, format_out);
+    let outputs = config.channels.output_indices(stream_config_out.channels)?;
+
+    let sample_rate = stream_config_out.sample_rate.0;
+    let source = signal_helpers::stimulus(sample_rate, length)?;
+    let stimulus_frames = source.len();
+    let sync = Arc::new(StreamSync::new());
+    let (done_sender, done_receiver) = mpsc::sync_channel(1);
+    let output = Output { outputs, source, sync: sync.clone(), done: Some(done_sender) };
+    #[cfg(feature = "jack")]
+    let patch = jack_helpers::Patch::open(&host, &config.jack)?;
+    let stream_out = build_output(&device_out, &stream_config_out, format_out, output)?;
+    #[cfg(feature = "jack")]
+    if let Some(patch) = &patch {
+        stream_out.pause().ok();
+        patch.connect(None, Some(&device_out.name()?))?;
+    }
+    stream_out.play()?;
+
+    let expected = Duration::from_millis(stimulus_frames as u64 * 1000 / sample_rate as u64);
+    let stream_errors = wait_for_streams(vec![stream_out], None, done_receiver, &sync, expected)?;
+    Ok(sync.report(sample_rate, stimulus_frames, 0, stream_errors))
+}
+
+// The recording holds just the mapped inputs (in order), the reference is mono
+fn create_recording(inputs: usize, reference: Option<usize>, config: &cpal::StreamConfig, format: cpal::SampleFormat)
+    -> Result<(WavFileWriter, Option<WavFileWriter>)> {
+    let spec = wav_spec_from_config(inputs as u16, config, format);
+    let writer = artifact_helpers::create_wav(crate::RECORD_PATH, spec)?;
+
+    let ref_writer = reference
+        .map(|_| artifact_helpers::create_wav(crate::REFERENCE_PATH, wav_spec_from_config(1, config, format)))
+        .transpose()?;
+    Ok((writer, ref_writer))
+}
+
+// The generated audio is mono, in the format of the stream it's played on (or recorded with)
+fn write_generated(stimulus: &[f32], config: &cpal::StreamConfig, format: cpal::SampleFormat) -> Result<()> {
+    let gen_spec = wav_spec_from_config(1, config, format);
+    match format {
+        cpal::SampleFormat::U16 | cpal::SampleFormat::I16 => write_stimulus::<i16>(stimulus, gen_spec),
+        cpal::SampleFormat::I32 => write_stimulus::<i32>(stimulus, gen_spec),
+        cpal::SampleFormat::F32 => write_stimulus::<f32>(stimulus, gen_spec),
+        _ => unreachable!("Stream config only allows supported sample formats"),
+    }
+}
+
+// Build the output stream for the device's sample format
+fn build_output(device: &cpal::Device, config: &cpal::StreamConfig, format: cpal::SampleFormat, output: Output) -> Result<cpal::Stream> {
+    match format {
+        cpal::SampleFormat::U16 => build_output_stream::<u16>(device, config, output),
+        cpal::SampleFormat::I16 => build_output_stream::<i16>(device, config, output),
+        cpal::SampleFormat::I32 => build_output_stream::<i32>(device, config, output),
+        cpal::SampleFormat::F32 => build_output_stream::<f32>(device, config, output),
+        _ => unreachable!("Stream config only allows supported sample formats"),
+    }
+}
+
+// Build the input stream for the device's sample format, unsigned samples are recorded as signed
+fn build_input(device: &cpal::Device, config: &cpal::StreamConfig, format: cpal::SampleFormat, input: Input)
+    -> Result<(cpal::Stream, JoinHandle<()>)> {
+    match format {
+        cpal::SampleFormat::U16 => build_input_stream::<u16, i16>(device, config, input),
+        cpal::SampleFormat::I16 => build_input_stream::<i16, i16>(device, config, input),
+        cpal::SampleFormat::I32 => build_input_stream::<i32, i32>(device, config, input),
+        cpal::SampleFormat::F32 => build_input_stream::<f32, f32>(device, config, input),
+        _ => unreachable!("Stream config only allows supported sample formats"),
+    }
+}
+
+// Wait for the writer thread to say it has written everything (or the output that it's played everything),
+// then stop the streams
+// If it never does, tell the writer thread to write what it has and stop
+// Returns everything that went wrong with the streams
+fn wait_for_streams(streams: Vec<cpal::Stream>, writer_thread: Option<JoinHandle<()>>, done: mpsc::Receiver<()>,
+    sync: &StreamSync, expected: Duration) -> Result<Vec<String>> {
+    // Wait in short steps, so a cancelled measurement doesn't have to wait for the capture to finish
+    let deadline = Instant::now() + expected + CAPTURE_TIMEOUT_MARGIN;
+    let mut finished = false;
+    while !finished && Instant::now() < deadline && check_cancelled().is_ok() {
+        finished = match done.recv_timeout(CANCEL_POLL_INTERVAL) {
+            Ok(()) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+    }
+    for stream in streams {
+        stream.pause().ok();
+    }
+    sync.stopped.store(true, Ordering::SeqCst);
+    if let Some(writer_thread) = writer_thread {
+        writer_thread.join().map_err(|_| Error::Stream(String::from("The WAV writer thread panicked")))?;
+    }
+    if !finished {
+        check_cancelled()?;
+    }
+
+    let mut stream_errors = sync.errors.lock().unwrap().clone();
+    if !finished {
+        stream_errors.push(String::from("the capture timed out"));
+    }
+    Ok(stream_errors)
+}
+
+// Look for jumps in each recorded channel, labelled by physical input
+fn find_discontinuities() -> Result<Vec<(u16, usize)>> {
+    let discontinuities = crate::wav_helpers::find_discontinuities(crate::RECORD_PATH)?;
+    let inputs = crate::result_helpers::input_labels(discontinuities.len())?;
+    Ok(discontinuities.iter()
+        .zip(inputs.iter())
+        .flat_map(|(frames, &input)| frames.iter().map(move |&frame| (input, frame)))
+        .collect())
+}
+
+// The stimulus an external generator is expected to play
+// - The first channel of the stimulus WAV file, if one is given (its length replaces the requested length)
+// - Otherwise the sine the analyser would have played itself
+pub fn expected_stimulus(config: &Config, sample_rate: u32, length: Duration) -> Result<Vec<f32>> {
+    let path = match &config.capture.stimulus_wav {
+        Some(path) => path,
+        None => return render_stimulus(sample_rate, length),
+    };
+    let spec = hound::WavReader::open(path)?.spec();
+    if spec.sample_rate != sample_rate {
+        fail!(Format, "The stimulus file {} is at {} Hz, but the recording is at {} Hz", path, spec.sample_rate, sample_rate);
+    }
+    let (channels, _) = crate::wav_helpers::read_channels(path)?;
+    Ok(channels[0].iter().map(|&sample| sample as f32).collect())
+}
+
+// The generator's stimulus rendered up front, so exactly what's played can also be written to a file
+pub fn render_stimulus(sample_rate: u32, length: Duration) -> Result<Vec<f32>> {
+    Ok(signal_helpers::stimulus(sample_rate, length)?.collect())
+}
+
+// W is the sample type of the WAV file
+fn write_stimulus<W: hound::Sample + FromSample<f32>>(stimulus: &[f32], spec: hound::WavSpec) -> Result<()> {
+    let mut writer = artifact_helpers::create_wav(crate::GENERATE_PATH, spec)?;
+    for &value in stimulus {
+        writer.write_sample(value.to_sample::<W>())?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+// Shared between the streams and the writer thread to keep them in step, and to collect anything that goes wrong
+// The stimulus start is in nanoseconds from the clock, plus one so that zero means it hasn't started yet
+// The errors are only touched by the error callbacks, never the audio callbacks
+struct StreamSync {
+    clock: Instant,
+    stimulus_start: AtomicU64,
+    played_frames: AtomicUsize,
+    recorded_frames: AtomicUsize,
+    input_xruns: AtomicUsize,
+    output_xruns: AtomicUsize,
+    dropped_samples: AtomicUsize,
+    stopped: AtomicBool,
+    errors: Mutex<Vec<String>>,
+}
+
+impl StreamSync {
+    fn new() -> StreamSync {
+        StreamSync {
+            clock: Instant::now(),
+            stimulus_start: AtomicU64::new(0),
+            played_frames: AtomicUsize::new(0),
+            recorded_frames: AtomicUsize::new(0),
+            input_xruns: AtomicUsize::new(0),
+            output_xruns: AtomicUsize::new(0),
+            dropped_samples: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            errors: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn report(&self, sample_rate: u32, stimulus_frames: usize, tail_frames: usize, stream_errors: Vec<String>) -> CaptureReport {
+        CaptureReport {
+            sample_rate,
+            stimulus_frames,
+            tail_frames,
+            played_frames: self.played_frames.load(Ordering::SeqCst),
+            recorded_frames: self.recorded_frames.load(Ordering::SeqCst),
+            input_xruns: self.input_xruns.load(Ordering::SeqCst),
+            output_xruns: self.output_xruns.load(Ordering::SeqCst),
+            dropped_samples: self.dropped_samples.load(Ordering::SeqCst),
+            stream_errors,
+            discontinuities: Vec::new(),
+        }
+    }
+}
+
+// Not every host reports xruns (ALSA recovers from them silently), but they all show up in the buffer timestamps
+// Each buffer should start where the last one ended, a gap of more than half a buffer means frames were lost
+struct XrunDetector {
+    sample_rate: f64,
+    last: Option<(StreamInstant, usize)>,
+}
+
+impl XrunDetector {
+    fn new(sample_rate: u32) -> XrunDetector {
+        XrunDetector { sample_rate: sample_rate as f64, last: None }
+    }
+
+    fn is_xrun(&mut self, instant: StreamInstant, frames: usize) -> bool {
+        let xrun = match self.last {
+            Some((last, last_frames)) => {
+                let expected = last_frames as f64 / self.sample_rate;
+                let actual = instant.duration_since(&last).unwrap_or_default().as_secs_f64();
+                actual - expected > expected / 2.0
+            },
+            None => false,
+        };
+        self.last = Some((instant, frames));
+        xrun
+    }
+}
+
+// Where the generator plays, what it plays, and who to tell once it's all been played (if anyone)
+struct Output {
+    outputs: Vec<usize>,
+    source: Box<dyn SignalSource>,
+    sync: Arc<StreamSync>,
+    done: Option<SyncSender<()>>,
+}
 
-            // Add conversation routines for different audio formats
-            match data {
-                cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer) } => {
-                    if let Ok(mut guard) = gen_writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for sample in buffer.chunks_mut(format.channels as usize) {
-                                let value = ((next_value() * 0.5 + 0.5) * std::u16::MAX as f32) as u16;
-                                for out in sample.iter_mut() {
-                                    *out = value;
-                                    writer.write_sample(value as i16).ok();
-                                }
-                            }
-                        }
-                    }
-                },
-                cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer) } => {
-                    if let Ok(mut guard) = gen_writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for sample in buffer.chunks_mut(format.channels as usize) {
-                                let value = (next_value() * std::i16::MAX as f32) as i16;
-                                for out in sample.iter_mut() {
-                                    *out = value;
-                                    writer.write_sample(value).ok();
-                                }
-                            }
-                        }
-                    }
-                },
-                cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer) } => {
-                    if let Ok(mut guard) = gen_writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for sample in buffer.chunks_mut(format.channels as usize) {
-                                let value = next_value();
-                                for out in sample.iter_mut() {
-                                    *out = value;
-                                    writer.write_sample(value).ok();
-                                }
-                            }
-                        }
-                    }
-                },
-                _ => (),
+// Which channels are recorded, where they're written, and how many frames to record
+struct Input {
+    inputs: Vec<usize>,
+    reference: Option<usize>,
+    writer: WavFileWriter,
+    ref_writer: Option<WavFileWriter>,
+    target_frames: usize,
+    sync: Arc<StreamSync>,
+    done: SyncSender<()>,
+}
+
+// Play the source on the mapped output channels (the others are kept silent), then silence
+// The first callback notes when its first frame will reach the DAC, that's where the stimulus starts
+// The callback after the last of the source has been handed over signals that it's done
+// T is the sample type of the device
+fn build_output_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, output: Output) -> Result<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let active: Vec<bool> = (0..channels).map(|channel| output.outputs.contains(&channel)).collect();
+    let Output { mut source, sync, done, .. } = output;
+    let stimulus_frames = source.len();
+    let error_sync = sync.clone();
+    let mut position = 0;
+    let mut xruns = XrunDetector::new(config.sample_rate.0);
+
+    let stream = device.build_output_stream(config, move |buffer: &mut [T], info: &cpal::OutputCallbackInfo| {
+        let timestamp = info.timestamp();
+        if position == 0 {
+            let latency = timestamp.playback.duration_since(&timestamp.callback).unwrap_or_default();
+            let start = sync.clock.elapsed() + latency;
+            sync.stimulus_start.store(start.as_nanos() as u64 + 1, Ordering::SeqCst);
+        }
+        // Only gaps in the stimulus matter, the silence after it can glitch all it likes
+        if xruns.is_xrun(timestamp.playback, buffer.len() / channels) && position <= stimulus_frames {
+            sync.output_xruns.fetch_add(1, Ordering::SeqCst);
+        }
+        if position >= stimulus_frames {
+            if let Some(done) = &done {
+                done.try_send(()).ok();
             }
-        });
-    });
-
-    // A flag to indicate that recording is in progress.
-    let recording = std::sync::Arc::new(AtomicBool::new(true));
-
-    // Run the input stream on a separate thread.
-    let writer_2 = writer.clone();
-    let recording_2 = recording.clone();
-    std::thread::spawn(move || {
-        event_loop.run(move |id, event| {
-            let data = match event {
-                Ok(data) => data,
-                Err(err) => {
-                    eprintln!("an error occurred on stream {:?}: {}", id, err);
-                    return;
-                }
-            };
+        }
+        for frame in buffer.chunks_mut(channels) {
+            let value = source.next();
+            for (out, &active) in frame.iter_mut().zip(active.iter()) {
+                *out = match value {
+                    Some(value) if active => T::from_sample(value),
+                    _ => T::EQUILIBRIUM,
+                };
+            }
+            position += 1;
+        }
+        sync.played_frames.store(position.min(stimulus_frames), Ordering::SeqCst);
+    }, move |err| {
+        error_sync.errors.lock().unwrap().push(format!("output stream error: {}", err));
+    }, None)?;
+    Ok(stream)
+}
 
-            // If we're done recording, return early.
-            if !recording_2.load(Ordering::Relaxed) {
+// Hand the mapped input channels (then the reference channel, if there is one) to the writer thread
+// - Buffers captured before the stimulus started are thrown away
+// - The buffer the stimulus starts in is recorded from the frame it starts on
+// - Once the target number of frames has been recorded the rest are ignored
+// - Xruns while recording, and samples that don't fit in the ring buffer, are counted
+// Everything the callback needs is set up here, so it doesn't allocate, lock or block
+// T is the sample type of the device, W the sample type of the WAV file
+fn build_input_stream<T, W>(device: &cpal::Device, config: &cpal::StreamConfig, input: Input) -> Result<(cpal::Stream, JoinHandle<()>)>
+where
+    T: SizedSample,
+    W: hound::Sample + FromSample<T> + Send + 'static,
+{
+    let channels = config.channels as usize;
+    let sample_rate = config.sample_rate.0 as f64;
+    let Input { inputs, reference, writer, ref_writer, target_frames, sync, done } = input;
+    let recorded_channels: Vec<usize> = inputs.iter().copied().chain(reference).collect();
+    let (mut producer, consumer) = RingBuffer::new(RING_BUFFER_SECONDS * config.sample_rate.0 as usize * recorded_channels.len());
+    let writer_sync = sync.clone();
+    let writer_thread = std::thread::spawn(move || write_recording::<W>(consumer, writer, ref_writer, target_frames, writer_sync, done));
+
+    let error_sync = sync.clone();
+    let mut recorded = 0;
+    let mut started = false;
+    let mut xruns = XrunDetector::new(config.sample_rate.0);
+
+    let stream = device.build_input_stream(config, move |buffer: &[T], info: &cpal::InputCallbackInfo| {
+        if recorded >= target_frames {
+            return;
+        }
+        let frames = buffer.len() / channels;
+        let timestamp = info.timestamp();
+        if xruns.is_xrun(timestamp.capture, frames) && started {
+            sync.input_xruns.fetch_add(1, Ordering::SeqCst);
+        }
+        let mut first_frame = 0;
+        if !started {
+            let stimulus_start = sync.stimulus_start.load(Ordering::SeqCst);
+            if stimulus_start == 0 {
                 return;
             }
-            // Otherwise write to the wav writer.
-            // Add conversation routines for different audio formats
-            match data {
-                cpal::StreamData::Input { buffer: cpal::UnknownTypeInputBuffer::U16(buffer) } => {
-                    if let Ok(mut guard) = writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for sample in buffer.iter() {
-                                let sample = cpal::Sample::to_i16(sample);
-                                writer.write_sample(sample).ok();
-                            }
-                        }
-                    }
-                },
-                cpal::StreamData::Input { buffer: cpal::UnknownTypeInputBuffer::I16(buffer) } => {
-                    if let Ok(mut guard) = writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for &sample in buffer.iter() {
-                                writer.write_sample(sample).ok();
-                            }
-                        }
-                    }
-                },
-                cpal::StreamData::Input { buffer: cpal::UnknownTypeInputBuffer::F32(buffer) } => {
-                    if let Ok(mut guard) = writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for &sample in buffer.iter() {
-                                writer.write_sample(sample).ok();
-                            }
-                        }
-                    }
-                },
-                _ => (),
+            // When the first frame of this buffer was captured, on the shared clock
+            let latency = timestamp.callback.duration_since(&timestamp.capture).unwrap_or_default();
+            let captured = sync.clock.elapsed().checked_sub(latency).unwrap_or_default();
+            let offset = (stimulus_start - 1) as f64 / 1e9 - captured.as_secs_f64();
+            first_frame = (offset * sample_rate).round().max(0.0) as usize;
+            if first_frame >= frames {
+                return;
             }
-        });
-    });
+            started = true;
+        }
 
-    // Give the threads time to play/record
-    // std::thread::sleep(std::time::Duration::from_secs(5));
-    std::thread::sleep(RECORDING_TIME);
-    recording.store(false, Ordering::Relaxed);
-    playing.store(false, Ordering::Relaxed);
+        let last_frame = frames.min(first_frame + target_frames - recorded);
+        let mut dropped = 0;
+        for frame in buffer[first_frame * channels..last_frame * channels].chunks(channels) {
+            for &channel in recorded_channels.iter() {
+                if producer.push(frame[channel].to_sample::<W>()).is_err() {
+                    dropped += 1;
+                }
+            }
+        }
+        if dropped > 0 {
+            sync.dropped_samples.fetch_add(dropped, Ordering::SeqCst);
+        }
 
-    writer.lock().unwrap().take().unwrap().finalize().expect("File write issue");
-    gen_writer.lock().unwrap().take().unwrap().finalize().expect("File write issue");
+        recorded += last_frame - first_frame;
+        sync.recorded_frames.store(recorded, Ordering::SeqCst);
+    }, move |err| {
+        error_sync.errors.lock().unwrap().push(format!("input stream error: {}", err));
+    }, None)?;
+    Ok((stream, writer_thread))
 }
 
-fn sample_format(format: cpal::SampleFormat) -> hound::SampleFormat {
-    match format {
-        cpal::SampleFormat::U16 => hound::SampleFormat::Int,
-        cpal::SampleFormat::I16 => hound::SampleFormat::Int,
-        cpal::SampleFormat::F32 => hound::SampleFormat::Float,
+// Take the recorded samples off the ring buffer and write them to the WAV files, away from the audio thread
+// - The samples come in frames of the mapped inputs followed by the reference channel, if there is one
+// - Carry on until the input has recorded everything (or the capture is stopped) and the ring buffer is empty
+// - Then finalize the files and signal that the recording is done
+fn write_recording<W: hound::Sample>(mut consumer: Consumer<W>, mut writer: WavFileWriter, mut ref_writer: Option<WavFileWriter>,
+    target_frames: usize, sync: Arc<StreamSync>, done: SyncSender<()>)
+{
+    let inputs = writer.spec().channels as usize;
+    let frame_len = inputs + ref_writer.is_some() as usize;
+    let mut position = 0;
+    loop {
+        // Check before emptying the ring buffer, so nothing pushed before the input finished is left behind
+        let finished = sync.recorded_frames.load(Ordering::SeqCst) >= target_frames || sync.stopped.load(Ordering::SeqCst);
+        while let Ok(sample) = consumer.pop() {
+            let result = match ref_writer.as_mut() {
+                Some(ref_writer) if position == inputs => ref_writer.write_sample(sample),
+                _ => writer.write_sample(sample),
+            };
+            if result.is_err() {
+                sync.dropped_samples.fetch_add(1, Ordering::SeqCst);
+            }
+            position = (position + 1) % frame_len;
+        }
+        if finished {
+            break;
+        }
+        std::thread::sleep(WRITER_POLL_INTERVAL);
     }
+
+    // The audio thread can't be told, so a file that can't be finished is reported with the stream errors
+    let finalized = writer.finalize().and_then(|_| ref_writer.map_or(Ok(()), |ref_writer| ref_writer.finalize()));
+    if let Err(e) = finalized {
+        sync.errors.lock().unwrap().push(format!("couldn't finish writing the recording: {}", e));
+    }
+    done.try_send(()).ok();
+}
+
+// The names of the input and output devices used for recording and playback
+// These identify the devices in the calibration file
+pub fn device_names() -> Result<(String, String)> {
+    let config = config_helpers::current()?;
+    backend_helpers::backend(&config).device_names(&config)
+}
+
+fn sample_format(format: cpal::SampleFormat) -> hound::SampleFormat {
+    if format.is_float() { hound::SampleFormat::Float } else { hound::SampleFormat::Int }
 }
 
-fn wav_spec_from_format(format: &cpal::Format) -> hound::WavSpec {
+// Unsigned samples are converted to signed before they're written, as that's what WAV files use
+fn wav_spec_from_config(channels: u16, config: &cpal::StreamConfig, format: cpal::SampleFormat) -> hound::WavSpec {
     hound::WavSpec {
-        channels: format.channels as _,
-        sample_rate: format.sample_rate.0 as _,
-        bits_per_sample: (format.data_type.sample_size() * 8) as _,
-        sample_format: sample_format(format.data_type),
+        channels,
+        sample_rate: config.sample_rate.0 as _,
+        bits_per_sample: (format.sample_size() * 8) as _,
+        sample_format: sample_format(format),
     }
 }
 
-#[cfg(not(feature = "default_card"))]
-fn get_soundcard(card_name: &str) -> Option<Device> {
-    // Use the default host for working with audio devices.
-    let host = cpal::default_host();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_stimulus_is_exactly_the_given_length_and_starts_at_zero() {
+        let stimulus = render_stimulus(48000, Duration::from_millis(250)).unwrap();
+        assert_eq!(stimulus.len(), 12000);
+        assert_eq!(stimulus[0], 0.0);
+    }
+
+    #[test]
+    fn the_reference_is_split_off_into_its_own_file() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(format!("audio_analyser_{}_{}.wav", name, std::process::id()));
+        let spec = |channels| hound::WavSpec { channels, sample_rate: 48000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let create = |name: &str, channels| {
+            let file = std::io::BufWriter::new(std::fs::File::create(path(name)).unwrap());
+            hound::WavWriter::new(artifact_helpers::Artifact::File(file), spec(channels)).unwrap()
+        };
+        let writer = create("recorded", 2);
+        let ref_writer = create("reference", 1);
 
-    let devices = host.devices().expect("");
-    println!("Looking for: {}", card_name);
-    for (_device_index, device) in devices.enumerate() {
-        let device_name = device.name().expect("");
-        if device_name == card_name {
-            println!("Found: {}", device_name);
-            return Some(device);
+        // Three frames of two inputs and the reference, all recorded before the writer starts
+        let (mut producer, consumer) = RingBuffer::<i16>::new(16);
+        for sample in 0..9 {
+            producer.push(sample).unwrap();
         }
+        let sync = Arc::new(StreamSync::new());
+        sync.recorded_frames.store(3, Ordering::SeqCst);
+        let (done, finished) = mpsc::sync_channel(1);
+        write_recording(consumer, writer, Some(ref_writer), 3, sync.clone(), done);
+        assert!(finished.try_recv().is_ok());
+        assert_eq!(sync.dropped_samples.load(Ordering::SeqCst), 0);
+
+        let read = |name: &str| {
+            let samples = hound::WavReader::open(path(name)).unwrap().samples::<i16>().map(Result::unwrap).collect::<Vec<_>>();
+            std::fs::remove_file(path(name)).unwrap();
+            samples
+        };
+        assert_eq!(read("recorded"), vec![0, 1, 3, 4, 6, 7]);
+        assert_eq!(read("reference"), vec![2, 5, 8]);
+    }
+
+    #[test]
+    fn an_external_generator_s_stimulus_comes_from_its_wav_file() {
+        let path = std::env::temp_dir().join(format!("audio_analyser_stimulus_{}.wav", std::process::id()));
+        let spec = hound::WavSpec { channels: 2, sample_rate: 44100, bits_per_sample: 32, sample_format: hound::SampleFormat::Float };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in [0.5f32, 0.0, -0.25, 0.0, 0.125, 0.0] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut config = Config::default();
+        config.capture.stimulus_wav = Some(path.to_str().unwrap().to_owned());
+        // The file's length replaces the requested one, and only its first channel is used
+        let stimulus = expected_stimulus(&config, 44100, Duration::from_secs(1));
+        let at_48k = expected_stimulus(&config, 48000, Duration::from_secs(1));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stimulus.unwrap(), vec![0.5, -0.25, 0.125]);
+        assert!(at_48k.is_err());
+    }
+
+    #[test]
+    fn the_tail_is_taken_from_the_config() {
+        let mut config = Config::default();
+        assert_eq!(tail_frames(&config, 48000), 4800);
+        config.capture.tail_ms = Some(250);
+        assert_eq!(tail_frames(&config, 44100), 11025);
     }
-    return None;
 }
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use crate::audio_helpers::{CaptureReport, CpalBackend};
+use crate::config_helpers::{Backend, Config};
+use crate::error_helpers::Result;
+use crate::simulation_helpers::SimulatedBackend;
+
+// Something that can play a stimulus and record the response
+// A capture has to write the generated, recorded (and reference, if one is mapped) WAV files,
+// starting the recording on the first sample of the stimulus, and report the sample counts
+pub trait AudioBackend {
+    // The names of the input and output devices, these identify them in the calibration and loopback files
+    fn device_names(&self, config: &Config) -> Result<(String, String)>;
+
+    fn capture(&self, config: &Config, length: Duration) -> Result<CaptureReport>;
+
+    // Record the response to a stimulus played by something else, without opening an output
+    // The expected stimulus is written as the generated WAV file, so the analysis runs the same as after a capture
+    fn record(&self, config: &Config, length: Duration) -> Result<CaptureReport>;
+
+    // Play the generator's stimulus without recording anything, counting the frames played
+    fn play(&self, config: &Config, length: Duration) -> Result<CaptureReport>;
+}
+
+pub fn backend(config: &Config) -> Box<dyn AudioBackend> {
+    match config.backend {
+        Backend::Cpal => Box::new(CpalBackend),
+        Backend::Simulated => Box::new(SimulatedBackend),
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error_helpers::Result;
+use crate::level_helpers::{self, Level, LevelUnit};
+use crate::{audio_helpers, config_helpers};
+use crate::result_helpers::ChannelResult;
+
+// Ignore channels that are this far below full scale when calibrating the input,
+// they're almost certainly not connected to the reference
+const MINIMUM_CALIBRATION_LEVEL: f64 = -60.0;
+
+// The calibration file stores, for each device and channel, the offset to convert dBFS into dBV
+//      dBV = dBFS + offset_db
+// For an input this is the voltage that reads as a full scale sine wave
+// For an output this is the voltage produced by a full scale sine wave
+// Channels are the physical channel numbers, counting from 1
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Calibration {
+    #[serde(default)]
+    pub devices: BTreeMap<String, DeviceCalibration>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeviceCalibration {
+    #[serde(default)]
+    pub input: Vec<ChannelCalibration>,
+    #[serde(default)]
+    pub output: Vec<ChannelCalibration>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelCalibration {
+    pub channel: u16,
+    pub offset_db: f64,
+}
+
+impl Calibration {
+    // A missing calibration file just means nothing has been calibrated yet
+    pub fn load(path: &str) -> Result<Calibration> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Calibration::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn input_offset(&self, device: &str, channel: u16) -> Option<f64> {
+        self.devices.get(device).and_then(|d| find_offset(&d.input, channel))
+    }
+
+    pub fn output_offset(&self, device: &str, channel: u16) -> Option<f64> {
+        self.devices.get(device).and_then(|d| find_offset(&d.output, channel))
+    }
+
+    fn set_input_offset(&mut self, device: &str, channel: u16, offset_db: f64) {
+        let device = self.devices.entry(device.to_owned()).or_default();
+        set_offset(&mut device.input, channel, offset_db);
+    }
+
+    fn set_output_offset(&mut self, device: &str, channel: u16, offset_db: f64) {
+        let device = self.devices.entry(device.to_owned()).or_default();
+        set_offset(&mut device.output, channel, offset_db);
+    }
+}
+
+fn find_offset(channels: &[ChannelCalibration], channel: u16) -> Option<f64> {
+    channels.iter().find(|c| c.channel == channel).map(|c| c.offset_db)
+}
+
+fn set_offset(channels: &mut Vec<ChannelCalibration>, channel: u16, offset_db: f64) {
+    match channels.iter_mut().find(|c| c.channel == channel) {
+        Some(existing) => existing.offset_db = offset_db,
+        None => channels.push(ChannelCalibration { channel, offset_db }),
+    }
+    channels.sort_by_key(|c| c.channel);
+}
+
+// Load the calibration file and apply the output calibration to the generator,
+// so levels can be requested in dBV and dBu
+// The generator level refers to the first output channel it plays on
+pub fn load_calibration() -> Result<Calibration> {
+    let calibration = Calibration::load(crate::CALIBRATION_PATH)?;
+    let (_, output_name) = crate::audio_helpers::device_names()?;
+    let output = crate::config_helpers::current()?.channels.first_output();
+    if let Some(offset_db) = calibration.output_offset(&output_name, output) {
+        level_helpers::set_output_full_scale_dbv(offset_db);
+    }
+    Ok(calibration)
+}
+
+// To calibrate the input
+// - Apply a sine wave of a known voltage (measured with a DMM) to the input channels
+// - Record it and find the level of each channel in dBFS
+// - The difference between the known voltage (in dBV) and the recorded level is the offset
+// Channels that don't see the reference are left alone, and a clipped reference can't be used
+pub fn calibrate_input(reference_vrms: f64) -> Result<Vec<(u16, f64)>> {
+    let mut calibration = Calibration::load(crate::CALIBRATION_PATH)?;
+    let offsets = measure_input_offsets(&mut calibration, reference_vrms)?;
+    calibration.save(crate::CALIBRATION_PATH)?;
+    Ok(offsets)
+}
+
+fn measure_input_offsets(calibration: &mut Calibration, reference_vrms: f64) -> Result<Vec<(u16, f64)>> {
+    if !reference_vrms.is_finite() || reference_vrms <= 0.0 {
+        fail!(Format, "The reference voltage must be greater than zero");
+    }
+    crate::audio_helpers::record_audio()?;
+    let clipping = crate::clip_helpers::check_clipping()?;
+    if let Some((input, _)) = clipping.inputs.iter().find(|(_, check)| check.clipped()) {
+        fail!(Analysis, "Input {} clipped, reduce the reference voltage or the input gain", input);
+    }
+    let levels = crate::wav_helpers::find_channel_levels(crate::RECORD_PATH)?;
+    let inputs = crate::result_helpers::input_labels(levels.len())?;
+
+    let (input_name, _) = crate::audio_helpers::device_names()?;
+    let reference_dbv = level_helpers::volts_to_dbv(reference_vrms);
+    let mut offsets = Vec::new();
+    for (&level_dbfs, &input) in levels.iter().zip(inputs.iter()) {
+        if level_dbfs < MINIMUM_CALIBRATION_LEVEL {
+            continue;
+        }
+        let offset_db = reference_dbv - level_dbfs;
+        calibration.set_input_offset(&input_name, input, offset_db);
+        offsets.push((input, offset_db));
+    }
+    if offsets.is_empty() {
+        fail!(Analysis, "No input channel recorded the reference signal");
+    }
+    Ok(offsets)
+}
+
+// To calibrate the output
+// - Play the test tone on just that output at a known level in dBFS, for as long as a measurement's stimulus
+//   (a longer --duration leaves more time for the DMM to settle)
+// - Measure the output voltage with a DMM while it plays
+// - The difference between the measured voltage (in dBV) and the generator level is the offset
+// The channel map and generator level are put back afterwards
+pub fn calibrate_output(channel: u16, level_dbfs: f64, measured_vrms: f64) -> Result<f64> {
+    let mut calibration = Calibration::load(crate::CALIBRATION_PATH)?;
+    let offset_db = measure_output_offset(&mut calibration, channel, level_dbfs, measured_vrms)?;
+    calibration.save(crate::CALIBRATION_PATH)?;
+    if channel == crate::config_helpers::current()?.channels.first_output() {
+        level_helpers::set_output_full_scale_dbv(offset_db);
+    }
+    Ok(offset_db)
+}
+
+fn measure_output_offset(calibration: &mut Calibration, channel: u16, level_dbfs: f64, measured_vrms: f64) -> Result<f64> {
+    if !measured_vrms.is_finite() || measured_vrms <= 0.0 {
+        fail!(Format, "The measured voltage must be greater than zero");
+    }
+    play_tone(channel, level_dbfs)?;
+    let (_, output_name) = crate::audio_helpers::device_names()?;
+    let offset_db = level_helpers::volts_to_dbv(measured_vrms) - level_dbfs;
+    calibration.set_output_offset(&output_name, channel, offset_db);
+    Ok(offset_db)
+}
+
+fn play_tone(channel: u16, level_dbfs: f64) -> Result<()> {
+    let config = config_helpers::current()?;
+    let previous_dbfs = level_helpers::level_dbfs();
+    level_helpers::set_level(Level::new(level_dbfs, LevelUnit::Dbfs))?;
+    let played = config_helpers::update(|config| config.channels.outputs = vec![channel])
+        .and_then(|_| audio_helpers::play_stimulus(audio_helpers::measurement_length(&config)));
+    config_helpers::replace(config);
+    level_helpers::set_level(Level::new(previous_dbfs, LevelUnit::Dbfs))?;
+    played.map(|_| ())
+}
+
+// Convert the recorded channel levels from dBFS to dBV,
+// leaving out any channel that hasn't been calibrated
+pub fn input_levels_dbv(calibration: &Calibration, results: &[ChannelResult]) -> Result<Vec<Option<f64>>> {
+    let (input_name, _) = crate::audio_helpers::device_names()?;
+    Ok(results.iter()
+        .map(|result| calibration.input_offset(&input_name, result.input).map(|offset| result.level_dbfs + offset))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_helpers::SimulationSettings;
+    use crate::simulation_helpers::tests::use_dut;
+
+    #[test]
+    fn an_offset_replaces_the_channel_s_and_stays_in_channel_order() {
+        let mut channels = Vec::new();
+        set_offset(&mut channels, 2, 1.0);
+        set_offset(&mut channels, 1, 3.0);
+        set_offset(&mut channels, 2, 5.0);
+        assert_eq!(channels.iter().map(|c| (c.channel, c.offset_db)).collect::<Vec<_>>(), vec![(1, 3.0), (2, 5.0)]);
+        assert_eq!(find_offset(&channels, 2), Some(5.0));
+        assert_eq!(find_offset(&channels, 3), None);
+    }
+
+    #[test]
+    fn the_input_offset_takes_the_reference_to_its_voltage() {
+        // The -6 dBFS tone through -6 dB records at -12 dBFS, input 2 isn't connected to anything
+        let _dut = use_dut(SimulationSettings { gain_db: -6.0, crosstalk: vec![vec![1.0, 0.0], vec![0.0, 0.0]], ..Default::default() });
+        let mut calibration = Calibration::default();
+        let offsets = measure_input_offsets(&mut calibration, 2.0).unwrap();
+        let expected_db = level_helpers::volts_to_dbv(2.0) + 12.0;
+        assert_eq!(offsets.len(), 1);
+        assert_eq!(offsets[0].0, 1);
+        assert!((offsets[0].1 - expected_db).abs() < 0.1, "{:?}", offsets);
+        assert_eq!(calibration.input_offset("Simulated input", 1), Some(offsets[0].1));
+        assert_eq!(calibration.input_offset("Simulated input", 2), None);
+    }
+
+    #[test]
+    fn inputs_that_dont_see_the_reference_cant_be_calibrated() {
+        let _dut = use_dut(SimulationSettings { crosstalk: vec![vec![0.0, 0.0], vec![0.0, 0.0]], ..Default::default() });
+        assert!(measure_input_offsets(&mut Calibration::default(), 1.0).is_err());
+        assert!(measure_input_offsets(&mut Calibration::default(), 0.0).is_err());
+    }
+
+    #[test]
+    fn the_output_offset_takes_the_generator_level_to_the_measured_voltage() {
+        let _dut = use_dut(SimulationSettings::default());
+        let mut calibration = Calibration::default();
+        // 0.25 Vrms is -12.04 dBV, so full scale is 7.96 dBV
+        let offset_db = measure_output_offset(&mut calibration, 2, -20.0, 0.25).unwrap();
+        assert!((offset_db - 7.96).abs() < 0.01, "{} dB", offset_db);
+        assert_eq!(calibration.output_offset("Simulated output", 2), Some(offset_db));
+        assert_eq!(calibration.output_offset("Simulated output", 1), None);
+        // The tone's level and channel are put back afterwards
+        assert!((level_helpers::level_dbfs() + 6.0).abs() < 1e-9);
+        assert!(config_helpers::current().unwrap().channels.outputs.is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::config_helpers::ClipAction;
+use crate::error_helpers::Result;
+use crate::level_helpers::{self, Level, LevelUnit};
+use crate::{audio_helpers, result_helpers, wav_helpers};
+
+// Samples this close to full scale (about -0.01 dBFS) count as full scale
+const FULL_SCALE_THRESHOLD: f64 = 0.999;
+// A signal is taken to have clipped when this many samples in a row sit at full scale
+// A clean sine only touches full scale for a single sample at its peak
+const CLIP_RUN_LENGTH: usize = 3;
+// Unless the config says otherwise, clipping is only reported, and backing off turns the generator down 6 dB at a time
+const DEFAULT_CLIP_ACTION: ClipAction = ClipAction::Warn;
+const DEFAULT_BACK_OFF_DB: f64 = 6.0;
+// How many times to back off before giving up
+const MAX_BACK_OFF_RETRIES: usize = 4;
+
+// The result of the last clipping check
+static LAST_CHECK: Mutex<Option<ClippingReport>> = Mutex::new(None);
+
+// The peak level of a signal, and the longest run of samples at full scale
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ClipCheck {
+    pub peak_dbfs: f64,
+    pub full_scale_run: usize,
+}
+
+impl ClipCheck {
+    pub fn clipped(&self) -> bool {
+        self.full_scale_run >= CLIP_RUN_LENGTH
+    }
+}
+
+// The generated signal, and each recorded channel labelled by its physical input
+#[derive(Debug, Clone, Default)]
+pub struct ClippingReport {
+    pub generated: ClipCheck,
+    pub inputs: Vec<(u16, ClipCheck)>,
+}
+
+impl ClippingReport {
+    pub fn clipped(&self) -> bool {
+        self.generated.clipped() || self.inputs.iter().any(|(_, check)| check.clipped())
+    }
+
+    // A line per clipped signal
+    pub fn describe(&self) -> Vec<String> {
+        let mut clipped = Vec::new();
+        if self.generated.clipped() {
+            clipped.push(format!("generated signal clipped ({} samples at full scale)", self.generated.full_scale_run));
+        }
+        for (input, check) in self.inputs.iter().filter(|(_, check)| check.clipped()) {
+            clipped.push(format!("input {} clipped ({} samples at full scale, peak {:.2} dBFS)", input, check.full_scale_run, check.peak_dbfs));
+        }
+        clipped
+    }
+}
+
+pub fn last_check() -> Option<ClippingReport> {
+    LAST_CHECK.lock().unwrap().clone()
+}
+
+// Find the peak, and the longest run of consecutive samples at full scale
+// (the samples are already relative to full scale)
+pub fn check_signal(samples: &[f64]) -> ClipCheck {
+    let mut peak = 0f64;
+    let mut run = 0;
+    let mut longest_run = 0;
+    for sample in samples.iter().map(|sample| sample.abs()) {
+        peak = peak.max(sample);
+        run = if sample >= FULL_SCALE_THRESHOLD { run + 1 } else { 0 };
+        longest_run = longest_run.max(run);
+    }
+    ClipCheck { peak_dbfs: level_helpers::amplitude_to_dbfs(peak), full_scale_run: longest_run }
+}
+
+// Check the generated signal and every recorded channel, storing the per-channel results
+pub fn check_clipping() -> Result<ClippingReport> {
+    let (generated, _) = wav_helpers::read_artifact(crate::GENERATE_PATH)?;
+    let (recorded, _) = wav_helpers::read_artifact(crate::RECORD_PATH)?;
+    let inputs = result_helpers::input_labels(recorded.len())?;
+
+    let report = ClippingReport {
+        generated: check_signal(&generated[0]),
+        inputs: recorded.iter().zip(inputs.iter()).map(|(channel, &input)| (input, check_signal(channel))).collect(),
+    };
+    for (index, &(input, check)) in report.inputs.iter().enumerate() {
+        result_helpers::update_channel(index, input, |result| {
+            result.peak_dbfs = check.peak_dbfs;
+            result.full_scale_run = check.full_scale_run;
+            result.clipped = check.clipped();
+        });
+    }
+    *LAST_CHECK.lock().unwrap() = Some(report.clone());
+    Ok(report)
+}
+
+// Record, then check for clipping and deal with it as configured
+// - Warn reports it and carries on with the measurement
+// - Fail makes the measurement invalid
+// - BackOff turns the generator down and records again, until nothing clips (or it runs out of retries)
+// The generator level is left wherever backing off ended up, so it can be reported with the results
+pub fn record_checked() -> Result<audio_helpers::CaptureReport> {
+    let settings = crate::config_helpers::current()?.capture;
+    let action = settings.on_clip.unwrap_or(DEFAULT_CLIP_ACTION);
+    let back_off_db = settings.clip_back_off_db.unwrap_or(DEFAULT_BACK_OFF_DB);
+
+    let mut retries = 0;
+    loop {
+        let capture = audio_helpers::record_audio()?;
+        let report = check_clipping()?;
+        if !report.clipped() {
+            return Ok(capture);
+        }
+        let clipped = report.describe().join(", ");
+        match action {
+            ClipAction::Warn => {
+                eprintln!("Warning: {}", clipped);
+                return Ok(capture);
+            },
+            ClipAction::Fail => fail!(Analysis, "Measurement invalid: {}", clipped),
+            // An external generator can't be turned down from here
+            ClipAction::BackOff if settings.external => fail!(Analysis, "Measurement invalid: {}, turn the external generator down", clipped),
+            ClipAction::BackOff if retries < MAX_BACK_OFF_RETRIES => {
+                let level_dbfs = level_helpers::level_dbfs() - back_off_db;
+                eprintln!("{}, backing the generator off to {:.2} dBFS", clipped, level_dbfs);
+                level_helpers::set_level(Level::new(level_dbfs, LevelUnit::Dbfs))?;
+                retries += 1;
+            },
+            ClipAction::BackOff => fail!(Analysis, "Measurement invalid: {} after backing off {} times", clipped, retries),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_helpers::{self, SimulationSettings};
+    use crate::simulation_helpers::tests::use_dut;
+
+    fn on_clip(action: ClipAction) {
+        config_helpers::update(|config| config.capture.on_clip = Some(action)).unwrap();
+    }
+
+    #[test]
+    fn a_clean_sine_at_full_scale_hasnt_clipped() {
+        // 1 kHz at 48 kHz, with a sample on each peak
+        let sine: Vec<f64> = (0..480).map(|n| (2.0 * std::f64::consts::PI * n as f64 / 48.0).cos()).collect();
+        let check = check_signal(&sine);
+        assert!(check.peak_dbfs.abs() < 1e-9);
+        assert_eq!(check.full_scale_run, 1);
+        assert!(!check.clipped());
+    }
+
+    #[test]
+    fn three_samples_at_full_scale_have_clipped() {
+        let mut samples = vec![0.5, 1.0, 1.0, 0.0, 0.0, -1.0, -1.0, 0.0];
+        assert!(!check_signal(&samples).clipped());
+        samples[4] = -1.0;
+        let check = check_signal(&samples);
+        assert_eq!(check.full_scale_run, 3);
+        assert!(check.clipped());
+    }
+
+    #[test]
+    fn only_the_clipped_signals_are_described() {
+        let clipped = ClipCheck { peak_dbfs: 0.0, full_scale_run: 5 };
+        let report = ClippingReport { generated: ClipCheck::default(), inputs: vec![(1, ClipCheck::default()), (3, clipped)] };
+        assert!(report.clipped());
+        assert_eq!(report.describe(), vec!["input 3 clipped (5 samples at full scale, peak 0.00 dBFS)"]);
+    }
+
+    #[test]
+    fn backing_off_turns_the_generator_down_until_nothing_clips() {
+        // -6 dBFS through +9 dB clips, -12 dBFS doesn't
+        let _dut = use_dut(SimulationSettings { gain_db: 9.0, ..Default::default() });
+        on_clip(ClipAction::BackOff);
+        record_checked().unwrap();
+        assert!((level_helpers::level_dbfs() + 12.0).abs() < 1e-9);
+        assert!(!last_check().unwrap().clipped());
+    }
+
+    #[test]
+    fn backing_off_gives_up_after_its_retries() {
+        // Backing off 1 dB at a time, -6 dBFS through +12 dB still clips at -10 dBFS
+        let _dut = use_dut(SimulationSettings { gain_db: 12.0, ..Default::default() });
+        on_clip(ClipAction::BackOff);
+        config_helpers::update(|config| config.capture.clip_back_off_db = Some(1.0)).unwrap();
+        assert!(record_checked().is_err());
+        assert!((level_helpers::level_dbfs() + 6.0 + MAX_BACK_OFF_RETRIES as f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clipping_is_let_through_or_fails_as_configured() {
+        // -6 dBFS is about 3 dB over full scale after a linear gain of 2.8
+        let _dut = use_dut(SimulationSettings { polynomial: vec![2.8], ..Default::default() });
+        on_clip(ClipAction::Warn);
+        record_checked().unwrap();
+        assert!(last_check().unwrap().inputs.iter().all(|(_, check)| check.clipped()));
+        on_clip(ClipAction::Fail);
+        assert!(record_checked().is_err());
+        assert!((level_helpers::level_dbfs() + 6.0).abs() < 1e-9);
+    }
+}
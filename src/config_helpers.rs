@@ -0,0 +1,485 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error_helpers::{Error, Result};
+
+// The config in use, loaded from the config file the first time it's needed
+// Changes made at runtime only reach the config file when it's saved
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+// Settings that persist between runs, stored in the config file
+// Devices are stored by their full name, whatever was used to select them
+// TOML needs the plain values before the sections, so they go first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub backend: Backend,
+    pub host: Option<String>,
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    #[serde(default)]
+    pub stream: StreamSettings,
+    #[serde(default)]
+    pub channels: ChannelMap,
+    #[serde(default)]
+    pub capture: CaptureSettings,
+    #[serde(default)]
+    pub ranging: RangingSettings,
+    #[serde(default)]
+    pub simulation: SimulationSettings,
+    #[serde(default)]
+    pub jack: JackSettings,
+    #[serde(default)]
+    pub analysis: AnalysisSettings,
+    #[serde(default)]
+    pub output: OutputSettings,
+    #[serde(default)]
+    pub limits: LimitSettings,
+    #[serde(default)]
+    pub golden: GoldenSettings,
+}
+
+// Where the audio goes: real devices through cpal, or a simulated DUT
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Cpal,
+    Simulated,
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cpal" => Ok(Backend::Cpal),
+            "simulated" => Ok(Backend::Simulated),
+            _ => fail!(Format, "Unknown backend '{}', expected cpal or simulated", s),
+        }
+    }
+}
+
+// The virtual DUT used by the simulated backend, each input channel gets:
+// - A mix of the output channels, given by its row of the crosstalk matrix (inputs follow their own output if it's empty)
+// - The gain, then the polynomial (coefficients of x, x², x³, ..., a straight line if it's empty)
+// - Low-pass and high-pass filters (first order, at the given cutoffs)
+// - The delay, then noise at the given level (in dBFS) and clipping at full scale, as the ADC would
+// The noise is seeded, so a simulation always gives the same answer
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulationSettings {
+    pub gain_db: f64,
+    pub delay_ms: f64,
+    pub lowpass_hz: Option<f64>,
+    pub highpass_hz: Option<f64>,
+    pub polynomial: Vec<f64>,
+    pub noise_dbfs: Option<f64>,
+    pub crosstalk: Vec<Vec<f64>>,
+    pub seed: u64,
+}
+
+// The JACK ports to patch the analyser into, in channel order
+// - Each output plays into the port at its position (the first output into the first port, and so on)
+// - Each input records from the port at its position
+// If neither is given the analyser's ports are connected to the system playback and capture ports,
+// otherwise only the ports given are connected
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JackSettings {
+    pub outputs: Vec<String>,
+    pub inputs: Vec<String>,
+}
+
+// Anything left unset falls back to the device's default stream config
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamSettings {
+    pub sample_rate: Option<u32>,
+    pub sample_format: Option<SampleFormat>,
+    pub input_channels: Option<u16>,
+    pub output_channels: Option<u16>,
+    pub buffer_size: Option<u32>,
+}
+
+// How long the stimulus plays for each measurement (SECONDS_TO_RECORD unless it's set),
+// how long to keep recording once the stimulus has finished, to catch the DUT's latency and decay,
+// and what to do when the generated or recorded signal clips
+// In capture-only (external) mode nothing is played, the stimulus comes from a separate generator (or the DUT itself)
+// and is described by the test frequency and generator level, or by the stimulus WAV file if one is given
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CaptureSettings {
+    pub duration_s: Option<f64>,
+    pub tail_ms: Option<u32>,
+    pub on_clip: Option<ClipAction>,
+    pub clip_back_off_db: Option<f64>,
+    #[serde(default)]
+    pub external: bool,
+    pub stimulus_wav: Option<String>,
+}
+
+// Auto-ranging sets the generator level before each measurement, so the recording peaks the given headroom below full scale
+// The generator is kept between the minimum and maximum levels (in dBFS)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RangingSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    pub target_headroom_db: Option<f64>,
+    pub min_level_dbfs: Option<f64>,
+    pub max_level_dbfs: Option<f64>,
+}
+
+// The window applied to the audio before the FFT
+// Rectangular relies on the audio being trimmed to whole cycles, the others also cope with signals that aren't periodic
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AnalysisSettings {
+    #[serde(default)]
+    pub window: Window,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Window {
+    #[default]
+    Rectangular,
+    Hann,
+    BlackmanHarris,
+}
+
+impl FromStr for Window {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rectangular" => Ok(Window::Rectangular),
+            "hann" => Ok(Window::Hann),
+            "blackman-harris" => Ok(Window::BlackmanHarris),
+            _ => fail!(Format, "Unknown window '{}', expected rectangular, hann or blackman-harris", s),
+        }
+    }
+}
+
+// Where the WAV, CSV and SVG files each measurement leaves behind are written
+// - Each run gets its own directory under the output directory, named by its run ID
+// - Without a run ID they're written straight into the output directory (the working directory if it isn't set)
+// - in_memory keeps the captures in memory instead, and nothing is written at all
+// A run ID is only stored in the config file if it was given, the ones made up for each run aren't,
+// otherwise every later run would be written into the same directory
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OutputSettings {
+    pub directory: Option<String>,
+    pub run_id: Option<String>,
+    #[serde(default)]
+    pub in_memory: bool,
+    #[serde(skip)]
+    pub automatic_run_id: Option<String>,
+}
+
+impl OutputSettings {
+    // The run ID that was given, or else the one made up for this run
+    pub fn current_run_id(&self) -> Option<&String> {
+        self.run_id.as_ref().or(self.automatic_run_id.as_ref())
+    }
+}
+
+// What a DUT has to meet to pass, checked once each measurement has been analysed
+// - Ranges are in the result's own units: gain_db, snr_db and crosstalk_db in dB, thd in %
+// - Masks are lists of [frequency, limit] points, joined by straight lines on a log frequency scale,
+//      and only apply between their first and last frequencies
+// - The response mask is in dB of gain, the noise spectrum mask in dBFS per FFT bin
+// Anything that isn't set isn't checked
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LimitSettings {
+    pub gain_db: Option<Range>,
+    pub thd: Option<Range>,
+    pub snr_db: Option<Range>,
+    pub crosstalk_db: Option<Range>,
+    pub response: Option<Mask>,
+    pub noise_spectrum: Option<Mask>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Range {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Mask {
+    #[serde(default)]
+    pub lower: Vec<(f64, f64)>,
+    #[serde(default)]
+    pub upper: Vec<(f64, f64)>,
+}
+
+// Comparing each DUT against a golden unit: a known good unit's results, saved to a file (golden.toml if it isn't set)
+// - save stores the results that pass in the golden set, in place of any at the same frequency and level
+// - compare checks the results against the golden set, each delta within ± its tolerance:
+//      level_db and gain_db per input, thd in % (points, not a ratio), response_db at each frequency
+// Deltas without a tolerance aren't checked. Saving is only for this run, so it isn't stored in the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GoldenSettings {
+    pub file: Option<String>,
+    #[serde(default)]
+    pub compare: bool,
+    #[serde(skip)]
+    pub save: bool,
+    pub level_db: Option<f64>,
+    pub gain_db: Option<f64>,
+    pub thd: Option<f64>,
+    pub response_db: Option<f64>,
+}
+
+impl GoldenSettings {
+    pub fn path(&self) -> &str {
+        self.file.as_deref().unwrap_or(crate::GOLDEN_PATH)
+    }
+}
+
+// Warn just reports the clipping, Fail makes the measurement invalid,
+// BackOff turns the generator down and measures again
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipAction {
+    Warn,
+    Fail,
+    BackOff,
+}
+
+impl FromStr for ClipAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(ClipAction::Warn),
+            "fail" => Ok(ClipAction::Fail),
+            "back-off" => Ok(ClipAction::BackOff),
+            _ => fail!(Format, "Unknown clip action '{}', expected warn, fail or back-off", s),
+        }
+    }
+}
+
+// Which physical channels to play on and record from, numbered from 1 as they are on the interface
+// - The generator plays on the outputs (all of them if none are given), the rest are kept silent
+// - The inputs are recorded and analysed (all of them, apart from the reference, if none are given)
+// - The reference is an input with an output looped straight back to it, if there is one
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChannelMap {
+    #[serde(default)]
+    pub outputs: Vec<u16>,
+    #[serde(default)]
+    pub inputs: Vec<u16>,
+    pub reference: Option<u16>,
+}
+
+impl ChannelMap {
+    // The indices of the output channels in each frame, given how many the stream has
+    pub fn output_indices(&self, channels: u16) -> Result<Vec<usize>> {
+        if self.outputs.is_empty() {
+            return Ok((0..channels as usize).collect());
+        }
+        self.outputs.iter().map(|&output| channel_index(output, channels, "Output")).collect()
+    }
+
+    // The indices of the input channels in each frame, given how many the stream has
+    pub fn input_indices(&self, channels: u16) -> Result<Vec<usize>> {
+        if self.inputs.is_empty() {
+            let reference = self.reference_index(channels)?;
+            return Ok((0..channels as usize).filter(|&index| Some(index) != reference).collect());
+        }
+        self.inputs.iter().map(|&input| channel_index(input, channels, "Input")).collect()
+    }
+
+    pub fn reference_index(&self, channels: u16) -> Result<Option<usize>> {
+        self.reference.map(|reference| channel_index(reference, channels, "Reference")).transpose()
+    }
+
+    // The physical channel numbers of the channels in the recording, in order
+    pub fn input_labels(&self, recorded_channels: usize) -> Vec<u16> {
+        if self.inputs.is_empty() {
+            (1..).filter(|&input| Some(input) != self.reference).take(recorded_channels).collect()
+        } else {
+            self.inputs.clone()
+        }
+    }
+
+    // The output channel the generator level refers to
+    pub fn first_output(&self) -> u16 {
+        self.outputs.first().copied().unwrap_or(1)
+    }
+}
+
+fn channel_index(channel: u16, channels: u16, name: &str) -> Result<usize> {
+    if channel == 0 || channel > channels {
+        fail!(Format, "{} channel {} doesn't exist, the stream has channels 1 - {}", name, channel, channels);
+    }
+    Ok(channel as usize - 1)
+}
+
+// The sample formats that can be played, recorded and written to a WAV file
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    U16,
+    I16,
+    I32,
+    F32,
+}
+
+impl FromStr for SampleFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "u16" => Ok(SampleFormat::U16),
+            "i16" => Ok(SampleFormat::I16),
+            "i32" => Ok(SampleFormat::I32),
+            "f32" => Ok(SampleFormat::F32),
+            _ => fail!(Format, "Unknown sample format '{}', expected u16, i16, i32 or f32", s),
+        }
+    }
+}
+
+impl From<SampleFormat> for cpal::SampleFormat {
+    fn from(format: SampleFormat) -> cpal::SampleFormat {
+        match format {
+            SampleFormat::U16 => cpal::SampleFormat::U16,
+            SampleFormat::I16 => cpal::SampleFormat::I16,
+            SampleFormat::I32 => cpal::SampleFormat::I32,
+            SampleFormat::F32 => cpal::SampleFormat::F32,
+        }
+    }
+}
+
+impl Config {
+    // A missing config file just means everything is on its defaults
+    // The duration is checked here, rather than when the measurement starts
+    pub fn load(path: &str) -> Result<Config> {
+        let config: Config = match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+        config.capture.duration_s.map(crate::audio_helpers::check_duration).transpose()?;
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+pub fn current() -> Result<Config> {
+    let mut config = CONFIG.lock().unwrap();
+    if config.is_none() {
+        *config = Some(Config::load(crate::CONFIG_PATH)?);
+    }
+    Ok(config.clone().unwrap())
+}
+
+pub fn update<F: FnOnce(&mut Config)>(change: F) -> Result<()> {
+    let mut config = current()?;
+    change(&mut config);
+    *CONFIG.lock().unwrap() = Some(config);
+    Ok(())
+}
+
+// Use a whole config at once, in place of whatever was loaded or changed before
+pub fn replace(config: Config) {
+    *CONFIG.lock().unwrap() = Some(config);
+}
+
+pub fn save() -> Result<()> {
+    current()?.save(crate::CONFIG_PATH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every section set, so anything TOML can't write shows up
+    fn full_config() -> Config {
+        Config {
+            backend: Backend::Simulated,
+            host: Some(String::from("alsa")),
+            input_device: Some(String::from("Scarlett 2i2")),
+            output_device: Some(String::from("Scarlett 2i2")),
+            stream: StreamSettings { sample_rate: Some(48000), sample_format: Some(SampleFormat::F32), ..Default::default() },
+            channels: ChannelMap { outputs: vec![1, 2], inputs: vec![1, 2], reference: Some(3) },
+            capture: CaptureSettings { tail_ms: Some(200), on_clip: Some(ClipAction::BackOff), ..Default::default() },
+            ranging: RangingSettings { enabled: true, target_headroom_db: Some(6.0), ..Default::default() },
+            simulation: SimulationSettings {
+                gain_db: -3.0,
+                crosstalk: vec![vec![1.0, 0.01], vec![0.01, 1.0]],
+                noise_dbfs: Some(-100.0),
+                ..Default::default()
+            },
+            jack: JackSettings { outputs: vec![String::from("system:playback_1")], inputs: Vec::new() },
+            analysis: AnalysisSettings { window: Window::BlackmanHarris },
+            output: OutputSettings {
+                directory: Some(String::from("results")),
+                in_memory: true,
+                automatic_run_id: Some(String::from("20240131-142530.123")),
+                ..Default::default()
+            },
+            limits: LimitSettings {
+                thd: Some(Range { min: None, max: Some(0.01) }),
+                response: Some(Mask { lower: vec![(20.0, -3.0), (20000.0, -3.0)], upper: vec![(20.0, 1.0), (20000.0, 1.0)] }),
+                ..Default::default()
+            },
+            golden: GoldenSettings { compare: true, response_db: Some(0.25), ..Default::default() },
+        }
+    }
+
+    #[test]
+    fn channels_are_numbered_from_one_and_the_reference_isnt_an_input() {
+        let channels = ChannelMap { outputs: vec![2], inputs: Vec::new(), reference: Some(2) };
+        assert_eq!(channels.output_indices(4).unwrap(), vec![1]);
+        assert_eq!(channels.input_indices(4).unwrap(), vec![0, 2, 3]);
+        assert_eq!(channels.input_labels(3), vec![1, 3, 4]);
+        assert_eq!(channels.first_output(), 2);
+        assert!(ChannelMap { outputs: vec![5], ..Default::default() }.output_indices(4).is_err());
+        assert!(ChannelMap { inputs: vec![0], ..Default::default() }.input_indices(4).is_err());
+    }
+
+    #[test]
+    fn config_round_trips_through_the_config_file() {
+        let path = std::env::temp_dir().join(format!("audio_analyser_config_{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        let config = full_config();
+        config.save(path).unwrap();
+        let loaded = Config::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(toml::to_string(&loaded).unwrap(), toml::to_string(&config).unwrap());
+        assert_eq!(loaded.backend, Backend::Simulated);
+        assert_eq!(loaded.simulation.crosstalk, config.simulation.crosstalk);
+        assert_eq!(loaded.limits.response.unwrap().upper, vec![(20.0, 1.0), (20000.0, 1.0)]);
+        // Made up run IDs aren't kept, or every later run would share the directory
+        assert_eq!(loaded.output.automatic_run_id, None);
+    }
+
+    #[test]
+    fn durations_the_analysis_cant_use_arent_loaded() {
+        let path = std::env::temp_dir().join(format!("audio_analyser_duration_{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        for &duration_s in [-1.0, 0.0, 1.0, 1.5, f64::INFINITY].iter() {
+            std::fs::write(path, format!("[capture]\nduration_s = {:?}\n", duration_s)).unwrap();
+            assert!(Config::load(path).is_err(), "{} seconds was loaded", duration_s);
+        }
+        std::fs::write(path, "[capture]\nduration_s = nan\n").unwrap();
+        assert!(Config::load(path).is_err());
+        std::fs::write(path, "[capture]\nduration_s = 2.0\n").unwrap();
+        assert_eq!(Config::load(path).unwrap().capture.duration_s, Some(2.0));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        let text = toml::to_string(&Config::default()).unwrap();
+        let loaded: Config = toml::from_str(&text).unwrap();
+        assert_eq!(toml::to_string(&loaded).unwrap(), text);
+    }
+}
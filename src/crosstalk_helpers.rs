@@ -0,0 +1,65 @@
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use crate::error_helpers::Result;
+use crate::{audio_helpers, config_helpers, fft_helpers, result_helpers, wav_helpers};
+
+// A stereo interface is measured left into right and right into left, unless the outputs are given
+const DEFAULT_OUTPUTS: [u16; 2] = [1, 2];
+
+// What playing the test tone on one output put on each input
+// The input that picked up the most is taken as the one the output is looped to,
+// the crosstalk into the others is relative to it (so it's 0 dB for the driven input itself)
+#[derive(Debug, Clone, Serialize)]
+pub struct Crosstalk {
+    pub output: u16,
+    pub driven_input: u16,
+    pub channels: Vec<CrosstalkChannel>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrosstalkChannel {
+    pub input: u16,
+    pub level_dbfs: f64,
+    pub crosstalk_db: f64,
+}
+
+// Measure the crosstalk between channels
+// - Play the test tone on each output in turn, keeping the others silent
+// - Find the level of the tone (not the noise) on every recorded input
+// - Put the channel map back afterwards
+pub fn measure_crosstalk() -> Result<Vec<Crosstalk>> {
+    let config = config_helpers::current()?;
+    if config.capture.external {
+        fail!(Format, "Crosstalk plays on one output at a time, it can't be measured in capture-only mode");
+    }
+    let outputs = if config.channels.outputs.is_empty() { DEFAULT_OUTPUTS.to_vec() } else { config.channels.outputs.clone() };
+    let crosstalk = outputs.iter().map(|&output| measure_output(output)).collect();
+    config_helpers::replace(config);
+    crosstalk
+}
+
+fn measure_output(output: u16) -> Result<Crosstalk> {
+    config_helpers::update(|config| config.channels.outputs = vec![output])?;
+    let report = audio_helpers::record_audio()?;
+    let recorded = wav_helpers::read_stimulus_section(crate::RECORD_PATH)?;
+    if recorded.is_empty() {
+        fail!(Analysis, "No inputs were recorded, there's nothing to measure the crosstalk on");
+    }
+
+    let frequency = crate::FREQUENCY.load(Ordering::Relaxed);
+    let levels = recorded.iter()
+        .map(|channel| fft_helpers::tone_level_dbfs(channel, report.sample_rate, frequency))
+        .collect::<Result<Vec<_>>>()?;
+    let inputs = result_helpers::input_labels(recorded.len())?;
+    let (driven, driven_level) = levels.iter().copied().enumerate()
+        .fold((0, f64::NEG_INFINITY), |loudest, (index, level)| if level > loudest.1 { (index, level) } else { loudest });
+    Ok(Crosstalk {
+        output,
+        driven_input: inputs[driven],
+        channels: inputs.iter().zip(levels)
+            .map(|(&input, level_dbfs)| CrosstalkChannel { input, level_dbfs, crosstalk_db: level_dbfs - driven_level })
+            .collect(),
+    })
+}
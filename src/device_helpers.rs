@@ -0,0 +1,238 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use serde::Serialize;
+
+use crate::config_helpers::{self, Config, StreamSettings};
+use crate::error_helpers::{Error, Result};
+
+// The sample formats the recorder and generator know how to handle
+const SUPPORTED_SAMPLE_FORMATS: [cpal::SampleFormat; 4] = [
+    cpal::SampleFormat::U16, cpal::SampleFormat::I16, cpal::SampleFormat::I32, cpal::SampleFormat::F32,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostInfo {
+    pub name: String,
+    // Whether devices are selected from this host
+    pub selected: bool,
+    pub devices: Vec<DeviceInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    // Only the selected host's devices are numbered, as an index always selects from that host
+    pub index: Option<usize>,
+    pub name: String,
+    pub is_default_input: bool,
+    pub is_default_output: bool,
+    pub input_formats: Vec<FormatRange>,
+    pub output_formats: Vec<FormatRange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FormatRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub min_buffer_size: Option<u32>,
+    pub max_buffer_size: Option<u32>,
+    pub sample_format: String,
+}
+
+impl DeviceInfo {
+    pub fn max_channels(&self, direction: Direction) -> u16 {
+        let formats = match direction {
+            Direction::Input => &self.input_formats,
+            Direction::Output => &self.output_formats,
+        };
+        formats.iter().map(|f| f.channels).max().unwrap_or(0)
+    }
+}
+
+impl From<cpal::SupportedStreamConfigRange> for FormatRange {
+    fn from(range: cpal::SupportedStreamConfigRange) -> FormatRange {
+        let (min_buffer_size, max_buffer_size) = match range.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } => (Some(*min), Some(*max)),
+            cpal::SupportedBufferSize::Unknown => (None, None),
+        };
+        FormatRange {
+            channels: range.channels(),
+            min_sample_rate: range.min_sample_rate().0,
+            max_sample_rate: range.max_sample_rate().0,
+            min_buffer_size,
+            max_buffer_size,
+            sample_format: format!("{:?}", range.sample_format()),
+        }
+    }
+}
+
+// Every device on every available host, with the formats it supports in each direction
+// The device index is its position in the selected host's device list, which is what selection by index uses
+pub fn list_devices(config: &Config) -> Result<Vec<HostInfo>> {
+    let selected = host(config)?.id();
+    let mut hosts = Vec::new();
+    for host_id in cpal::available_hosts() {
+        let host = cpal::host_from_id(host_id)?;
+        let default_input = host.default_input_device().and_then(|d| d.name().ok());
+        let default_output = host.default_output_device().and_then(|d| d.name().ok());
+        let mut devices = Vec::new();
+        for (index, device) in host.devices()?.enumerate() {
+            let name = device.name()?;
+            // Devices that can't report their formats in a direction simply don't support it
+            let input_formats = device.supported_input_configs()
+                .map(|formats| formats.map(FormatRange::from).collect())
+                .unwrap_or_default();
+            let output_formats = device.supported_output_configs()
+                .map(|formats| formats.map(FormatRange::from).collect())
+                .unwrap_or_default();
+            devices.push(DeviceInfo {
+                index: if host_id == selected { Some(index) } else { None },
+                is_default_input: default_input.as_ref() == Some(&name),
+                is_default_output: default_output.as_ref() == Some(&name),
+                name,
+                input_formats,
+                output_formats,
+            });
+        }
+        hosts.push(HostInfo { name: host_id.name().to_owned(), selected: host_id == selected, devices });
+    }
+    Ok(hosts)
+}
+
+// The host from the config file, or the platform's default host if none has been selected
+// Hosts are selected by name, ignoring case (e.g. alsa, jack, wasapi or asio)
+pub fn host(config: &Config) -> Result<cpal::Host> {
+    let name = match &config.host {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+    let host_ids = cpal::available_hosts();
+    match host_ids.iter().find(|host_id| host_id.name().eq_ignore_ascii_case(name)) {
+        Some(&host_id) => Ok(cpal::host_from_id(host_id)?),
+        // JACK is only there when it's been built in
+        None if name.eq_ignore_ascii_case("jack") && cfg!(not(feature = "jack")) =>
+            fail!(Device, "JACK support isn't built in, rebuild with --features jack"),
+        None => fail!(Device, "No host named '{}', the available hosts are {}", name,
+            host_ids.iter().map(|host_id| host_id.name()).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+// The input device from the config file, or the host's default input device if none has been selected
+pub fn input_device(host: &cpal::Host, config: &Config) -> Result<cpal::Device> {
+    match &config.input_device {
+        Some(selector) => find_device(host, selector, Direction::Input),
+        None => host.default_input_device().ok_or_else(|| Error::Device(String::from("No default input device"))),
+    }
+}
+
+// The output device from the config file, or the host's default output device if none has been selected
+pub fn output_device(host: &cpal::Host, config: &Config) -> Result<cpal::Device> {
+    match &config.output_device {
+        Some(selector) => find_device(host, selector, Direction::Output),
+        None => host.default_output_device().ok_or_else(|| Error::Device(String::from("No default output device"))),
+    }
+}
+
+// Find a device that supports the given direction, with the selector being (in order of preference)
+// - The index of the device, as shown in the device list
+// - The exact name of the device
+// - Part of the name of the device (ignoring case), as long as only one device matches
+pub fn find_device(host: &cpal::Host, selector: &str, direction: Direction) -> Result<cpal::Device> {
+    if let Ok(index) = selector.parse::<usize>() {
+        let device = host.devices()?.nth(index).ok_or_else(|| Error::Device(format!("No device at index {}", index)))?;
+        if !supports(&device, direction) {
+            fail!(Device, "Device {} ({}) doesn't support {:?}", index, device.name()?, direction);
+        }
+        return Ok(device);
+    }
+
+    let devices: Vec<_> = host.devices()?
+        .filter(|device| supports(device, direction))
+        .collect();
+    let names = devices.iter().map(|device| device.name()).collect::<Result<Vec<_>, _>>()?;
+    if let Some(position) = names.iter().position(|name| name == selector) {
+        return Ok(devices.into_iter().nth(position).unwrap());
+    }
+
+    let lower_selector = selector.to_lowercase();
+    let matches: Vec<_> = names.iter()
+        .enumerate()
+        .filter(|(_, name)| name.to_lowercase().contains(&lower_selector))
+        .collect();
+    match matches.as_slice() {
+        [] => fail!(Device, "No {:?} device matches '{}'", direction, selector),
+        [(position, _)] => Ok(devices.into_iter().nth(*position).unwrap()),
+        _ => fail!(Device, "'{}' matches more than one {:?} device: {}", selector, direction,
+            matches.iter().map(|(_, name)| name.as_str()).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+fn supports(device: &cpal::Device, direction: Direction) -> bool {
+    match direction {
+        Direction::Input => device.supported_input_configs().map(|mut c| c.next().is_some()).unwrap_or(false),
+        Direction::Output => device.supported_output_configs().map(|mut c| c.next().is_some()).unwrap_or(false),
+    }
+}
+
+// Work out the stream config for a device from the requested settings
+// - Anything not requested comes from the device's default config
+// - The channel count, sample format and sample rate must all be supported together
+// - A fixed buffer size must be within the range the device supports (if it says)
+pub fn stream_config(device: &cpal::Device, direction: Direction, settings: &StreamSettings)
+    -> Result<(cpal::StreamConfig, cpal::SampleFormat)> {
+    let (default, ranges) = match direction {
+        Direction::Input => (device.default_input_config()?, device.supported_input_configs()?.collect::<Vec<_>>()),
+        Direction::Output => (device.default_output_config()?, device.supported_output_configs()?.collect::<Vec<_>>()),
+    };
+    let channels = match direction {
+        Direction::Input => settings.input_channels,
+        Direction::Output => settings.output_channels,
+    }.unwrap_or_else(|| default.channels());
+    let sample_rate = settings.sample_rate.map(cpal::SampleRate).unwrap_or_else(|| default.sample_rate());
+    let sample_format = settings.sample_format.map(cpal::SampleFormat::from).unwrap_or_else(|| default.sample_format());
+    if !SUPPORTED_SAMPLE_FORMATS.contains(&sample_format) {
+        fail!(Format, "{:?} samples aren't supported, select one of u16, i16, i32 or f32", sample_format);
+    }
+
+    let range = ranges.iter()
+        .find(|range| range.channels() == channels
+            && range.sample_format() == sample_format
+            && range.min_sample_rate() <= sample_rate
+            && sample_rate <= range.max_sample_rate())
+        .ok_or_else(|| Error::Format(format!("{:?} device doesn't support {} channels of {:?} at {} Hz",
+            direction, channels, sample_format, sample_rate.0)))?;
+
+    let buffer_size = match settings.buffer_size {
+        Some(frames) => {
+            if let cpal::SupportedBufferSize::Range { min, max } = range.buffer_size() {
+                if frames < *min || frames > *max {
+                    fail!(Format, "{:?} buffer size of {} frames is outside the supported range of {} - {}", direction, frames, min, max);
+                }
+            }
+            cpal::BufferSize::Fixed(frames)
+        },
+        None => cpal::BufferSize::Default,
+    };
+    Ok((cpal::StreamConfig { channels, sample_rate, buffer_size }, sample_format))
+}
+
+// Select a device on the selected host and store its full name in the config file, returning the name
+// Only the device is written to the config file, other runtime changes are left out
+pub fn select_device(selector: &str, direction: Direction) -> Result<String> {
+    let host = host(&config_helpers::current()?)?;
+    let name = find_device(&host, selector, direction)?.name()?;
+    let set_device = |config: &mut Config| match direction {
+        Direction::Input => config.input_device = Some(name.clone()),
+        Direction::Output => config.output_device = Some(name.clone()),
+    };
+    config_helpers::update(set_device)?;
+    let mut saved = Config::load(crate::CONFIG_PATH)?;
+    set_device(&mut saved);
+    saved.save(crate::CONFIG_PATH)?;
+    Ok(name)
+}
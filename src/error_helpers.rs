@@ -0,0 +1,86 @@
+use std::fmt;
+
+use failure::Fail;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+// Everything that can go wrong, grouped by what failed
+// - Device: finding a host or device, or asking it what it supports
+// - Stream: building, starting or running the audio streams, including captures that come out invalid
+// - File: reading or writing any of the files (WAV, CSV, SVG, calibration, loopback and config files)
+// - Format: a setting, argument or file that doesn't make sense (unknown names, unsupported formats, bad numbers)
+// - Analysis: a measurement that can't give a result (clipping, no signal, not enough audio to analyse)
+// - Cancelled: a measurement that was asked to stop before it finished
+#[derive(Debug)]
+pub enum Error {
+    Device(String),
+    Stream(String),
+    File(String),
+    Format(String),
+    Analysis(String),
+    Cancelled(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Device(message) | Error::Stream(message) | Error::File(message)
+                | Error::Format(message) | Error::Analysis(message) | Error::Cancelled(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Fail for Error {}
+
+impl Error {
+    // The kind of error in lower case, as it's named in reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Device(_) => "device",
+            Error::Stream(_) => "stream",
+            Error::File(_) => "file",
+            Error::Format(_) => "format",
+            Error::Analysis(_) => "analysis",
+            Error::Cancelled(_) => "cancelled",
+        }
+    }
+}
+
+// Reported as its kind and message, e.g. {"kind": "stream", "message": "..."}
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut error = serializer.serialize_struct("Error", 2)?;
+        error.serialize_field("kind", self.kind())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Return early with an error of the given kind, e.g. fail!(Format, "Unknown backend '{}'", name)
+macro_rules! fail {
+    ($kind:ident, $($arg:tt)*) => {
+        return Err($crate::error_helpers::Error::$kind(format!($($arg)*)))
+    };
+}
+
+// Errors from the libraries are put into the kind they belong to, keeping their message
+macro_rules! error_kind {
+    ($kind:ident: $($from:ty),* $(,)?) => {
+        $(
+            impl From<$from> for Error {
+                fn from(e: $from) -> Error {
+                    Error::$kind(e.to_string())
+                }
+            }
+        )*
+    };
+}
+
+error_kind!(Device: cpal::HostUnavailable, cpal::DevicesError, cpal::DeviceNameError,
+    cpal::DefaultStreamConfigError, cpal::SupportedStreamConfigsError);
+error_kind!(Stream: cpal::BuildStreamError, cpal::PlayStreamError, cpal::PauseStreamError);
+error_kind!(File: std::io::Error, hound::Error, csv::Error);
+error_kind!(Format: toml::de::Error, toml::ser::Error, serde_json::Error, serde_yaml::Error, std::num::ParseIntError, std::num::ParseFloatError);
+#[cfg(feature = "jack")]
+error_kind!(Device: jack::Error);
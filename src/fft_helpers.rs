@@ -9,105 +9,243 @@ use csv::Writer;
 
 use std::sync::atomic::{Ordering};
 
-// TODO: Should be able to get the sampling rate directly from the soundcard format info
-const SAMPLE_RATE: usize = 48000;
+use crate::config_helpers::{self, Window};
+use crate::error_helpers::{Error, Result};
+use crate::{artifact_helpers, result_helpers, wav_helpers};
+
 // Cut some of the first and last samples to ensure the audio is clean
-const SAMPLE_SIZE: usize = (crate::SECONDS_TO_RECORD - 1.5 as usize) * SAMPLE_RATE;
-const SAMPLE_OFFSET: usize = 0.5 as usize * SAMPLE_RATE;
+// These are in seconds, and get scaled by the sample rate of the recording
+const OFFSET_SECONDS: f64 = 0.5;
+// The analysis stops this long before the end of the stimulus
+const END_MARGIN_SECONDS: usize = 1;
+// A stimulus has to be longer than this to leave anything to analyse
+pub const MIN_ANALYSIS_SECONDS: f64 = OFFSET_SECONDS + END_MARGIN_SECONDS as f64;
+// The harmonics reported individually, from the 2nd up to this one
+const HIGHEST_HARMONIC: usize = 5;
+// Each harmonic is the highest bin this close to where it should be, to allow for leakage
+const HARMONIC_SEARCH_BINS: usize = 2;
+
+// What was found in the spectrum of one signal
+// - The frequency of the fundamental, and the THD+N as a percentage
+// - The level of each harmonic from the 2nd up, relative to the fundamental (dBc),
+//      stopping at the highest harmonic or the Nyquist frequency
+pub struct SpectralPeak {
+    pub frequency: f32,
+    pub thd: f64,
+    pub harmonics: Vec<f64>,
+    level: f64,
+}
+
+// The magnitude of each bin up to the Nyquist frequency, as a peak amplitude relative to full scale
+// Bin n is centred on n × bin Hz
+#[derive(Debug, Clone)]
+pub struct Spectrum {
+    pub bin: f64,
+    pub amplitudes: Vec<f64>,
+}
 
 // This will analyse both the generated and recorded audio
-// - Read the audio samples in
+// - Read the audio samples in, one channel at a time
 // - Trim them down to a window of samples between two zero-cross points
-// - Run the FFT calculation
+// - Run the FFT calculation, through the configured window
 // - Find the fundamental frequency, then use that to calculate the THD+N from the remaining signal
-pub fn calculate_peak_frequency() {
-    let (mut gen_signal, gen_wave_spec) = read_wav_file(crate::GENERATE_PATH);
-    gen_signal = find_zero_crosses(gen_signal);
-    if let Some((generated_peak, generated_thd)) = find_spectral_peak(gen_signal, gen_wave_spec, "generated") {
-        crate::GENERATED_PEAK_FREQUENCY.store(f32::to_bits(generated_peak), Ordering::SeqCst);
-        crate::GENERATED_THD.store(f64::to_bits(generated_thd), Ordering::SeqCst);
+// Each recorded channel is labelled (and its plots named) by its physical input number
+pub fn calculate_peak_frequency() -> Result<()> {
+    let (gen_channels, gen_wave_spec) = wav_helpers::read_artifact(crate::GENERATE_PATH)?;
+    let gen_sample_rate = gen_wave_spec.sample_rate as usize;
+    let stimulus_frames = gen_channels[0].len();
+    let window = config_helpers::current()?.analysis.window;
+    let gen_signal = find_zero_crosses(to_complex(&gen_channels[0]), gen_sample_rate, stimulus_frames)?;
+    if let Some(generated) = find_spectral_peak(gen_signal, gen_sample_rate as f32, window, "generated")? {
+        crate::GENERATED_PEAK_FREQUENCY.store(f32::to_bits(generated.frequency), Ordering::SeqCst);
+        crate::GENERATED_THD.store(f64::to_bits(generated.thd), Ordering::SeqCst);
     }
 
-    let (mut rec_signal, rec_wave_spec) = read_wav_file(crate::RECORD_PATH);
-    rec_signal = find_zero_crosses(rec_signal);
-    if let Some((recorded_peak, recorded_thd)) = find_spectral_peak(rec_signal, rec_wave_spec, "recorded") {
-        crate::RECORDED_PEAK_FREQUENCY.store(f32::to_bits(recorded_peak), Ordering::SeqCst);
-        crate::RECORDED_THD.store(f64::to_bits(recorded_thd), Ordering::SeqCst);
+    let (rec_channels, rec_wave_spec) = wav_helpers::read_artifact(crate::RECORD_PATH)?;
+    let rec_sample_rate = rec_wave_spec.sample_rate as usize;
+    let inputs = result_helpers::input_labels(rec_channels.len())?;
+    for (index, (channel, &input)) in rec_channels.iter().zip(inputs.iter()).enumerate() {
+        let rec_signal = find_zero_crosses(to_complex(channel), rec_sample_rate, stimulus_frames)?;
+        let filename = format!("recorded_in{}", input);
+        // Without a tone there's no THD+N, rather than none at all, so it can't pass a THD+N limit
+        let recorded = find_spectral_peak(rec_signal, rec_sample_rate as f32, window, &filename)?
+            .unwrap_or(SpectralPeak { frequency: 0.0, thd: f64::NAN, harmonics: Vec::new(), level: 0.0 });
+        // The first channel is also kept as the headline result
+        if index == 0 {
+            crate::RECORDED_PEAK_FREQUENCY.store(f32::to_bits(recorded.frequency), Ordering::SeqCst);
+            crate::RECORDED_THD.store(f64::to_bits(recorded.thd), Ordering::SeqCst);
+        }
+        result_helpers::update_channel(index, input, |result| {
+            result.peak_frequency = recorded.frequency;
+            result.thd = recorded.thd;
+            result.harmonics = recorded.harmonics;
+        });
     }
+    Ok(())
+}
+
+fn to_complex(samples: &[f64]) -> Vec<Complex<f32>> {
+    samples.iter().map(|&x| Complex::new(x as f32, 0f32)).collect()
 }
 
 // Run an FFT on the audio and detect the maximum frequency
 // This will be the fundamental frequency and can be used later for calculating the THD+N (signal vs noise)
-fn find_spectral_peak(mut signal: Vec<Complex<f32>>, wave_spec: hound::WavSpec, filename: &str) -> Option<(f32, f64)> {
-    let bin = wave_spec.sample_rate as f32 * wave_spec.channels as f32 / signal.len() as f32;
-
+fn find_spectral_peak(signal: Vec<Complex<f32>>, sample_rate: f32, window: Window, filename: &str) -> Result<Option<SpectralPeak>> {
+    let bin = sample_rate / signal.len() as f32;
     let frequency = crate::FREQUENCY.load(std::sync::atomic::Ordering::Relaxed);
-    // This controls the signal versus noise window we will use for the calculation
-    // Currently this is trial-and-error, probably need a more mathmatical way to calcualate it
-    let thd_size: usize = 100 + (frequency / 200 as usize);
 
+    let spectrum = run_fft(signal, window);
+    let save_plots = artifact_helpers::enabled()?;
+    if save_plots {
+        save_to_csv(spectrum.clone(), filename, bin)?;
+    }
+
+    let peak = analyse_spectrum(&spectrum, bin, Some(frequency));
+    match &peak {
+        Some(peak) if save_plots => plot_fft(spectrum, filename, bin as f64, peak.level)?,
+        _ => (),
+    }
+    Ok(peak)
+}
+
+// The window's coherent gain is taken back out, so a sine gives the same peak whichever window is used
+fn run_fft(mut signal: Vec<Complex<f32>>, window: Window) -> Vec<Complex<f32>> {
+    if window != Window::Rectangular {
+        let coefficients = window_coefficients(window, signal.len());
+        let coherent_gain = coefficients.iter().sum::<f32>() / signal.len() as f32;
+        for (sample, coefficient) in signal.iter_mut().zip(coefficients) {
+            *sample *= coefficient / coherent_gain;
+        }
+    }
     let mut spectrum = signal.clone();
     let mut planner = FFTplanner::new(false);
     let fft = planner.plan_fft(signal.len());
     fft.process(&mut signal[..], &mut spectrum[..]);
+    spectrum
+}
 
-    save_to_csv(spectrum.clone(), filename, bin);
-
-    let max_peak = spectrum.iter()
-        .take(signal.len() / 4)
+// Find the fundamental, then the THD+N and harmonics around it
+// The signal versus noise window is sized for the test frequency, or for the fundamental if there isn't one
+fn analyse_spectrum(spectrum: &[Complex<f32>], bin: f32, frequency: Option<usize>) -> Option<SpectralPeak> {
+    let spectrum = &spectrum[..spectrum.len() / 2];
+    let (i, freq) = spectrum.iter()
         .enumerate()
-        .max_by_key(|&(_, freq)| freq.norm() as u32);
-
-    let mut signal_strength;
-    let mut tone_strength = 0f64;
-    let mut thd = 0.0;
-    if let Some((i, freq)) = max_peak {
-        plot_fft(spectrum.clone(), filename, bin as f64, freq.norm() as f64);
-
-        let half_thd_size = thd_size/2;
-        let mut index = if i > half_thd_size { i - half_thd_size } else { 0 };
-        for _n in 0..thd_size {
-            tone_strength += (spectrum[index].norm() as f64).powi(2);
-            index += 1;
-        }
+        .max_by(|(_, a), (_, b)| a.norm().total_cmp(&b.norm()))?;
 
-        signal_strength = spectrum.iter().take(signal.len()/4).fold(0f64, |sum, s| sum + (s.norm() as f64).powi(2));
-        signal_strength = signal_strength.sqrt();
-        tone_strength = tone_strength.sqrt();
-        thd = 100f64 * (signal_strength - tone_strength)/signal_strength;
-    }
+    let frequency = frequency.unwrap_or((i as f32 * bin) as usize);
+    // This controls the signal versus noise window we will use for the calculation
+    // Currently this is trial-and-error, probably need a more mathmatical way to calcualate it
+    let thd_size: usize = 100 + (frequency / 200);
 
-    if let Some((i, _)) = max_peak {
-        Some((i as f32 * bin, thd))
-    } else {
-        None
-    }
+    let half_thd_size = thd_size/2;
+    let start = i.saturating_sub(half_thd_size);
+    let mut tone_strength = spectrum.iter().skip(start).take(thd_size).fold(0f64, |sum, s| sum + (s.norm() as f64).powi(2));
+
+    let mut signal_strength = spectrum.iter().fold(0f64, |sum, s| sum + (s.norm() as f64).powi(2));
+    signal_strength = signal_strength.sqrt();
+    tone_strength = tone_strength.sqrt();
+    let thd = 100f64 * (signal_strength - tone_strength)/signal_strength;
+
+    let level = freq.norm() as f64;
+    let harmonics = find_harmonics(spectrum, i, level);
+    Some(SpectralPeak { frequency: i as f32 * bin, thd, harmonics, level })
+}
+
+// Analyse any signal (e.g. one handed over from Python) the same way as a recording, without writing any files
+// - Trim it to whole cycles, between its first and last zero crosses
+// - Find the fundamental, THD+N and harmonics, with the window sized for the given frequency (or the fundamental)
+// The FFT window is the caller's, as the analysis doesn't need the config (or the lock on it)
+pub fn analyse_signal(samples: &[f64], sample_rate: u32, frequency: Option<usize>, window: Window) -> Result<Option<SpectralPeak>> {
+    let signal = whole_cycles(to_complex(samples))?;
+    let bin = sample_rate as f32 / signal.len() as f32;
+    Ok(analyse_spectrum(&run_fft(signal, window), bin, frequency))
 }
 
-fn read_wav_file(filename: &str) -> (Vec<Complex<f32>>, hound::WavSpec) {
-    let mut reader = hound::WavReader::open(filename).expect("Failed to open WAV file");
-    let wave_spec = reader.spec();
+// The spectrum of any signal, trimmed to whole cycles the same way
+pub fn signal_spectrum(samples: &[f64], sample_rate: u32) -> Result<Spectrum> {
+    let window = config_helpers::current()?.analysis.window;
+    Ok(amplitudes(run_fft(whole_cycles(to_complex(samples))?, window), sample_rate))
+}
+
+// The level of the tone at the given frequency in dBFS, taken from the spectrum rather than the RMS
+// so noise and other tones (e.g. the other channel's crosstalk) are left out
+pub fn tone_level_dbfs(samples: &[f64], sample_rate: u32, frequency: usize) -> Result<f64> {
+    let spectrum = signal_spectrum(samples, sample_rate)?;
+    let centre = (frequency as f64 / spectrum.bin).round() as usize;
+    let peak = spectrum.amplitudes.iter()
+        .skip(centre.saturating_sub(HARMONIC_SEARCH_BINS))
+        .take(2 * HARMONIC_SEARCH_BINS + 1)
+        .fold(0.0, |peak: f64, &amplitude| peak.max(amplitude));
+    Ok(crate::level_helpers::amplitude_to_dbfs(peak))
+}
+
+// The spectrum of each channel of one of the capture's WAV files, trimmed the same way as for a measurement
+// These are handed to Python as arrays, the binary only plots them
+#[allow(dead_code)]
+pub fn recording_spectra(name: &str) -> Result<Vec<Spectrum>> {
+    let (channels, spec) = wav_helpers::read_artifact(name)?;
+    let stimulus_frames = wav_helpers::stimulus_frames()?;
+    let window = config_helpers::current()?.analysis.window;
+    channels.iter()
+        .map(|channel| {
+            let signal = find_zero_crosses(to_complex(channel), spec.sample_rate as usize, stimulus_frames)?;
+            Ok(amplitudes(run_fft(signal, window), spec.sample_rate))
+        })
+        .collect()
+}
+
+// The window's coefficient for each sample, over the whole signal
+fn window_coefficients(window: Window, length: usize) -> Vec<f32> {
+    let terms: &[f64] = match window {
+        Window::Rectangular => &[1.0],
+        Window::Hann => &[0.5, 0.5],
+        Window::BlackmanHarris => &[0.35875, 0.48829, 0.14128, 0.01168],
+    };
+    (0..length)
+        .map(|n| {
+            let phase = 2.0 * std::f64::consts::PI * n as f64 / length as f64;
+            terms.iter().enumerate()
+                .map(|(k, term)| (-1f64).powi(k as i32) * term * (k as f64 * phase).cos())
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
 
-    match wave_spec.sample_format {
-        hound::SampleFormat::Int => (reader.samples::<i16>()
-                .map(|x| Complex::new(x.unwrap() as f32, 0f32))
-                .collect::<Vec<_>>(), wave_spec),
-        hound::SampleFormat::Float => (reader.samples::<f32>()
-                .map(|x| Complex::new(x.unwrap() as f32, 0f32))
-                .collect::<Vec<_>>(), wave_spec),
+// Scale the FFT so each bin is the peak amplitude of the sine in it, relative to full scale
+fn amplitudes(spectrum: Vec<Complex<f32>>, sample_rate: u32) -> Spectrum {
+    let length = spectrum.len();
+    Spectrum {
+        bin: sample_rate as f64 / length as f64,
+        amplitudes: spectrum.iter().take(length / 2).map(|value| value.norm() as f64 * 2.0 / length as f64).collect(),
     }
 }
 
-fn plot_fft(spectrum: Vec<Complex<f32>>, filename: &str, bin: f64, max_peak: f64) {
+// The level of each harmonic of the fundamental (at the given bin) relative to it, in dBc
+fn find_harmonics(spectrum: &[Complex<f32>], fundamental: usize, fundamental_level: f64) -> Vec<f64> {
+    let mut harmonics = Vec::new();
+    for order in 2..=HIGHEST_HARMONIC {
+        let centre = fundamental * order;
+        if fundamental == 0 || centre + HARMONIC_SEARCH_BINS >= spectrum.len() {
+            break;
+        }
+        let level = spectrum[centre - HARMONIC_SEARCH_BINS..=centre + HARMONIC_SEARCH_BINS].iter()
+            .map(|value| value.norm() as f64)
+            .fold(0.0, f64::max);
+        harmonics.push(20.0 * (level / fundamental_level).log10());
+    }
+    harmonics
+}
+
+fn plot_fft(spectrum: Vec<Complex<f32>>, filename: &str, bin: f64, max_peak: f64) -> Result<()> {
 
     let log_data: Vec<_> = spectrum.iter()
-        .take(spectrum.len() / 4)
+        .take(spectrum.len() / 2)
         .enumerate()
         .map(|(i,value)| (i as f64 * bin, 20f64 * (value.norm() as f64/max_peak).log10() ))
         .collect();
 
     let linear_data: Vec<_> = spectrum.iter()
-        .take(spectrum.len() / 4)
+        .take(spectrum.len() / 2)
         .enumerate()
         .map(|(i,value)| (i as f64 * bin, value.norm() as f64))
         .collect();
@@ -125,25 +263,31 @@ fn plot_fft(spectrum: Vec<Complex<f32>>, filename: &str, bin: f64, max_peak: f64
         .y_label("dB");
 
     // A page with a single view is then saved to an SVG file
-    Page::single(&log_view).save(filename.to_owned() + "_log.svg").expect("saving svg");
-    Page::single(&linear_view).save(filename.to_owned() + "_linear.svg").expect("saving svg");
+    Page::single(&log_view).save(artifact_helpers::path(&format!("{}_log.svg", filename))?).map_err(|e| Error::File(e.to_string()))?;
+    Page::single(&linear_view).save(artifact_helpers::path(&format!("{}_linear.svg", filename))?).map_err(|e| Error::File(e.to_string()))?;
+    Ok(())
 }
 
 // Dump the data to a CSV file, so we can load it into a spreadsheet for debugging
-fn save_to_csv(spectrum: Vec<Complex<f32>>, filename: &str, bin: f32) {
+fn save_to_csv(spectrum: Vec<Complex<f32>>, filename: &str, bin: f32) -> Result<()> {
 
-    let mut wtr = Writer::from_path(filename.to_owned() + ".csv").expect("Couldn't open CSV file");
-    for (i,value) in spectrum.iter().take(spectrum.len() / 4).enumerate() {
-        wtr.write_record(&[(i as f32 * bin).to_string(), (value.norm() as f32).to_string()]).expect("Couldn't write to CSV");
+    let mut wtr = Writer::from_path(artifact_helpers::path(&format!("{}.csv", filename))?)?;
+    for (i,value) in spectrum.iter().take(spectrum.len() / 2).enumerate() {
+        wtr.write_record(&[(i as f32 * bin).to_string(), value.norm().to_string()])?;
     }
-    wtr.flush().expect("Couldn't flush CSV");
+    wtr.flush()?;
+    Ok(())
 }
 
 // Any FFT calculations need to be done between zero crosses, otherwise the discontinuous data
 // will cause havoc with the FFT calc and we'll get a garbage result
-fn find_zero_crosses(signal: Vec<Complex<f32>>) -> Vec<Complex<f32>> {
-    let mut start_cross = SAMPLE_OFFSET;
-    let mut end_cross = SAMPLE_SIZE + SAMPLE_OFFSET;
+// The section analysed runs from the offset to the end margin before the end of the stimulus
+fn find_zero_crosses(signal: Vec<Complex<f32>>, sample_rate: usize, stimulus_frames: usize) -> Result<Vec<Complex<f32>>> {
+    let mut start_cross = (OFFSET_SECONDS * sample_rate as f64) as usize;
+    let mut end_cross = stimulus_frames.saturating_sub(END_MARGIN_SECONDS * sample_rate);
+    if end_cross <= start_cross || end_cross >= signal.len() {
+        fail!(Analysis, "The recording is too short to analyse, it needs more than {} seconds", MIN_ANALYSIS_SECONDS);
+    }
 
     let mut positive = signal[start_cross].re >= 0f32;
     while start_cross < signal.len() {
@@ -161,5 +305,72 @@ fn find_zero_crosses(signal: Vec<Complex<f32>>) -> Vec<Complex<f32>> {
         end_cross += 1;
     }
 
-    signal[start_cross..end_cross].to_vec()
+    Ok(signal[start_cross..end_cross].to_vec())
+}
+
+// Trim a signal to the samples between its first and last zero crosses
+fn whole_cycles(signal: Vec<Complex<f32>>) -> Result<Vec<Complex<f32>>> {
+    let crosses = |&i: &usize| (signal[i].re >= 0f32) != (signal[i + 1].re >= 0f32);
+    let last = signal.len().saturating_sub(1);
+    match ((0..last).find(crosses), (0..last).rev().find(crosses)) {
+        (Some(first), Some(last)) if last > first => Ok(signal[first + 1..=last].to_vec()),
+        _ => fail!(Analysis, "The signal doesn't cross zero often enough to analyse"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_start_of_the_recording_is_skipped() {
+        // A 100 Hz sine, so a zero cross is never more than 5 ms away, with each frame's number kept in the imaginary part
+        let sample_rate = 48000;
+        let signal: Vec<Complex<f32>> = (0..4 * sample_rate)
+            .map(|n| Complex::new((2.0 * std::f32::consts::PI * 100.0 * n as f32 / sample_rate as f32 + 0.1).sin(), n as f32))
+            .collect();
+        let start = find_zero_crosses(signal, sample_rate, 4 * sample_rate).unwrap()[0].im as usize;
+        assert!(start >= sample_rate / 2 && start < sample_rate / 2 + sample_rate / 200, "starts at frame {}", start);
+    }
+
+    #[test]
+    fn a_recording_too_short_to_analyse_is_an_analysis_error() {
+        let signal = vec![Complex::new(0.0, 0.0); 48000];
+        match find_zero_crosses(signal, 48000, 48000) {
+            Err(Error::Analysis(message)) => assert!(message.contains("too short"), "{}", message),
+            other => panic!("expected an analysis error, got {:?}", other.map(|crosses| crosses.len())),
+        }
+    }
+
+    #[test]
+    fn harmonics_are_found_near_where_they_should_be_up_to_nyquist() {
+        // A fundamental at bin 100, the 2nd harmonic leaked one bin high at -40 dBc and the 3rd at -60 dBc
+        let mut spectrum = vec![Complex::new(0.0f32, 0.0); 320];
+        spectrum[100] = Complex::new(1.0, 0.0);
+        spectrum[201] = Complex::new(0.01, 0.0);
+        spectrum[300] = Complex::new(0.0, 0.001);
+        let harmonics = find_harmonics(&spectrum, 100, 1.0);
+        assert_eq!(harmonics.len(), 2, "{:?}", harmonics);
+        assert!((harmonics[0] + 40.0).abs() < 1e-3, "{:?}", harmonics);
+        assert!((harmonics[1] + 60.0).abs() < 1e-3, "{:?}", harmonics);
+    }
+
+    #[test]
+    fn a_nan_in_the_spectrum_doesnt_stop_the_analysis() {
+        let mut spectrum = vec![Complex::new(0.0, 0.0); 64];
+        spectrum[4] = Complex::new(1.0, 0.0);
+        spectrum[10] = Complex::new(f32::NAN, 0.0);
+        assert!(analyse_spectrum(&spectrum, 100.0, Some(400)).is_some());
+    }
+
+    #[test]
+    fn a_signal_is_analysed_on_its_own() {
+        let sample_rate = 48000;
+        let samples: Vec<f64> = (0..sample_rate)
+            .map(|n| 0.5 * (2.0 * std::f64::consts::PI * 1000.0 * n as f64 / sample_rate as f64).sin())
+            .collect();
+        let peak = analyse_signal(&samples, sample_rate as u32, None, Window::Hann).unwrap().unwrap();
+        assert!((peak.frequency - 1000.0).abs() < 2.0, "found {} Hz", peak.frequency);
+        assert!(peak.thd < 0.1, "THD+N of {}%", peak.thd);
+    }
 }
@@ -0,0 +1,250 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config_helpers::GoldenSettings;
+use crate::error_helpers::Result;
+use crate::level_helpers;
+use crate::limit_helpers::LimitCheck;
+use crate::measurement_helpers::ThdnResult;
+use crate::response_helpers::ResponsePoint;
+
+// Results closer than this are taken to be at the same generator level or frequency
+const LEVEL_MATCH_DB: f64 = 0.01;
+const FREQUENCY_MATCH_HZ: f64 = 0.5;
+
+// The results of a known good unit, that later units are compared against
+// The generated/recorded gain of every THD+N measurement is kept per input, along with the level and THD+N,
+// and every response is kept point by point. Each is stored against the generator level it was measured at,
+// as the results of another level can't be compared
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GoldenSet {
+    pub input_device: String,
+    pub output_device: String,
+    pub updated_at: DateTime<Utc>,
+    // An empty list after the other's tables can't be written in TOML, so they're left out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thdn: Vec<GoldenThdn>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response: Vec<GoldenResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GoldenThdn {
+    pub frequency: usize,
+    pub level_dbfs: f64,
+    pub channels: Vec<GoldenChannel>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GoldenChannel {
+    pub input: u16,
+    pub level_dbfs: f64,
+    pub gain_db: f64,
+    pub thd: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GoldenResponse {
+    pub level_dbfs: f64,
+    pub points: Vec<ResponsePoint>,
+}
+
+impl GoldenSet {
+    pub fn load(path: &str) -> Result<GoldenSet> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    // The set that's there to add to, or an empty one for the devices being used now
+    pub fn load_or_new(path: &str) -> Result<GoldenSet> {
+        if std::path::Path::new(path).exists() {
+            return GoldenSet::load(path);
+        }
+        let (input_device, output_device) = crate::audio_helpers::device_names()?;
+        Ok(GoldenSet { input_device, output_device, updated_at: Utc::now(), thdn: Vec::new(), response: Vec::new() })
+    }
+
+    pub fn save(&mut self, path: &str) -> Result<()> {
+        self.updated_at = Utc::now();
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    // Replace any THD+N measurement at the same frequency and level
+    pub fn record_thdn(&mut self, result: &ThdnResult) {
+        self.thdn.retain(|thdn| !(thdn.frequency == result.frequency && same_level(thdn.level_dbfs, result.level_dbfs)));
+        self.thdn.push(GoldenThdn {
+            frequency: result.frequency,
+            level_dbfs: result.level_dbfs,
+            channels: result.channels.iter()
+                .map(|channel| GoldenChannel { input: channel.input, level_dbfs: channel.level_dbfs, gain_db: channel.gain_db, thd: channel.thd })
+                .collect(),
+        });
+        self.thdn.sort_by_key(|thdn| thdn.frequency);
+    }
+
+    // Replace any response at the same level
+    pub fn record_response(&mut self, points: &[ResponsePoint]) {
+        let level_dbfs = level_helpers::level_dbfs();
+        self.response.retain(|response| !same_level(response.level_dbfs, level_dbfs));
+        self.response.push(GoldenResponse { level_dbfs, points: points.to_vec() });
+    }
+}
+
+// The level, gain and THD+N of each input, less the golden unit's at the same frequency and level
+pub fn compare_thdn(result: &ThdnResult, golden: &GoldenSet, tolerances: &GoldenSettings) -> Result<Vec<LimitCheck>> {
+    let thdn = match golden.thdn.iter().find(|thdn| thdn.frequency == result.frequency && same_level(thdn.level_dbfs, result.level_dbfs)) {
+        Some(thdn) => thdn,
+        None => fail!(Analysis, "The golden set has no THD+N measurement at {} Hz, {:.2} dBFS", result.frequency, result.level_dbfs),
+    };
+    let mut checks = Vec::new();
+    for channel in &result.channels {
+        let reference = match thdn.channels.iter().find(|reference| reference.input == channel.input) {
+            Some(reference) => reference,
+            None => fail!(Analysis, "The golden set's THD+N measurement at {} Hz has no input {}", result.frequency, channel.input),
+        };
+        let deltas = [
+            ("golden_level_db", "dB", channel.level_dbfs - reference.level_dbfs, tolerances.level_db),
+            ("golden_gain_db", "dB", channel.gain_db - reference.gain_db, tolerances.gain_db),
+            ("golden_thd", "%", channel.thd - reference.thd, tolerances.thd),
+        ];
+        for &(limit, unit, delta, tolerance) in deltas.iter() {
+            if let Some(mut check) = check_delta(limit, unit, delta, tolerance) {
+                check.frequency = Some(result.frequency as f64);
+                checks.push(check.input(channel.input));
+            }
+        }
+    }
+    Ok(checks)
+}
+
+// The gain and THD+N at each frequency, less the golden unit's response at the same level
+// The response is the first input's, the same as the response measurement's
+pub fn compare_response(points: &[ResponsePoint], golden: &GoldenSet, tolerances: &GoldenSettings) -> Result<Vec<LimitCheck>> {
+    let level_dbfs = level_helpers::level_dbfs();
+    let response = match golden.response.iter().find(|response| same_level(response.level_dbfs, level_dbfs)) {
+        Some(response) => response,
+        None => fail!(Analysis, "The golden set has no response at {:.2} dBFS", level_dbfs),
+    };
+    let mut checks = Vec::new();
+    for point in points {
+        let reference = match response.points.iter().find(|reference| (reference.frequency - point.frequency).abs() < FREQUENCY_MATCH_HZ) {
+            Some(reference) => reference,
+            None => fail!(Analysis, "The golden response has no point at {:.0} Hz", point.frequency),
+        };
+        let deltas = [
+            ("golden_response_db", "dB", point.gain_db - reference.gain_db, tolerances.response_db),
+            ("golden_thd", "%", point.thd - reference.thd, tolerances.thd),
+        ];
+        for &(limit, unit, delta, tolerance) in deltas.iter() {
+            if let Some(mut check) = check_delta(limit, unit, delta, tolerance) {
+                check.frequency = Some(point.frequency);
+                checks.push(check);
+            }
+        }
+    }
+    Ok(checks)
+}
+
+// A delta has to be within ± the tolerance, and isn't checked without one
+fn check_delta(limit: &'static str, unit: &'static str, delta: f64, tolerance: Option<f64>) -> Option<LimitCheck> {
+    tolerance.map(|tolerance| LimitCheck::new(limit, unit, delta, Some(-tolerance.abs()), Some(tolerance.abs())))
+}
+
+fn same_level(a: f64, b: f64) -> bool {
+    (a - b).abs() < LEVEL_MATCH_DB
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_helpers::{self, SimulationSettings};
+    use crate::level_helpers::{Level, LevelUnit};
+    use crate::simulation_helpers::tests::use_dut;
+    use crate::{measurement_helpers, response_helpers};
+
+    fn empty_set() -> GoldenSet {
+        GoldenSet { input_device: String::new(), output_device: String::new(), updated_at: Utc::now(), thdn: Vec::new(), response: Vec::new() }
+    }
+
+    fn set_gain(gain_db: f64) {
+        config_helpers::update(|config| config.simulation.gain_db = gain_db).unwrap();
+    }
+
+    #[test]
+    fn a_result_replaces_the_one_at_the_same_frequency_and_level() {
+        let _dut = use_dut(SimulationSettings::default());
+        let mut golden = empty_set();
+        let mut result = measurement_helpers::measure_thdn(None).unwrap();
+        golden.record_thdn(&result);
+        result.channels[0].gain_db = -1.0;
+        golden.record_thdn(&result);
+        assert_eq!(golden.thdn.len(), 1);
+        assert_eq!(golden.thdn[0].channels[0].gain_db, -1.0);
+        result.level_dbfs -= 1.0;
+        golden.record_thdn(&result);
+        assert_eq!(golden.thdn.len(), 2);
+
+        let points = vec![ResponsePoint { frequency: 1000.0, gain_db: 0.0, thd: 0.0 }];
+        golden.record_response(&points);
+        golden.record_response(&points);
+        assert_eq!(golden.response.len(), 1);
+        level_helpers::set_level(Level::new(-12.0, LevelUnit::Dbfs)).unwrap();
+        golden.record_response(&points);
+        assert_eq!(golden.response.len(), 2);
+    }
+
+    #[test]
+    fn thdn_is_checked_against_the_golden_unit_s_gain() {
+        let _dut = use_dut(SimulationSettings::default());
+        let mut golden = empty_set();
+        golden.record_thdn(&measurement_helpers::measure_thdn(None).unwrap());
+        let tolerances = GoldenSettings { gain_db: Some(0.5), ..Default::default() };
+
+        set_gain(-0.2);
+        let checks = compare_thdn(&measurement_helpers::measure_thdn(None).unwrap(), &golden, &tolerances).unwrap();
+        assert_eq!(checks.len(), 2);
+        for check in &checks {
+            assert_eq!(check.limit, "golden_gain_db");
+            assert!(check.passed && (check.value + 0.2).abs() < 0.05, "{}", check);
+        }
+
+        set_gain(-1.0);
+        let checks = compare_thdn(&measurement_helpers::measure_thdn(None).unwrap(), &golden, &tolerances).unwrap();
+        assert!(checks.iter().all(|check| !check.passed && (check.value + 1.0).abs() < 0.05), "{:?}", checks);
+    }
+
+    #[test]
+    fn response_is_checked_against_the_golden_unit_s() {
+        let _dut = use_dut(SimulationSettings::default());
+        let mut golden = empty_set();
+        golden.record_response(&response_helpers::measure_response(&[100, 1000]).unwrap());
+        let tolerances = GoldenSettings { response_db: Some(0.5), ..Default::default() };
+
+        set_gain(-1.0);
+        let checks = compare_response(&response_helpers::measure_response(&[100, 1000]).unwrap(), &golden, &tolerances).unwrap();
+        assert_eq!(checks.iter().map(|check| check.frequency).collect::<Vec<_>>(), vec![Some(100.0), Some(1000.0)]);
+        assert!(checks.iter().all(|check| !check.passed && (check.value + 1.0).abs() < 0.05), "{:?}", checks);
+    }
+
+    #[test]
+    fn results_the_golden_unit_doesnt_have_cant_be_compared() {
+        let _dut = use_dut(SimulationSettings::default());
+        let mut golden = empty_set();
+        let mut result = measurement_helpers::measure_thdn(None).unwrap();
+        golden.record_thdn(&result);
+        golden.record_response(&[ResponsePoint { frequency: 1000.0, gain_db: 0.0, thd: 0.0 }]);
+        let tolerances = GoldenSettings { gain_db: Some(0.5), response_db: Some(0.5), ..Default::default() };
+
+        // An input the golden unit wasn't measured on
+        result.channels[1].input = 3;
+        assert!(compare_thdn(&result, &golden, &tolerances).is_err());
+        // A frequency the golden response doesn't have
+        let points = [ResponsePoint { frequency: 2000.0, gain_db: 0.0, thd: 0.0 }];
+        assert!(compare_response(&points, &golden, &tolerances).is_err());
+        // Another generator level
+        result.level_dbfs -= 1.0;
+        assert!(compare_thdn(&result, &golden, &tolerances).is_err());
+        level_helpers::set_level(Level::new(-12.0, LevelUnit::Dbfs)).unwrap();
+        assert!(compare_response(&points[..0], &golden, &tolerances).is_err());
+    }
+}
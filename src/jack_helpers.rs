@@ -0,0 +1,157 @@
+use crate::config_helpers::JackSettings;
+use crate::error_helpers::{Error, Result};
+
+// The name of the client used to patch the analyser's ports, it has no ports of its own
+const PATCH_CLIENT_NAME: &str = "rust_audio_analyser_patch";
+
+pub fn is_jack(host: &cpal::Host) -> bool {
+    host.id() == cpal::HostId::Jack
+}
+
+// Patches the analyser's ports into the JACK graph
+// - cpal names the ports in_0, in_1, ... and out_0, out_1, ... on a client named after the device, but each stream
+//   opens a client of its own, and JACK renames a client whose name is taken (e.g. cpal_client_in-01)
+// - So the graph's ports are noted before the streams are built, and the streams' ports are the new ones on a
+//   client with the device's name (or that name with JACK's suffix)
+pub struct Patch {
+    client: jack::Client,
+    settings: JackSettings,
+    existing_ports: Vec<String>,
+}
+
+impl Patch {
+    // Open the patch client before the streams are built
+    // None if the host isn't JACK, or no ports are given (cpal connects the streams to the system ports itself)
+    pub fn open(host: &cpal::Host, settings: &JackSettings) -> Result<Option<Patch>> {
+        if !is_jack(host) || (settings.outputs.is_empty() && settings.inputs.is_empty()) {
+            return Ok(None);
+        }
+        let (client, _) = jack::Client::new(PATCH_CLIENT_NAME, jack::ClientOptions::NO_START_SERVER)?;
+        let existing_ports = client.ports(None, None, jack::PortFlags::empty());
+        Ok(Some(Patch { client, settings: settings.clone(), existing_ports }))
+    }
+
+    // Connect the streams that have been built, the input or output device is left out when it's not being used
+    // - Clear cpal's connections to the system ports first, so the stimulus only goes where it's been sent
+    // - Then connect each port given to the analyser's port in the same position
+    pub fn connect(&self, input_device: Option<&str>, output_device: Option<&str>) -> Result<()> {
+        let all_ports = self.client.ports(None, None, jack::PortFlags::empty());
+        let inputs = match input_device {
+            Some(device) => stream_ports(&all_ports, &self.existing_ports, device, "in_")?,
+            None => Vec::new(),
+        };
+        let outputs = match output_device {
+            Some(device) => stream_ports(&all_ports, &self.existing_ports, device, "out_")?,
+            None => Vec::new(),
+        };
+
+        for own_port in inputs.iter().chain(&outputs) {
+            let port = self.client.port_by_name(own_port).ok_or_else(|| Error::Device(format!("JACK port {} has gone", own_port)))?;
+            let is_input = port.flags().contains(jack::PortFlags::IS_INPUT);
+            for other_port in &all_ports {
+                if !port.is_connected_to(other_port)? {
+                    continue;
+                }
+                if is_input {
+                    self.client.disconnect_ports_by_name(other_port, own_port)?;
+                } else {
+                    self.client.disconnect_ports_by_name(own_port, other_port)?;
+                }
+            }
+        }
+
+        if output_device.is_some() {
+            for (source, destination) in positions(&outputs, &self.settings.outputs, "output")? {
+                self.connect_ports(source, destination)?;
+            }
+        }
+        if input_device.is_some() {
+            for (destination, source) in positions(&inputs, &self.settings.inputs, "input")? {
+                self.connect_ports(source, destination)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn connect_ports(&self, source: &str, destination: &str) -> Result<()> {
+        self.client.connect_ports_by_name(source, destination)
+            .map_err(|e| Error::Device(format!("Couldn't connect {} to {}: {}", source, destination, e)))?;
+        eprintln!("Connected {} to {}", source, destination);
+        Ok(())
+    }
+}
+
+// The ports of the stream opened for a device, in channel order
+// They're the ports with the stream's prefix (in_ or out_) that have appeared on the device's client since the patch was opened
+fn stream_ports(all_ports: &[String], existing_ports: &[String], device: &str, prefix: &str) -> Result<Vec<String>> {
+    let mut ports: Vec<(usize, &String)> = all_ports.iter()
+        .filter(|port| !existing_ports.contains(port))
+        .filter_map(|port| {
+            let (client, name) = port.split_once(':')?;
+            let channel = name.strip_prefix(prefix)?.parse().ok()?;
+            if is_device_client(client, device) { Some((channel, port)) } else { None }
+        })
+        .collect();
+    if ports.is_empty() {
+        fail!(Device, "The JACK ports of {}'s stream can't be found", device);
+    }
+    ports.sort();
+    Ok(ports.into_iter().map(|(_, port)| port.clone()).collect())
+}
+
+// The device's name, or the name JACK gives a second client with the same name (the name, a dash and a number)
+fn is_device_client(client: &str, device: &str) -> bool {
+    match client.strip_prefix(device) {
+        Some("") => true,
+        Some(suffix) => suffix.strip_prefix('-').is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())),
+        None => false,
+    }
+}
+
+// Pair the analyser's ports with the ports given for them, there can't be more given than the stream has
+fn positions<'a>(own_ports: &'a [String], given: &'a [String], direction: &str) -> Result<Vec<(&'a str, &'a str)>> {
+    if given.len() > own_ports.len() {
+        fail!(Device, "{} JACK {} ports were given, but the stream only has {}", given.len(), direction, own_ports.len());
+    }
+    Ok(own_ports.iter().zip(given).map(|(own, other)| (own.as_str(), other.as_str())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn a_renamed_client_s_ports_are_found() {
+        // Another program already has a client named cpal_client_in, so JACK renamed the stream's
+        let existing = ports(&["system:capture_1", "system:playback_1", "cpal_client_in:in_0"]);
+        let all = ports(&["system:capture_1", "system:playback_1", "cpal_client_in:in_0",
+            "cpal_client_in-01:in_1", "cpal_client_in-01:in_0", "cpal_client_out:out_0"]);
+        let inputs = stream_ports(&all, &existing, "cpal_client_in", "in_").unwrap();
+        assert_eq!(inputs, ports(&["cpal_client_in-01:in_0", "cpal_client_in-01:in_1"]));
+        let outputs = stream_ports(&all, &existing, "cpal_client_out", "out_").unwrap();
+        assert_eq!(outputs, ports(&["cpal_client_out:out_0"]));
+    }
+
+    #[test]
+    fn ports_are_in_channel_order() {
+        let all: Vec<String> = (0..12).map(|channel| format!("cpal_client_in:in_{}", channel)).collect();
+        assert_eq!(stream_ports(&all, &[], "cpal_client_in", "in_").unwrap(), all);
+    }
+
+    #[test]
+    fn other_clients_are_left_alone() {
+        let all = ports(&["cpal_client_input:in_0", "cpal_client_in-x:in_0", "cpal_client_in_2:in_0", "cpal.client.in:in_0"]);
+        assert!(stream_ports(&all, &[], "cpal_client_in", "in_").is_err());
+    }
+
+    #[test]
+    fn more_ports_than_the_stream_has_are_refused() {
+        let own = ports(&["cpal_client_out:out_0"]);
+        assert!(positions(&own, &ports(&["dut:in_1", "dut:in_2"]), "output").is_err());
+        assert_eq!(positions(&own, &ports(&["dut:in_1"]), "output").unwrap(), vec![("cpal_client_out:out_0", "dut:in_1")]);
+    }
+}
@@ -0,0 +1,160 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pyo3::exceptions::PyTimeoutError;
+use pyo3::prelude::*;
+
+use crate::{artifact_helpers, audio_helpers, config_helpers};
+use crate::error_helpers::{Error, Result};
+use crate::to_py_err;
+
+// The devices, the WAV files and the results are shared, so only one measurement runs at a time
+static ENGINE: Mutex<()> = Mutex::new(());
+
+// Turns a finished measurement's result into Python objects, once the GIL is held again
+type Convert = Box<dyn FnOnce(Python) -> PyResult<PyObject> + Send>;
+
+// Run a measurement once any other has finished, with its cancel flag in place
+pub fn exclusive<T, F: FnOnce() -> T>(cancel: Arc<AtomicBool>, measurement: F) -> T {
+    let _engine = ENGINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    audio_helpers::set_cancel_flag(Some(cancel));
+    let result = measurement();
+    audio_helpers::set_cancel_flag(None);
+    result
+}
+
+// Run a measurement from Python without holding the GIL, so other Python threads (e.g. a UI) keep running
+// It can still be cancelled from another thread with the module's cancel()
+// Each one gets a new run ID, so it doesn't write over the last one's files, and the module's get_ functions read
+// back from the same run (an Analyser puts its own config in place, run ID and all, before it measures)
+pub fn blocking<T: Send, F: Send + FnOnce() -> Result<T>>(py: Python, measurement: F) -> PyResult<T> {
+    let measure = || config_helpers::update(|config| config.output.automatic_run_id = Some(artifact_helpers::new_run_id()))
+        .and_then(|_| measurement());
+    py.allow_threads(|| exclusive(Arc::new(AtomicBool::new(false)), measure)).map_err(to_py_err)
+}
+
+// Start a measurement on its own thread, returning straight away with a handle to it
+// The result is converted to Python objects when it's asked for, as the thread never takes the GIL
+pub fn spawn<T, F>(measurement: F, convert: fn(Python, T) -> PyResult<PyObject>) -> Measurement
+where
+    T: Send + 'static,
+    F: Send + 'static + FnOnce() -> Result<T>,
+{
+    let job = Arc::new(Job { outcome: Mutex::new(None), finished: Condvar::new(), cancel: Arc::new(AtomicBool::new(false)) });
+    let thread_job = job.clone();
+    thread::spawn(move || {
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| exclusive(thread_job.cancel.clone(), measurement)))
+            .unwrap_or_else(|_| Err(Error::Stream(String::from("The measurement thread panicked"))))
+            .map(|value| Box::new(move |py: Python| convert(py, value)) as Convert);
+        *thread_job.outcome.lock().unwrap() = Some(outcome);
+        thread_job.finished.notify_all();
+    });
+    Measurement { job, result: None }
+}
+
+struct Job {
+    outcome: Mutex<Option<Result<Convert>>>,
+    finished: Condvar,
+    cancel: Arc<AtomicBool>,
+}
+
+// A measurement running in the background, started by one of the Analyser's start_ methods
+// - done() polls it, e.g. from a UI timer, and wait() blocks until it finishes (or the timeout runs out)
+// - result() gives what the blocking method would have returned, or raises its error
+// - cancel() stops it at its current capture, after which result() raises CancelledError
+#[pyclass(module = "rust_audio_tester")]
+pub struct Measurement {
+    job: Arc<Job>,
+    result: Option<PyResult<PyObject>>,
+}
+
+#[pymethods]
+impl Measurement {
+    fn done(&self) -> bool {
+        self.result.is_some() || self.job.outcome.lock().unwrap().is_some()
+    }
+
+    // Wait for the measurement to finish, for up to timeout seconds if one is given
+    // A timeout that's infinite, NaN or too long to count down is the same as none
+    // Returns whether it has finished
+    #[args(timeout = "None")]
+    fn wait(&self, py: Python, timeout: Option<f64>) -> bool {
+        if self.result.is_some() {
+            return true;
+        }
+        let deadline = timeout
+            .filter(|timeout| timeout.is_finite())
+            .and_then(|timeout| Duration::try_from_secs_f64(timeout.max(0.0)).ok())
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        let job = self.job.clone();
+        py.allow_threads(move || {
+            let mut outcome = job.outcome.lock().unwrap();
+            while outcome.is_none() {
+                outcome = match deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(remaining) => job.finished.wait_timeout(outcome, remaining).unwrap().0,
+                        None => return false,
+                    },
+                    None => job.finished.wait(outcome).unwrap(),
+                };
+            }
+            true
+        })
+    }
+
+    // Raises TimeoutError if the measurement hasn't finished within the timeout
+    #[args(timeout = "None")]
+    fn result(&mut self, py: Python, timeout: Option<f64>) -> PyResult<PyObject> {
+        if !self.wait(py, timeout) {
+            return Err(PyTimeoutError::new_err("The measurement hasn't finished"));
+        }
+        if self.result.is_none() {
+            let outcome = match self.job.outcome.lock().unwrap().take() {
+                Some(outcome) => outcome,
+                None => return Err(to_py_err(Error::Stream(String::from("The measurement finished without a result")))),
+            };
+            self.result = Some(outcome.map_err(to_py_err).and_then(|convert| convert(py)));
+        }
+        match self.result.as_ref().unwrap() {
+            Ok(result) => Ok(result.clone_ref(py)),
+            Err(e) => Err(e.clone_ref(py)),
+        }
+    }
+
+    fn cancel(&self) {
+        self.job.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finish(measurement: &Measurement) -> Result<Convert> {
+        let mut outcome = measurement.job.outcome.lock().unwrap();
+        while outcome.is_none() {
+            outcome = measurement.job.finished.wait(outcome).unwrap();
+        }
+        outcome.take().unwrap()
+    }
+
+    #[test]
+    fn a_background_measurement_keeps_its_error() {
+        let measurement = spawn(|| -> Result<()> { fail!(Analysis, "No signal") }, |py, _| Ok(py.None()));
+        match finish(&measurement) {
+            Err(Error::Analysis(message)) => assert_eq!(message, "No signal"),
+            Err(e) => panic!("expected an analysis error, got {:?}", e),
+            Ok(_) => panic!("expected an analysis error"),
+        }
+    }
+
+    #[test]
+    fn a_panicking_measurement_is_a_stream_error_and_frees_the_engine() {
+        let measurement = spawn(|| -> Result<()> { panic!("The device went away") }, |py, _| Ok(py.None()));
+        assert!(matches!(finish(&measurement), Err(Error::Stream(_))));
+        assert_eq!(exclusive(Arc::new(AtomicBool::new(false)), || 1), 1);
+    }
+}
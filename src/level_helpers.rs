@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::str::FromStr;
+
+use crate::error_helpers::{Error, Result};
+
+// The generator used to run at a fixed amplitude of 0.8 (about -1.9 dBFS), keep that as the default
+pub const DEFAULT_AMPLITUDE: f64 = 0.8;
+// 0 dBu is defined as 0.7746 Vrms (1 mW into 600 ohms), which sits 2.2185 dB above 0 dBV (1 Vrms)
+const DBU_TO_DBV: f64 = -2.2185;
+
+static GENERATOR_AMPLITUDE: AtomicU64 = AtomicU64::new(f64::to_bits(DEFAULT_AMPLITUDE));
+// The voltage (in dBV) the output produces for a full scale sine wave
+// This is unknown (NaN) until the output has been calibrated
+static OUTPUT_FULL_SCALE_DBV: AtomicU64 = AtomicU64::new(f64::to_bits(f64::NAN));
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LevelUnit {
+    Dbfs,
+    Dbv,
+    Dbu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub value: f64,
+    pub unit: LevelUnit,
+}
+
+impl Level {
+    pub fn new(value: f64, unit: LevelUnit) -> Level {
+        Level { value, unit }
+    }
+}
+
+impl FromStr for LevelUnit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dbfs" => Ok(LevelUnit::Dbfs),
+            "dbv" => Ok(LevelUnit::Dbv),
+            "dbu" => Ok(LevelUnit::Dbu),
+            _ => fail!(Format, "Unknown level unit '{}', expected dBFS, dBV or dBu", s),
+        }
+    }
+}
+
+// Levels are written as a number followed by the unit, e.g. "-6dBFS", "-10 dBV" or "4dBu"
+// A bare number is taken to be dBFS
+impl FromStr for Level {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        let value = value.trim().parse::<f64>()?;
+        let unit = if unit.is_empty() { LevelUnit::Dbfs } else { unit.parse()? };
+        Ok(Level::new(value, unit))
+    }
+}
+
+// Set the generator output level
+// - dBFS levels are used directly
+// - dBV and dBu levels need the output calibration to know what voltage full scale produces
+// - Anything above full scale would clip the output, so it is rejected, as is a level that isn't a number
+// - -inf dBFS is silence (the noise measurement plays it)
+pub fn set_level(level: Level) -> Result<()> {
+    let level_dbfs = to_dbfs(level)?;
+    if level_dbfs.is_nan() {
+        fail!(Format, "Requested level isn't a number");
+    }
+    if level_dbfs > 0.0 {
+        fail!(Format, "Requested level {:.2} dBFS is above full scale", level_dbfs);
+    }
+    GENERATOR_AMPLITUDE.store(f64::to_bits(dbfs_to_amplitude(level_dbfs)), Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn level_dbfs() -> f64 {
+    amplitude_to_dbfs(amplitude())
+}
+
+// The peak amplitude of the generated sine wave, where 1.0 is digital full scale
+pub fn amplitude() -> f64 {
+    f64::from_bits(GENERATOR_AMPLITUDE.load(Ordering::Relaxed))
+}
+
+pub fn set_output_full_scale_dbv(full_scale_dbv: f64) {
+    OUTPUT_FULL_SCALE_DBV.store(f64::to_bits(full_scale_dbv), Ordering::SeqCst);
+}
+
+// Back to not knowing, until the output is calibrated (or the calibration is loaded) again
+// An Analyser without a calibration needs this, the binary loads the calibration once and keeps it
+#[allow(dead_code)]
+pub fn clear_output_full_scale() {
+    OUTPUT_FULL_SCALE_DBV.store(f64::to_bits(f64::NAN), Ordering::SeqCst);
+}
+
+pub fn output_full_scale_dbv() -> Option<f64> {
+    let full_scale_dbv = f64::from_bits(OUTPUT_FULL_SCALE_DBV.load(Ordering::Relaxed));
+    if full_scale_dbv.is_nan() { None } else { Some(full_scale_dbv) }
+}
+
+fn to_dbfs(level: Level) -> Result<f64> {
+    let level_dbv = match level.unit {
+        LevelUnit::Dbfs => return Ok(level.value),
+        LevelUnit::Dbv => level.value,
+        LevelUnit::Dbu => level.value + DBU_TO_DBV,
+    };
+    match output_full_scale_dbv() {
+        Some(full_scale_dbv) => Ok(level_dbv - full_scale_dbv),
+        None => fail!(Format, "The output must be calibrated before setting the level in dBV or dBu"),
+    }
+}
+
+pub fn dbfs_to_amplitude(level_dbfs: f64) -> f64 {
+    10f64.powf(level_dbfs / 20.0)
+}
+
+pub fn amplitude_to_dbfs(amplitude: f64) -> f64 {
+    20.0 * amplitude.log10()
+}
+
+pub fn volts_to_dbv(volts: f64) -> f64 {
+    20.0 * volts.log10()
+}
+
+pub fn dbv_to_volts(level_dbv: f64) -> f64 {
+    10f64.powf(level_dbv / 20.0)
+}
+
+pub fn dbv_to_microvolts(level_dbv: f64) -> f64 {
+    dbv_to_volts(level_dbv) * 1e6
+}
+
+// The part of a signal that isn't the test tone, for a signal at the given level with the given THD+N (in %)
+pub fn residual_microvolts(level_dbv: f64, thd: f64) -> f64 {
+    dbv_to_microvolts(level_dbv) * thd / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_that_arent_numbers_are_rejected() {
+        for &value in [f64::NAN, f64::INFINITY].iter() {
+            assert!(set_level(Level::new(value, LevelUnit::Dbfs)).is_err(), "{} dBFS was set", value);
+        }
+    }
+}
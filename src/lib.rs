@@ -1,33 +1,409 @@
 use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
+use pyo3::types::PyDict;
 
+#[macro_use]
+mod error_helpers;
 mod audio_helpers;
 mod wav_helpers;
 mod fft_helpers;
+mod level_helpers;
+mod calibration_helpers;
+mod response_helpers;
+mod loopback_helpers;
+mod config_helpers;
+mod device_helpers;
+mod result_helpers;
+mod clip_helpers;
+mod range_helpers;
+mod backend_helpers;
+mod simulation_helpers;
+mod signal_helpers;
+mod measurement_helpers;
+mod artifact_helpers;
+mod crosstalk_helpers;
+mod noise_helpers;
+mod report_helpers;
+mod plan_helpers;
+mod limit_helpers;
+mod golden_helpers;
+mod analyser_helpers;
+mod job_helpers;
+mod array_helpers;
+#[cfg(feature = "jack")]
+mod jack_helpers;
 
 use std::sync::atomic::{AtomicUsize, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
 
-const GENERATE_PATH: &'static str = "generated.wav";
-const RECORD_PATH: &'static str = "recorded.wav";
+use level_helpers::{Level, LevelUnit};
+use device_helpers::Direction;
+use error_helpers::Error;
+
+const GENERATE_PATH: &str = "generated.wav";
+const RECORD_PATH: &str = "recorded.wav";
+const REFERENCE_PATH: &str = "reference.wav";
+const CALIBRATION_PATH: &str = "calibration.toml";
+const LOOPBACK_PATH: &str = "loopback.toml";
+const GOLDEN_PATH: &str = "golden.toml";
+const CONFIG_PATH: &str = "audio_analyser.toml";
 const SECONDS_TO_RECORD: usize = 4;
+const DEFAULT_FREQUENCY: usize = 1000;
 
-static FREQUENCY: AtomicUsize = AtomicUsize::new(1000);
+static FREQUENCY: AtomicUsize = AtomicUsize::new(DEFAULT_FREQUENCY);
 static RMS_GAIN: AtomicU64 = AtomicU64::new(0);
 static GENERATED_THD: AtomicU64 = AtomicU64::new(0);
 static GENERATED_PEAK_FREQUENCY: AtomicU32 = AtomicU32::new(0);
 static RECORDED_THD: AtomicU64 = AtomicU64::new(0);
 static RECORDED_PEAK_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static CHANNEL_RESULTS: Mutex<Vec<result_helpers::ChannelResult>> = Mutex::new(Vec::new());
+
+// Every error raised by the module is an AnalyserError, with a subclass for each kind of error
+// AnalyserError is a ValueError, so code written before the subclasses existed still catches them
+pyo3::create_exception!(rust_audio_tester, AnalyserError, pyo3::exceptions::PyValueError);
+pyo3::create_exception!(rust_audio_tester, DeviceError, AnalyserError);
+pyo3::create_exception!(rust_audio_tester, StreamError, AnalyserError);
+pyo3::create_exception!(rust_audio_tester, FileError, AnalyserError);
+pyo3::create_exception!(rust_audio_tester, FormatError, AnalyserError);
+pyo3::create_exception!(rust_audio_tester, AnalysisError, AnalyserError);
+pyo3::create_exception!(rust_audio_tester, CancelledError, AnalyserError);
 
 #[pyfunction]
 fn set_frequency(freq: usize) {
     FREQUENCY.store(freq, Ordering::SeqCst);
 }
 
+// One dict per device, across all the available hosts
+// Only the selected host's devices have an index, the others' is None
+#[pyfunction]
+fn list_devices(py: Python) -> PyResult<Vec<PyObject>> {
+    let config = config_helpers::current().map_err(to_py_err)?;
+    let hosts = device_helpers::list_devices(&config).map_err(to_py_err)?;
+    let mut devices = Vec::new();
+    for host in hosts {
+        for device in host.devices {
+            let dict = PyDict::new(py);
+            dict.set_item("host", &host.name)?;
+            dict.set_item("selected_host", host.selected)?;
+            dict.set_item("index", device.index)?;
+            dict.set_item("name", &device.name)?;
+            dict.set_item("default_input", device.is_default_input)?;
+            dict.set_item("default_output", device.is_default_output)?;
+            dict.set_item("input_channels", device.max_channels(Direction::Input))?;
+            dict.set_item("output_channels", device.max_channels(Direction::Output))?;
+            dict.set_item("input_formats", format_ranges(&device.input_formats))?;
+            dict.set_item("output_formats", format_ranges(&device.output_formats))?;
+            devices.push(dict.to_object(py));
+        }
+    }
+    Ok(devices)
+}
+
+// Formats are given as (channels, min sample rate, max sample rate, min buffer size, max buffer size, sample format)
+// The buffer sizes are None when the device doesn't report them
+type FormatTuple = (u16, u32, u32, Option<u32>, Option<u32>, String);
+
+fn format_ranges(formats: &[device_helpers::FormatRange]) -> Vec<FormatTuple> {
+    formats.iter()
+        .map(|f| (f.channels, f.min_sample_rate, f.max_sample_rate, f.min_buffer_size, f.max_buffer_size, f.sample_format.clone()))
+        .collect()
+}
+
+// Devices can be selected by index, name or part of the name, the full name is returned
+#[pyfunction]
+fn set_input_device(selector: &str) -> PyResult<String> {
+    device_helpers::select_device(selector, Direction::Input).map_err(to_py_err)
+}
+
+#[pyfunction]
+fn set_output_device(selector: &str) -> PyResult<String> {
+    device_helpers::select_device(selector, Direction::Output).map_err(to_py_err)
+}
+
+// Select the host by name (e.g. "alsa" or "jack"), or None for the platform's default
+// Devices are looked for on this host, so select it before the devices
+#[pyfunction]
+fn set_host(host: Option<String>) -> PyResult<()> {
+    config_helpers::update(|config| config.host = host).map_err(to_py_err)?;
+    device_helpers::host(&config_helpers::current().map_err(to_py_err)?).map_err(to_py_err)?;
+    Ok(())
+}
+
+// The JACK ports to patch the outputs and inputs into, in channel order
+// With neither given the analyser connects to the system ports
+#[pyfunction]
+fn set_jack_ports(outputs: Vec<String>, inputs: Vec<String>) -> PyResult<()> {
+    config_helpers::update(|config| {
+        config.jack.outputs = outputs;
+        config.jack.inputs = inputs;
+    }).map_err(to_py_err)
+}
+
+// "cpal" plays and records on real devices, "simulated" runs everything through the virtual DUT instead
+#[pyfunction]
+fn set_backend(backend: &str) -> PyResult<()> {
+    let backend = backend.parse().map_err(to_py_err)?;
+    config_helpers::update(|config| config.backend = backend).map_err(to_py_err)
+}
+
+// Set up the virtual DUT used by the simulated backend (see SimulationSettings for what each setting does)
+// The crosstalk matrix has a row per input, giving how much of each output it picks up
+#[pyfunction(gain_db = "0.0", delay_ms = "0.0", lowpass_hz = "None", highpass_hz = "None",
+    polynomial = "Vec::new()", noise_dbfs = "None", crosstalk = "Vec::new()", seed = "0")]
+#[allow(clippy::too_many_arguments)]
+fn set_simulation(gain_db: f64, delay_ms: f64, lowpass_hz: Option<f64>, highpass_hz: Option<f64>,
+    polynomial: Vec<f64>, noise_dbfs: Option<f64>, crosstalk: Vec<Vec<f64>>, seed: u64) -> PyResult<()>
+{
+    config_helpers::update(|config| {
+        config.simulation = config_helpers::SimulationSettings {
+            gain_db, delay_ms, lowpass_hz, highpass_hz, polynomial, noise_dbfs, crosstalk, seed,
+        };
+    }).map_err(to_py_err)
+}
+
+// Stream settings apply to both directions (except the channel counts)
+// Passing None goes back to the device's default
+#[pyfunction]
+fn set_sample_rate(sample_rate: Option<u32>) -> PyResult<()> {
+    config_helpers::update(|config| config.stream.sample_rate = sample_rate).map_err(to_py_err)
+}
+
+#[pyfunction]
+fn set_sample_format(sample_format: Option<&str>) -> PyResult<()> {
+    let sample_format = sample_format.map(|f| f.parse()).transpose().map_err(to_py_err)?;
+    config_helpers::update(|config| config.stream.sample_format = sample_format).map_err(to_py_err)
+}
+
+#[pyfunction]
+fn set_channels(input_channels: Option<u16>, output_channels: Option<u16>) -> PyResult<()> {
+    config_helpers::update(|config| {
+        config.stream.input_channels = input_channels;
+        config.stream.output_channels = output_channels;
+    }).map_err(to_py_err)
+}
+
+// Channels are numbered from 1, as they are on the interface
+// Empty output or input lists mean all of them, the reference is an input with an output looped back to it
+#[pyfunction(reference = "None")]
+fn set_channel_map(outputs: Vec<u16>, inputs: Vec<u16>, reference: Option<u16>) -> PyResult<()> {
+    config_helpers::update(|config| config.channels = config_helpers::ChannelMap { outputs, inputs, reference }).map_err(to_py_err)
+}
+
+// Where the WAV, CSV and SVG files go: <directory>/<run id>, with the directory defaulting to runs/
+// Without a run ID each measurement gets its own, from the time it starts, and in_memory keeps the captures in memory
+#[pyfunction(directory = "None", run_id = "None", in_memory = "false")]
+fn set_output(directory: Option<String>, run_id: Option<String>, in_memory: bool) -> PyResult<()> {
+    config_helpers::update(|config| {
+        config.output.directory = directory;
+        config.output.run_id = run_id;
+        config.output.in_memory = in_memory;
+    }).map_err(to_py_err)
+}
+
+// How long to keep recording after the stimulus, None goes back to the default
+#[pyfunction]
+fn set_capture_tail(tail_ms: Option<u32>) -> PyResult<()> {
+    config_helpers::update(|config| config.capture.tail_ms = tail_ms).map_err(to_py_err)
+}
+
+// Only record, with the stimulus coming from an external generator
+// It's expected to play the test frequency at the generator level, or the stimulus file if one is given
+#[pyfunction(stimulus_wav = "None")]
+fn set_capture_only(enabled: bool, stimulus_wav: Option<String>) -> PyResult<()> {
+    config_helpers::update(|config| {
+        config.capture.external = enabled;
+        config.capture.stimulus_wav = stimulus_wav;
+    }).map_err(to_py_err)
+}
+
+// What to do when the signal clips: "warn", "fail" or "back-off" (by back_off_db each time)
+// None goes back to the default
+#[pyfunction(back_off_db = "None")]
+fn set_clip_action(action: Option<&str>, back_off_db: Option<f64>) -> PyResult<()> {
+    let action = action.map(|a| a.parse()).transpose().map_err(to_py_err)?;
+    config_helpers::update(|config| {
+        config.capture.on_clip = action;
+        config.capture.clip_back_off_db = back_off_db;
+    }).map_err(to_py_err)
+}
+
+// Auto-range the generator before each measurement, so the recording peaks target_headroom_db below full scale
+// Limits left as None go back to their defaults
+#[pyfunction(target_headroom_db = "None", min_level_dbfs = "None", max_level_dbfs = "None")]
+fn set_auto_range(enabled: bool, target_headroom_db: Option<f64>, min_level_dbfs: Option<f64>, max_level_dbfs: Option<f64>) -> PyResult<()> {
+    config_helpers::update(|config| {
+        config.ranging = config_helpers::RangingSettings { enabled, target_headroom_db, min_level_dbfs, max_level_dbfs };
+    }).map_err(to_py_err)
+}
+
+// Auto-range now, returning the generator level chosen and the recorded peak, both in dBFS
+#[pyfunction]
+fn auto_range(py: Python) -> PyResult<(f64, f64)> {
+    let result = job_helpers::blocking(py, range_helpers::auto_range)?;
+    Ok((result.level_dbfs, result.peak_dbfs))
+}
+
+// The result of the last auto-range as (level in dBFS, recorded peak in dBFS, probe steps), or None if it hasn't run
+#[pyfunction]
+fn get_auto_range_result() -> Option<(f64, f64, usize)> {
+    range_helpers::last_ranging().map(|result| (result.level_dbfs, result.peak_dbfs, result.steps))
+}
+
+#[pyfunction]
+fn set_buffer_size(buffer_size: Option<u32>) -> PyResult<()> {
+    config_helpers::update(|config| config.stream.buffer_size = buffer_size).map_err(to_py_err)
+}
+
+// Store the current settings in the config file, so they're used by later runs
+#[pyfunction]
+fn save_config() -> PyResult<()> {
+    config_helpers::save().map_err(to_py_err)
+}
+
+#[pyfunction]
+fn set_level_dbfs(level: f64) -> PyResult<()> {
+    set_level(Level::new(level, LevelUnit::Dbfs))
+}
+
+#[pyfunction]
+fn set_level_dbv(level: f64) -> PyResult<()> {
+    set_level(Level::new(level, LevelUnit::Dbv))
+}
+
+#[pyfunction]
+fn set_level_dbu(level: f64) -> PyResult<()> {
+    set_level(Level::new(level, LevelUnit::Dbu))
+}
+
+fn set_level(level: Level) -> PyResult<()> {
+    level_helpers::set_level(level).map_err(to_py_err)
+}
+
 #[pyfunction]
-fn process_audio() {
-    audio_helpers::record_audio();
-    wav_helpers::calculate_rms();
-    fft_helpers::calculate_peak_frequency();
+fn set_output_full_scale_dbv(full_scale_dbv: f64) {
+    level_helpers::set_output_full_scale_dbv(full_scale_dbv);
+}
+
+#[pyfunction]
+fn get_level_dbfs() -> f64 {
+    level_helpers::level_dbfs()
+}
+
+#[pyfunction]
+fn get_level_dbv() -> Option<f64> {
+    level_helpers::output_full_scale_dbv().map(|full_scale_dbv| level_helpers::level_dbfs() + full_scale_dbv)
+}
+
+#[pyfunction]
+fn load_calibration() -> PyResult<()> {
+    calibration_helpers::load_calibration().map(|_| ()).map_err(to_py_err)
+}
+
+#[pyfunction]
+fn calibrate_input(py: Python, reference_vrms: f64) -> PyResult<Vec<(u16, f64)>> {
+    job_helpers::blocking(py, || calibration_helpers::calibrate_input(reference_vrms))
+}
+
+#[pyfunction(channel = "1")]
+fn calibrate_output(py: Python, measured_vrms: f64, channel: u16) -> PyResult<f64> {
+    job_helpers::blocking(py, || calibration_helpers::calibrate_output(channel, level_helpers::level_dbfs(), measured_vrms))
+}
+
+fn to_py_err(e: Error) -> PyErr {
+    match e {
+        Error::Device(message) => DeviceError::new_err(message),
+        Error::Stream(message) => StreamError::new_err(message),
+        Error::File(message) => FileError::new_err(message),
+        Error::Format(message) => FormatError::new_err(message),
+        Error::Analysis(message) => AnalysisError::new_err(message),
+        Error::Cancelled(message) => CancelledError::new_err(message),
+    }
+}
+
+// How long to play or render the stimulus for, from Python's seconds
+// Nothing is analysed, so any length will do, but it has to be one
+fn stimulus_length(seconds: f64) -> error_helpers::Result<std::time::Duration> {
+    if !seconds.is_finite() || seconds <= 0.0 {
+        fail!(Format, "The stimulus has to be longer than 0 seconds, not {}", seconds);
+    }
+    Ok(std::time::Duration::from_secs_f64(seconds))
+}
+
+// Play the stimulus without recording, for DUTs that can't be looped back
+#[pyfunction(seconds = "4.0")]
+fn play_stimulus(py: Python, seconds: f64) -> PyResult<()> {
+    let length = stimulus_length(seconds).map_err(to_py_err)?;
+    job_helpers::blocking(py, || audio_helpers::play_stimulus(length))?;
+    Ok(())
+}
+
+// Write the stimulus to a WAV file, at the stream's sample rate and channels
+// The bit depth is "16", "24", "32" or "32f"
+#[pyfunction(seconds = "4.0", bit_depth = "\"32f\"")]
+fn render_stimulus(path: &str, seconds: f64, bit_depth: &str) -> PyResult<usize> {
+    let bit_depth = bit_depth.parse().map_err(to_py_err)?;
+    let length = stimulus_length(seconds).map_err(to_py_err)?;
+    let config = config_helpers::current().map_err(to_py_err)?;
+    signal_helpers::render_file(path, &config, bit_depth, length).map_err(to_py_err)
+}
+
+// Play an array of samples (at the given sample rate) in place of the generator's sine, or the sine again if it's None
+#[pyfunction(samples = "None", sample_rate = "48000")]
+fn set_stimulus(py: Python, samples: Option<&PyAny>, sample_rate: u32) -> PyResult<()> {
+    array_helpers::set_stimulus(py, samples, sample_rate)
+}
+
+// The audio from the last capture as NumPy arrays, the recording is (channels, frames)
+#[pyfunction]
+fn get_generated(py: Python) -> PyResult<PyObject> {
+    array_helpers::generated(py)
+}
+
+#[pyfunction]
+fn get_recorded(py: Python) -> PyResult<PyObject> {
+    array_helpers::recorded(py)
+}
+
+#[pyfunction]
+fn get_reference(py: Python) -> PyResult<Option<PyObject>> {
+    array_helpers::reference(py)
+}
+
+// The spectra from the last process_audio call, as arrays of frequencies and amplitudes (relative to full scale)
+#[pyfunction]
+fn get_spectra(py: Python) -> PyResult<PyObject> {
+    array_helpers::spectra(py)
+}
+
+// Offline analysis of an array of samples, one channel or (channels, frames)
+#[pyfunction(frequency = "None")]
+fn analyse(py: Python, samples: &PyAny, sample_rate: u32, frequency: Option<usize>) -> PyResult<PyObject> {
+    let window = config_helpers::current().map_err(to_py_err)?.analysis.window;
+    array_helpers::analyse(py, samples, sample_rate, frequency, window)
+}
+
+#[pyfunction]
+fn spectrum(py: Python, samples: &PyAny, sample_rate: u32) -> PyResult<PyObject> {
+    array_helpers::spectrum(py, samples, sample_rate)
+}
+
+// An invalid capture (xruns, dropouts, discontinuities or clipping, if set to fail) raises an error rather than giving results
+// The GIL is released while it runs, so other Python threads carry on (and can cancel it)
+#[pyfunction]
+fn process_audio(py: Python) -> PyResult<()> {
+    job_helpers::blocking(py, || {
+        range_helpers::auto_range_if_enabled()?;
+        clip_helpers::record_checked()?;
+        wav_helpers::calculate_rms()?;
+        wav_helpers::calculate_levels()?;
+        fft_helpers::calculate_peak_frequency()
+    })
+}
+
+// Cancel the running measurement from another thread, it stops at its current capture and raises CancelledError
+#[pyfunction]
+fn cancel() {
+    audio_helpers::cancel();
 }
 
 #[pyfunction]
@@ -55,16 +431,143 @@ fn get_recorded_peak_frequency() -> f32 {
     f32::from_bits(RECORDED_PEAK_FREQUENCY.load(Ordering::Relaxed))
 }
 
+// The sample counts (in frames) of the last capture and any problems with it, or None if nothing has been captured yet
+// Discontinuities are given as (input, frame)
+#[pyfunction]
+fn get_capture_counts(py: Python) -> PyResult<Option<PyObject>> {
+    match audio_helpers::last_capture() {
+        Some(report) => Ok(Some(analyser_helpers::capture_dict(py, &report)?.to_object(py))),
+        None => Ok(None),
+    }
+}
+
+// The peak level and longest run of full scale samples of the generated signal from the last capture,
+// as (peak in dBFS, full scale run, clipped), or None if nothing has been checked yet
+#[pyfunction]
+fn get_generated_clipping() -> Option<(f64, usize, bool)> {
+    clip_helpers::last_check().map(|report| (report.generated.peak_dbfs, report.generated.full_scale_run, report.generated.clipped()))
+}
+
+// Response points are returned as (frequency, gain in dB, THD+N in %)
+#[pyfunction]
+fn capture_loopback(py: Python) -> PyResult<Vec<(f64, f64, f64)>> {
+    let profile = job_helpers::blocking(py, loopback_helpers::capture_loopback)?;
+    Ok(profile.points.iter().map(|p| (p.frequency, p.gain_db, p.thd)).collect())
+}
+
+#[pyfunction(frequencies = "None", loopback_correction = "false")]
+fn measure_response(py: Python, frequencies: Option<Vec<usize>>, loopback_correction: bool) -> PyResult<Vec<(f64, f64, f64)>> {
+    let profile = if loopback_correction { Some(loopback_helpers::load_loopback().map_err(to_py_err)?) } else { None };
+    let frequencies = frequencies.unwrap_or_else(|| response_helpers::THIRD_OCTAVE_FREQUENCIES.to_vec());
+    let response = job_helpers::blocking(py, || response_helpers::measure_response(&frequencies))?;
+    Ok(response.iter()
+        .map(|point| match &profile {
+            Some(profile) => profile.correct(point),
+            None => *point,
+        })
+        .map(|p| (p.frequency, p.gain_db, p.thd))
+        .collect())
+}
+
+// The gain and recorded THD+N from the last process_audio call, with the loopback profile taken off
+#[pyfunction]
+fn get_loopback_corrected_results() -> PyResult<(f64, f64)> {
+    let profile = loopback_helpers::load_loopback().map_err(to_py_err)?;
+    let point = profile.correct(&response_helpers::ResponsePoint {
+        frequency: FREQUENCY.load(Ordering::Relaxed) as f64,
+        gain_db: get_rms_gain(),
+        thd: get_recorded_thd(),
+    });
+    Ok((point.gain_db, point.thd))
+}
+
+#[pyfunction]
+fn get_recorded_levels_dbfs() -> Vec<f64> {
+    result_helpers::channel_results().iter().map(|result| result.level_dbfs).collect()
+}
+
+// Channels without an input calibration come back as None
+#[pyfunction]
+fn get_recorded_levels_dbv() -> PyResult<Vec<Option<f64>>> {
+    let calibration = calibration_helpers::Calibration::load(CALIBRATION_PATH).map_err(to_py_err)?;
+    calibration_helpers::input_levels_dbv(&calibration, &result_helpers::channel_results()).map_err(to_py_err)
+}
+
+// One dict per recorded channel from the last process_audio call, labelled with its physical input number
+#[pyfunction]
+fn get_channel_results(py: Python) -> PyResult<Vec<PyObject>> {
+    let calibration = calibration_helpers::Calibration::load(CALIBRATION_PATH).map_err(to_py_err)?;
+    let results = result_helpers::channel_results();
+    let levels_dbv = calibration_helpers::input_levels_dbv(&calibration, &results).map_err(to_py_err)?;
+    analyser_helpers::channel_dicts(py, &results, &levels_dbv)
+}
+
 /// This module is a python module implemented in Rust.
 #[pymodule]
-fn rust_audio_tester(_py: Python, m: &PyModule) -> PyResult<()> {
+fn rust_audio_tester(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("AnalyserError", py.get_type::<AnalyserError>())?;
+    m.add("DeviceError", py.get_type::<DeviceError>())?;
+    m.add("StreamError", py.get_type::<StreamError>())?;
+    m.add("FileError", py.get_type::<FileError>())?;
+    m.add("FormatError", py.get_type::<FormatError>())?;
+    m.add("AnalysisError", py.get_type::<AnalysisError>())?;
+    m.add("CancelledError", py.get_type::<CancelledError>())?;
+    m.add_class::<analyser_helpers::Analyser>()?;
+    m.add_class::<job_helpers::Measurement>()?;
     m.add_wrapped(wrap_pyfunction!(set_frequency))?;
+    m.add_wrapped(wrap_pyfunction!(list_devices))?;
+    m.add_wrapped(wrap_pyfunction!(set_input_device))?;
+    m.add_wrapped(wrap_pyfunction!(set_output_device))?;
+    m.add_wrapped(wrap_pyfunction!(set_backend))?;
+    m.add_wrapped(wrap_pyfunction!(set_host))?;
+    m.add_wrapped(wrap_pyfunction!(set_jack_ports))?;
+    m.add_wrapped(wrap_pyfunction!(set_simulation))?;
+    m.add_wrapped(wrap_pyfunction!(set_sample_rate))?;
+    m.add_wrapped(wrap_pyfunction!(set_sample_format))?;
+    m.add_wrapped(wrap_pyfunction!(set_channels))?;
+    m.add_wrapped(wrap_pyfunction!(set_channel_map))?;
+    m.add_wrapped(wrap_pyfunction!(set_buffer_size))?;
+    m.add_wrapped(wrap_pyfunction!(set_output))?;
+    m.add_wrapped(wrap_pyfunction!(set_capture_tail))?;
+    m.add_wrapped(wrap_pyfunction!(set_capture_only))?;
+    m.add_wrapped(wrap_pyfunction!(play_stimulus))?;
+    m.add_wrapped(wrap_pyfunction!(render_stimulus))?;
+    m.add_wrapped(wrap_pyfunction!(set_stimulus))?;
+    m.add_wrapped(wrap_pyfunction!(get_generated))?;
+    m.add_wrapped(wrap_pyfunction!(get_recorded))?;
+    m.add_wrapped(wrap_pyfunction!(get_reference))?;
+    m.add_wrapped(wrap_pyfunction!(get_spectra))?;
+    m.add_wrapped(wrap_pyfunction!(analyse))?;
+    m.add_wrapped(wrap_pyfunction!(spectrum))?;
+    m.add_wrapped(wrap_pyfunction!(set_clip_action))?;
+    m.add_wrapped(wrap_pyfunction!(set_auto_range))?;
+    m.add_wrapped(wrap_pyfunction!(auto_range))?;
+    m.add_wrapped(wrap_pyfunction!(get_auto_range_result))?;
+    m.add_wrapped(wrap_pyfunction!(save_config))?;
+    m.add_wrapped(wrap_pyfunction!(set_level_dbfs))?;
+    m.add_wrapped(wrap_pyfunction!(set_level_dbv))?;
+    m.add_wrapped(wrap_pyfunction!(set_level_dbu))?;
+    m.add_wrapped(wrap_pyfunction!(set_output_full_scale_dbv))?;
+    m.add_wrapped(wrap_pyfunction!(get_level_dbfs))?;
+    m.add_wrapped(wrap_pyfunction!(get_level_dbv))?;
+    m.add_wrapped(wrap_pyfunction!(load_calibration))?;
+    m.add_wrapped(wrap_pyfunction!(calibrate_input))?;
+    m.add_wrapped(wrap_pyfunction!(calibrate_output))?;
     m.add_wrapped(wrap_pyfunction!(process_audio))?;
+    m.add_wrapped(wrap_pyfunction!(cancel))?;
     m.add_wrapped(wrap_pyfunction!(get_rms_gain))?;
     m.add_wrapped(wrap_pyfunction!(get_generated_thd))?;
     m.add_wrapped(wrap_pyfunction!(get_generated_peak_frequency))?;
     m.add_wrapped(wrap_pyfunction!(get_recorded_thd))?;
     m.add_wrapped(wrap_pyfunction!(get_recorded_peak_frequency))?;
+    m.add_wrapped(wrap_pyfunction!(get_capture_counts))?;
+    m.add_wrapped(wrap_pyfunction!(get_generated_clipping))?;
+    m.add_wrapped(wrap_pyfunction!(get_recorded_levels_dbfs))?;
+    m.add_wrapped(wrap_pyfunction!(get_recorded_levels_dbv))?;
+    m.add_wrapped(wrap_pyfunction!(get_channel_results))?;
+    m.add_wrapped(wrap_pyfunction!(capture_loopback))?;
+    m.add_wrapped(wrap_pyfunction!(measure_response))?;
+    m.add_wrapped(wrap_pyfunction!(get_loopback_corrected_results))?;
 
     Ok(())
 }
@@ -0,0 +1,201 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::config_helpers::{LimitSettings, Mask, Range};
+use crate::crosstalk_helpers::Crosstalk;
+use crate::measurement_helpers::ThdnResult;
+use crate::noise_helpers::NoiseResult;
+use crate::response_helpers::ResponsePoint;
+
+// One result checked against its limit
+// The margin is how far inside the limit the value is, in the value's unit (negative if it's outside)
+// For a mask it's the point with the least margin, at the frequency it's at, with the mask's limits there
+#[derive(Debug, Clone, Serialize)]
+pub struct LimitCheck {
+    pub limit: &'static str,
+    pub unit: &'static str,
+    pub input: Option<u16>,
+    pub output: Option<u16>,
+    pub frequency: Option<f64>,
+    pub value: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub margin: f64,
+    pub passed: bool,
+}
+
+impl fmt::Display for LimitCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", if self.passed { "PASS" } else { "FAIL" }, self.limit)?;
+        if let Some(output) = self.output {
+            write!(f, " output {}", output)?;
+        }
+        if let Some(input) = self.input {
+            write!(f, " input {}", input)?;
+        }
+        if let Some(frequency) = self.frequency {
+            write!(f, " at {:.0} Hz", frequency)?;
+        }
+        write!(f, ": {:.4} {}", self.value, self.unit)?;
+        let bounds: Vec<String> = [("min", self.min), ("max", self.max)].iter()
+            .filter_map(|(name, bound)| bound.map(|bound| format!("{} {:.4}", name, bound)))
+            .collect();
+        write!(f, " ({}, margin {:+.4} {})", bounds.join(", "), self.margin, self.unit)
+    }
+}
+
+impl LimitCheck {
+    // A value that isn't a number (e.g. the THD+N of an input with no tone on it) fails whatever the limits are
+    pub fn new(limit: &'static str, unit: &'static str, value: f64, min: Option<f64>, max: Option<f64>) -> LimitCheck {
+        let margin = if value.is_finite() {
+            [min.map(|min| value - min), max.map(|max| max - value)].iter()
+                .flatten()
+                .fold(f64::INFINITY, |margin, &bound| margin.min(bound))
+        } else {
+            f64::NAN
+        };
+        let passed = value.is_finite() && margin >= 0.0;
+        LimitCheck { limit, unit, input: None, output: None, frequency: None, value, min, max, margin, passed }
+    }
+
+    pub fn input(mut self, input: u16) -> LimitCheck {
+        self.input = Some(input);
+        self
+    }
+}
+
+// The gain and THD+N of each input
+pub fn check_thdn(result: &ThdnResult, limits: &LimitSettings) -> Vec<LimitCheck> {
+    let mut checks = Vec::new();
+    for channel in &result.channels {
+        checks.extend(check_range("gain_db", "dB", channel.gain_db, limits.gain_db).map(|check| check.input(channel.input)));
+        checks.extend(check_range("thd", "%", channel.thd, limits.thd).map(|check| check.input(channel.input)));
+    }
+    checks
+}
+
+// The gain at each frequency, against the response mask
+pub fn check_response(points: &[ResponsePoint], limits: &LimitSettings) -> Vec<LimitCheck> {
+    let mask = match &limits.response {
+        Some(mask) => mask,
+        None => return Vec::new(),
+    };
+    let points = points.iter().map(|point| (point.frequency, point.gain_db));
+    check_mask("response", "dB", points, mask).into_iter().collect()
+}
+
+// The crosstalk into each input that isn't being driven
+pub fn check_crosstalk(crosstalk: &[Crosstalk], limits: &LimitSettings) -> Vec<LimitCheck> {
+    let mut checks = Vec::new();
+    for output in crosstalk {
+        for channel in output.channels.iter().filter(|channel| channel.input != output.driven_input) {
+            if let Some(mut check) = check_range("crosstalk_db", "dB", channel.crosstalk_db, limits.crosstalk_db) {
+                check.output = Some(output.output);
+                checks.push(check.input(channel.input));
+            }
+        }
+    }
+    checks
+}
+
+// The SNR of each input, and its noise spectrum against the mask
+pub fn check_noise(noise: &NoiseResult, limits: &LimitSettings) -> Vec<LimitCheck> {
+    let mut checks = Vec::new();
+    for channel in &noise.channels {
+        checks.extend(check_range("snr_db", "dB", channel.snr_db, limits.snr_db).map(|check| check.input(channel.input)));
+        if let Some(mask) = &limits.noise_spectrum {
+            let spectrum = &channel.spectrum;
+            let bins = spectrum.amplitudes.iter().enumerate()
+                .map(|(bin, &amplitude)| (bin as f64 * spectrum.bin, crate::level_helpers::amplitude_to_dbfs(amplitude)));
+            checks.extend(check_mask("noise_spectrum", "dBFS", bins, mask).map(|check| check.input(channel.input)));
+        }
+    }
+    checks
+}
+
+fn check_range(limit: &'static str, unit: &'static str, value: f64, range: Option<Range>) -> Option<LimitCheck> {
+    match range {
+        Some(Range { min: None, max: None }) | None => None,
+        Some(range) => Some(LimitCheck::new(limit, unit, value, range.min, range.max)),
+    }
+}
+
+// The point with the least margin to the mask, None if none of the points are within the mask's frequencies
+fn check_mask<I: Iterator<Item = (f64, f64)>>(limit: &'static str, unit: &'static str, points: I, mask: &Mask) -> Option<LimitCheck> {
+    points
+        .filter_map(|(frequency, value)| {
+            let (min, max) = (mask_at(&mask.lower, frequency), mask_at(&mask.upper, frequency));
+            if min.is_none() && max.is_none() {
+                return None;
+            }
+            let mut check = LimitCheck::new(limit, unit, value, min, max);
+            check.frequency = Some(frequency);
+            Some(check)
+        })
+        .min_by(|a, b| a.margin.partial_cmp(&b.margin).unwrap_or(std::cmp::Ordering::Equal))
+}
+
+// The mask's limit at a frequency, interpolated on a log frequency scale between the points either side
+fn mask_at(points: &[(f64, f64)], frequency: f64) -> Option<f64> {
+    if frequency <= 0.0 {
+        return None;
+    }
+    let mut points: Vec<(f64, f64)> = points.iter().copied().filter(|&(frequency, _)| frequency > 0.0).collect();
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    points.windows(2)
+        .find(|pair| pair[0].0 <= frequency && frequency <= pair[1].0)
+        .map(|pair| {
+            let ((f0, l0), (f1, l1)) = (pair[0], pair[1]);
+            if f1 == f0 {
+                return l0;
+            }
+            l0 + (l1 - l0) * (frequency.log10() - f0.log10()) / (f1.log10() - f0.log10())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_helpers::SimulationSettings;
+    use crate::measurement_helpers;
+    use crate::simulation_helpers::tests::use_dut;
+
+    #[test]
+    fn the_margin_is_to_the_nearest_limit() {
+        let check = LimitCheck::new("gain_db", "dB", 0.5, Some(-1.0), Some(1.0));
+        assert!(check.passed);
+        assert_eq!(check.margin, 0.5);
+        let check = LimitCheck::new("gain_db", "dB", -1.5, Some(-1.0), Some(1.0));
+        assert!(!check.passed);
+        assert_eq!(check.margin, -0.5);
+    }
+
+    #[test]
+    fn values_that_arent_numbers_fail() {
+        for &value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter() {
+            let check = LimitCheck::new("thd", "%", value, None, Some(0.01));
+            assert!(!check.passed, "{} passed", value);
+        }
+    }
+
+    #[test]
+    fn masks_are_interpolated_on_a_log_scale() {
+        let mask = Mask { lower: vec![(100.0, -1.0), (10000.0, -3.0)], upper: Vec::new() };
+        assert!((mask_at(&mask.lower, 1000.0).unwrap() + 2.0).abs() < 1e-9);
+        assert_eq!(mask_at(&mask.lower, 50.0), None);
+        let check = check_mask("response", "dB", vec![(100.0, 0.0), (1000.0, -2.5)].into_iter(), &mask).unwrap();
+        assert_eq!(check.frequency, Some(1000.0));
+        assert!(!check.passed);
+    }
+
+    #[test]
+    fn an_input_with_no_tone_fails_the_thd_limit() {
+        // Input 2 isn't connected to anything
+        let _dut = use_dut(SimulationSettings { crosstalk: vec![vec![1.0, 0.0], vec![0.0, 0.0]], ..Default::default() });
+        let result = measurement_helpers::measure_thdn(None).unwrap();
+        let limits = LimitSettings { thd: Some(Range { min: None, max: Some(1.0) }), ..Default::default() };
+        let checks = check_thdn(&result, &limits);
+        assert_eq!(checks.iter().map(|check| (check.input, check.passed)).collect::<Vec<_>>(), vec![(Some(1), true), (Some(2), false)]);
+    }
+}
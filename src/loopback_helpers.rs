@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error_helpers::Result;
+use crate::response_helpers::{self, ResponsePoint};
+
+// The soundcard's own DAC and ADC are part of every measurement
+// With the interface output patched straight into its input, the measured response and residual
+// distortion belong to the interface alone, and can be taken off later measurements of a DUT
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoopbackProfile {
+    pub input_device: String,
+    pub output_device: String,
+    pub level_dbfs: f64,
+    pub points: Vec<ResponsePoint>,
+}
+
+impl LoopbackProfile {
+    pub fn load(path: &str) -> Result<LoopbackProfile> {
+        let profile: LoopbackProfile = toml::from_str(&std::fs::read_to_string(path)?)?;
+        if profile.points.is_empty() {
+            fail!(Format, "Loopback profile {} has no measurements", path);
+        }
+        Ok(profile)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    // Remove the interface's contribution from a DUT measurement
+    // - Gains in dB subtract (the same as dividing the linear responses)
+    // - The interface's residual is uncorrelated with the DUT's, so the THD+N is removed as power
+    pub fn correct(&self, point: &ResponsePoint) -> ResponsePoint {
+        let (reference_gain, reference_thd) = self.interpolate(point.frequency);
+        ResponsePoint {
+            frequency: point.frequency,
+            gain_db: point.gain_db - reference_gain,
+            thd: (point.thd.powi(2) - reference_thd.powi(2)).max(0.0).sqrt(),
+        }
+    }
+
+    // The loopback gain and THD+N at any frequency, interpolating on a log frequency scale between
+    // the captured points, and holding the end values outside the captured range
+    pub fn interpolate(&self, frequency: f64) -> (f64, f64) {
+        let first = &self.points[0];
+        let last = &self.points[self.points.len() - 1];
+        if frequency <= first.frequency {
+            return (first.gain_db, first.thd);
+        }
+        if frequency >= last.frequency {
+            return (last.gain_db, last.thd);
+        }
+        let upper = self.points.iter().position(|p| p.frequency >= frequency).unwrap();
+        let (low, high) = (&self.points[upper - 1], &self.points[upper]);
+        let position = (frequency / low.frequency).ln() / (high.frequency / low.frequency).ln();
+        (low.gain_db + (high.gain_db - low.gain_db) * position, low.thd + (high.thd - low.thd) * position)
+    }
+}
+
+// Measure the interface's own response with its output looped back to its input, and save it
+pub fn capture_loopback() -> Result<LoopbackProfile> {
+    let (input_device, output_device) = crate::audio_helpers::device_names()?;
+    let profile = LoopbackProfile {
+        input_device,
+        output_device,
+        level_dbfs: crate::level_helpers::level_dbfs(),
+        points: response_helpers::measure_response(&response_helpers::THIRD_OCTAVE_FREQUENCIES)?,
+    };
+    profile.save(crate::LOOPBACK_PATH)?;
+    Ok(profile)
+}
+
+// Load the saved loopback profile, checking it was captured on the devices being used now
+pub fn load_loopback() -> Result<LoopbackProfile> {
+    let profile = LoopbackProfile::load(crate::LOOPBACK_PATH)?;
+    let (input_device, output_device) = crate::audio_helpers::device_names()?;
+    if profile.input_device != input_device || profile.output_device != output_device {
+        fail!(Device, "Loopback profile was captured on {} / {}, not {} / {}",
+            profile.input_device, profile.output_device, input_device, output_device);
+    }
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> LoopbackProfile {
+        let point = |frequency, gain_db, thd| ResponsePoint { frequency, gain_db, thd };
+        LoopbackProfile {
+            input_device: String::new(),
+            output_device: String::new(),
+            level_dbfs: -6.0,
+            points: vec![point(100.0, -1.0, 0.01), point(10000.0, -3.0, 0.03)],
+        }
+    }
+
+    #[test]
+    fn the_profile_is_interpolated_on_a_log_scale() {
+        let profile = profile();
+        let (gain_db, thd) = profile.interpolate(1000.0);
+        assert!((gain_db + 2.0).abs() < 1e-9 && (thd - 0.02).abs() < 1e-9, "{} dB, {} %", gain_db, thd);
+        assert_eq!(profile.interpolate(20.0), (-1.0, 0.01));
+        assert_eq!(profile.interpolate(20000.0), (-3.0, 0.03));
+    }
+
+    #[test]
+    fn the_interface_s_gain_and_residual_are_taken_off() {
+        let corrected = profile().correct(&ResponsePoint { frequency: 100.0, gain_db: -7.0, thd: 0.05 });
+        assert!((corrected.gain_db + 6.0).abs() < 1e-9);
+        // Taken off as power: 0.05² - 0.01² = 0.0024
+        assert!((corrected.thd - 0.0024f64.sqrt()).abs() < 1e-9, "{} %", corrected.thd);
+        // A DUT can't have less distortion than nothing
+        assert_eq!(profile().correct(&ResponsePoint { frequency: 100.0, gain_db: 0.0, thd: 0.001 }).thd, 0.0);
+    }
+}
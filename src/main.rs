@@ -1,28 +1,669 @@
+#[macro_use]
+mod error_helpers;
 mod audio_helpers;
 mod wav_helpers;
 mod fft_helpers;
+mod level_helpers;
+mod calibration_helpers;
+mod response_helpers;
+mod loopback_helpers;
+mod config_helpers;
+mod device_helpers;
+mod result_helpers;
+mod clip_helpers;
+mod range_helpers;
+mod backend_helpers;
+mod simulation_helpers;
+mod signal_helpers;
+mod measurement_helpers;
+mod artifact_helpers;
+mod crosstalk_helpers;
+mod noise_helpers;
+mod report_helpers;
+mod plan_helpers;
+mod limit_helpers;
+mod golden_helpers;
+#[cfg(feature = "jack")]
+mod jack_helpers;
 use std::sync::atomic::{AtomicUsize, AtomicU32, AtomicU64, Ordering};
+use std::str::FromStr;
+use std::sync::Mutex;
 
-const GENERATE_PATH: &'static str = "generated.wav";
-const RECORD_PATH: &'static str = "recorded.wav";
+use serde::Serialize;
+
+use level_helpers::Level;
+use device_helpers::Direction;
+use error_helpers::{Error, Result};
+use report_helpers::{Report, Reportable};
+
+const GENERATE_PATH: &str = "generated.wav";
+const RECORD_PATH: &str = "recorded.wav";
+const REFERENCE_PATH: &str = "reference.wav";
+const CALIBRATION_PATH: &str = "calibration.toml";
+const LOOPBACK_PATH: &str = "loopback.toml";
+const GOLDEN_PATH: &str = "golden.toml";
+const CONFIG_PATH: &str = "audio_analyser.toml";
 const SECONDS_TO_RECORD: usize = 4;
+const DEFAULT_FREQUENCY: usize = 1000;
 
-static FREQUENCY: AtomicUsize = AtomicUsize::new(1000);
+static FREQUENCY: AtomicUsize = AtomicUsize::new(DEFAULT_FREQUENCY);
 static RMS_GAIN: AtomicU64 = AtomicU64::new(0);
 static GENERATED_THD: AtomicU64 = AtomicU64::new(0);
 static GENERATED_PEAK_FREQUENCY: AtomicU32 = AtomicU32::new(0);
 static RECORDED_THD: AtomicU64 = AtomicU64::new(0);
 static RECORDED_PEAK_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static CHANNEL_RESULTS: Mutex<Vec<result_helpers::ChannelResult>> = Mutex::new(Vec::new());
+
+// What the tester has been asked to do, the first argument that isn't an option (a THD+N measurement if there isn't one)
+#[derive(Debug, Clone, Default, PartialEq)]
+enum Command {
+    #[default]
+    Thdn,
+    Devices,
+    Response,
+    Crosstalk,
+    Noise,
+    Analyse(Vec<String>),
+    // Play the stimulus, or render it to the file if one is given
+    Generate(Option<String>),
+    // Run the steps of a test plan file
+    Plan(Option<String>),
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "thdn" => Ok(Command::Thdn),
+            "devices" => Ok(Command::Devices),
+            "response" => Ok(Command::Response),
+            "crosstalk" => Ok(Command::Crosstalk),
+            "noise" => Ok(Command::Noise),
+            "analyse" | "analyze" => Ok(Command::Analyse(Vec::new())),
+            "generate" => Ok(Command::Generate(None)),
+            "plan" => Ok(Command::Plan(None)),
+            _ => fail!(Format, "Unknown command '{}', expected devices, thdn, response, crosstalk, noise, analyse, generate or plan", s),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    help: bool,
+    command: Option<Command>,
+    json: bool,
+    report: Option<String>,
+    frequency: Option<usize>,
+    frequencies: Option<Vec<usize>>,
+    level: Option<Level>,
+    output_full_scale: Option<f64>,
+    calibrate_input: Option<f64>,
+    calibrate_output: Option<f64>,
+    channel: Option<u16>,
+    capture_loopback: bool,
+    loopback: bool,
+    golden: bool,
+    save_golden: bool,
+    golden_file: Option<String>,
+    input_device: Option<String>,
+    output_device: Option<String>,
+    stream: config_helpers::StreamSettings,
+    outputs: Option<Vec<u16>>,
+    inputs: Option<Vec<u16>>,
+    reference: Option<u16>,
+    tail_ms: Option<u32>,
+    duration_s: Option<f64>,
+    window: Option<config_helpers::Window>,
+    output_dir: Option<String>,
+    run_id: Option<String>,
+    no_artifacts: bool,
+    on_clip: Option<config_helpers::ClipAction>,
+    clip_back_off_db: Option<f64>,
+    capture_only: bool,
+    bit_depth: Option<signal_helpers::BitDepth>,
+    stimulus_wav: Option<String>,
+    auto_range: bool,
+    backend: Option<config_helpers::Backend>,
+    host: Option<String>,
+    jack_outputs: Option<Vec<String>>,
+    jack_inputs: Option<Vec<String>>,
+    headroom_db: Option<f64>,
+    min_level_dbfs: Option<f64>,
+    max_level_dbfs: Option<f64>,
+    save_config: bool,
+}
+
+// Scripts can tell a DUT that failed its limits from a measurement that couldn't be made (which exits with 1)
+const FAILED_EXIT_CODE: i32 = 2;
+
+const ERROR_EXIT_CODE: i32 = 1;
+
+fn main() {
+    match parse_args().and_then(run) {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(FAILED_EXIT_CODE),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(ERROR_EXIT_CODE);
+        },
+    }
+}
+
+// Returns whether everything that was measured passed
+fn run(args: Args) -> Result<bool> {
+    if args.help {
+        print!("{}", USAGE);
+        return Ok(true);
+    }
+    let command = args.command.clone().unwrap_or_default();
+
+    if command == Command::Devices {
+        let mut config = config_helpers::current()?;
+        config.host = args.host.clone().or(config.host);
+        let hosts = device_helpers::list_devices(&config)?;
+        return report(args.json, &hosts, || print_devices(&hosts));
+    }
+    // Stream, channel and capture settings given on the command line override the config file for this run
+    config_helpers::update(|config| {
+        config.backend = args.backend.unwrap_or(config.backend);
+        config.host = args.host.clone().or(config.host.take());
+        if let Some(outputs) = &args.jack_outputs {
+            config.jack.outputs = outputs.clone();
+        }
+        if let Some(inputs) = &args.jack_inputs {
+            config.jack.inputs = inputs.clone();
+        }
+        let stream = &mut config.stream;
+        stream.sample_rate = args.stream.sample_rate.or(stream.sample_rate);
+        stream.sample_format = args.stream.sample_format.or(stream.sample_format);
+        stream.input_channels = args.stream.input_channels.or(stream.input_channels);
+        stream.output_channels = args.stream.output_channels.or(stream.output_channels);
+        stream.buffer_size = args.stream.buffer_size.or(stream.buffer_size);
+        let channels = &mut config.channels;
+        if let Some(outputs) = &args.outputs {
+            channels.outputs = outputs.clone();
+        }
+        if let Some(inputs) = &args.inputs {
+            channels.inputs = inputs.clone();
+        }
+        channels.reference = args.reference.or(channels.reference);
+        config.capture.tail_ms = args.tail_ms.or(config.capture.tail_ms);
+        config.capture.duration_s = args.duration_s.or(config.capture.duration_s);
+        config.capture.on_clip = args.on_clip.or(config.capture.on_clip);
+        config.capture.clip_back_off_db = args.clip_back_off_db.or(config.capture.clip_back_off_db);
+        config.capture.external = args.capture_only || args.stimulus_wav.is_some() || config.capture.external;
+        config.capture.stimulus_wav = args.stimulus_wav.clone().or(config.capture.stimulus_wav.take());
+        config.analysis.window = args.window.unwrap_or(config.analysis.window);
+        // Every run gets its own directory for its files, named by the time it started unless it's given a run ID
+        config.output.directory = args.output_dir.clone().or(config.output.directory.take());
+        config.output.run_id = args.run_id.clone().or(config.output.run_id.take());
+        config.output.automatic_run_id = Some(artifact_helpers::new_run_id());
+        config.output.in_memory = args.no_artifacts || config.output.in_memory;
+        config.golden.file = args.golden_file.clone().or(config.golden.file.take());
+        config.golden.compare = args.golden || config.golden.compare;
+        config.golden.save = args.save_golden;
+        let ranging = &mut config.ranging;
+        ranging.enabled = args.auto_range || ranging.enabled;
+        ranging.target_headroom_db = args.headroom_db.or(ranging.target_headroom_db);
+        ranging.min_level_dbfs = args.min_level_dbfs.or(ranging.min_level_dbfs);
+        ranging.max_level_dbfs = args.max_level_dbfs.or(ranging.max_level_dbfs);
+    })?;
+    if args.save_config {
+        config_helpers::save()?;
+    }
+
+    // Analysing files doesn't need the devices, the calibration or the generator
+    if let Command::Analyse(files) = &command {
+        if files.is_empty() {
+            fail!(Format, "Nothing to analyse, give the WAV files after analyse");
+        }
+        let window = config_helpers::current()?.analysis.window;
+        let report = report_helpers::run(|| files.iter()
+            .map(|file| measurement_helpers::analyse_file(file, args.frequency, window))
+            .collect::<Result<Vec<_>>>())?;
+        return output(&args, report, |analyses| print_analyses(analyses));
+    }
+
+    // Selected devices are stored in the config file, so they stay selected for later runs
+    // They're looked for on the selected host, so that has to be set first
+    if let Some(selector) = &args.input_device {
+        eprintln!("Input device set to {}", device_helpers::select_device(selector, Direction::Input)?);
+    }
+    if let Some(selector) = &args.output_device {
+        eprintln!("Output device set to {}", device_helpers::select_device(selector, Direction::Output)?);
+    }
+
+    // The calibration is loaded before the level is applied, so dBV and dBu levels can be used
+    calibration_helpers::load_calibration()?;
+    if let Some(full_scale_dbv) = args.output_full_scale {
+        level_helpers::set_output_full_scale_dbv(full_scale_dbv);
+    }
+    if let Some(level) = args.level {
+        level_helpers::set_level(level)?;
+    }
+
+    if let Some(reference_vrms) = args.calibrate_input {
+        let offsets = calibration_helpers::calibrate_input(reference_vrms)?;
+        return report(args.json, &offsets, || for (channel, offset_db) in &offsets {
+            println!("Input channel {} calibrated: 0 dBFS = {:.2} dBV", channel, offset_db);
+        });
+    }
+    if let Some(measured_vrms) = args.calibrate_output {
+        let channel = args.channel.unwrap_or(config_helpers::current()?.channels.first_output());
+        let offset_db = calibration_helpers::calibrate_output(channel, level_helpers::level_dbfs(), measured_vrms)?;
+        return report(args.json, &(channel, offset_db), ||
+            println!("Output channel {} calibrated: 0 dBFS = {:.2} dBV", channel, offset_db));
+    }
+    if args.capture_loopback {
+        let report = report_helpers::run(loopback_helpers::capture_loopback)?;
+        return output(&args, report, |profile|
+            println!("Loopback profile of {} points saved to {}", profile.points.len(), LOOPBACK_PATH));
+    }
+
+    let loopback = if args.loopback { Some(loopback_helpers::load_loopback()?) } else { None };
+    match command {
+        // Play-only and rendering are for DUTs that can't be looped back, the stimulus is all they need
+        Command::Generate(Some(path)) => {
+            let config = config_helpers::current()?;
+            let bit_depth = args.bit_depth.unwrap_or(signal_helpers::BitDepth::Float32);
+            let frames = signal_helpers::render_file(&path, &config, bit_depth, audio_helpers::measurement_length(&config))?;
+            report(args.json, &frames, || println!("Rendered {} frames of stimulus to {}", frames, path))
+        },
+        Command::Generate(None) => {
+            let capture = audio_helpers::play_stimulus(audio_helpers::measurement_length(&config_helpers::current()?))?;
+            report(args.json, &capture, || ())
+        },
+        Command::Response => {
+            let frequencies = args.frequencies.clone().unwrap_or_else(|| response_helpers::THIRD_OCTAVE_FREQUENCIES.to_vec());
+            let report = report_helpers::run(|| {
+                let response = response_helpers::measure_response(&frequencies)?;
+                Ok(match &loopback {
+                    Some(profile) => response.iter().map(|point| profile.correct(point)).collect(),
+                    None => response,
+                })
+            })?;
+            output(&args, report, |response| {
+                if loopback.is_some() {
+                    println!("Loopback corrected response:");
+                }
+                print_response(response);
+            })
+        },
+        Command::Crosstalk => {
+            let report = report_helpers::run(crosstalk_helpers::measure_crosstalk)?;
+            output(&args, report, |crosstalk| print_crosstalk(crosstalk))
+        },
+        Command::Noise => {
+            let report = report_helpers::run(noise_helpers::measure_noise)?;
+            output(&args, report, print_noise)
+        },
+        Command::Thdn => {
+            let report = report_helpers::run(|| measurement_helpers::measure_thdn(loopback.as_ref()))?;
+            output(&args, report, print_thdn)
+        },
+        Command::Plan(path) => {
+            let path = path.ok_or_else(|| Error::Format(String::from("No test plan given, give its file after plan")))?;
+            let report = plan_helpers::run_plan(&plan_helpers::TestPlan::load(&path)?)?;
+            if let Some(path) = &args.report {
+                report_helpers::write(&report, Some(path))?;
+            }
+            if args.json {
+                report_helpers::write(&report, None)?;
+            } else {
+                print_plan(&report);
+            }
+            Ok(report.passed)
+        },
+        Command::Devices | Command::Analyse(_) => unreachable!("Handled before the devices are opened"),
+    }
+}
+
+// Write a measurement's report to the report file and as JSON to stdout, if they're asked for, otherwise print it as text
+// The measurement's error is returned once it's been reported, otherwise whether it passed
+fn output<T: Reportable, F: FnOnce(&T)>(args: &Args, report: Report<T>, print: F) -> Result<bool> {
+    if let Some(path) = &args.report {
+        report.write(Some(path))?;
+    }
+    if args.json {
+        report.write(None)?;
+    } else {
+        if let Some(result) = &report.result {
+            print(result);
+        }
+        print_verdict(&report.failures, &report.limits, &report.golden);
+    }
+    match report.error {
+        Some(e) => Err(e),
+        None => Ok(report.passed),
+    }
+}
+
+// The limits and golden unit deltas are listed whether they passed or not, the other failures only if there are any
+fn print_verdict(failures: &[String], limits: &[limit_helpers::LimitCheck], golden: &[limit_helpers::LimitCheck]) {
+    let checks: Vec<String> = limits.iter().chain(golden).map(|check| check.to_string()).collect();
+    for check in &checks {
+        println!("{}", check);
+    }
+    for failure in failures.iter().filter(|failure| !checks.contains(failure)) {
+        println!("FAIL: {}", failure);
+    }
+}
+
+// Print a result that isn't a measurement as JSON for scripts, or as text
+// There's nothing for it to fail, so it always passes
+fn report<T: Serialize, F: FnOnce()>(json: bool, result: &T, print: F) -> Result<bool> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(result)?);
+    } else {
+        print();
+    }
+    Ok(true)
+}
+
+fn print_thdn(result: &measurement_helpers::ThdnResult) {
+    if let Some(ranging) = &result.ranging {
+        println!("Auto-ranged to {:.2} dBFS in {} step(s), probe peak {:.2} dBFS", ranging.level_dbfs, ranging.steps, ranging.peak_dbfs);
+    }
+    match result.level_dbv {
+        Some(level_dbv) => println!("Generator level is {:.2} dBFS ({:.2} dBV, {:.4} Vrms)",
+            result.level_dbfs, level_dbv, level_helpers::dbv_to_volts(level_dbv)),
+        None => println!("Generator level is {:.2} dBFS", result.level_dbfs),
+    }
+    println!("Gain is {:.2} dB", result.gain_db);
+    println!("Generated THD+N {:.4} %", result.generated_thd);
+    println!("Generated Peak is {:.0} Hz", result.generated_peak_frequency);
+    if let Some(clipping) = &result.generated_clipping {
+        println!("Generated peak level is {:.2} dBFS{}", clipping.peak_dbfs, if clipping.clipped() { " (clipped)" } else { "" });
+    }
+    println!("Recorded THD+N {:.4} %", result.thd);
+    println!("Recorded Peak is {:.0} Hz", result.peak_frequency);
+    if let Some(corrected) = &result.loopback_corrected {
+        println!("Loopback corrected gain is {:.2} dB", corrected.gain_db);
+        println!("Loopback corrected THD+N {:.4} %", corrected.thd);
+    }
+
+    // The THD+N residual is the part of the recorded signal that isn't the test tone
+    for ((result, &level_dbv), &residual_uv) in result.channels.iter().zip(&result.levels_dbv).zip(&result.residuals_uv) {
+        println!("Input {}: gain {:.2} dB, THD+N {:.4} %, peak {:.0} Hz", result.input, result.gain_db, result.thd, result.peak_frequency);
+        println!("Input {}: peak level {:.2} dBFS{}", result.input, result.peak_dbfs, if result.clipped { " (clipped)" } else { "" });
+        match (level_dbv, residual_uv) {
+            (Some(level_dbv), Some(residual_uv)) => {
+                println!("Input {}: level {:.2} dBFS ({:.2} dBV, {:.4} Vrms)", result.input, result.level_dbfs, level_dbv, level_helpers::dbv_to_volts(level_dbv));
+                println!("Input {}: THD+N residual {:.1} µV", result.input, residual_uv);
+            },
+            _ => println!("Input {}: level {:.2} dBFS", result.input, result.level_dbfs),
+        }
+    }
+}
+
+fn print_response(response: &[response_helpers::ResponsePoint]) {
+    for point in response {
+        println!("{:>6} Hz: {:+.2} dB, THD+N {:.4} %", point.frequency, point.gain_db, point.thd);
+    }
+}
+
+fn print_crosstalk(crosstalk: &[crosstalk_helpers::Crosstalk]) {
+    for output in crosstalk {
+        for channel in output.channels.iter().filter(|channel| channel.input != output.driven_input) {
+            println!("Output {} into input {}: {:.2} dB (driven input {})",
+                output.output, channel.input, channel.crosstalk_db, output.driven_input);
+        }
+    }
+}
+
+fn print_noise(noise: &noise_helpers::NoiseResult) {
+    for channel in &noise.channels {
+        match (channel.level_dbv, channel.level_uv) {
+            (Some(level_dbv), Some(level_uv)) => println!("Input {}: noise {:.2} dBFS ({:.2} dBV, {:.1} µV)",
+                channel.input, channel.level_dbfs, level_dbv, level_uv),
+            _ => println!("Input {}: noise {:.2} dBFS", channel.input, channel.level_dbfs),
+        }
+    }
+}
+
+// Each step's results as they'd be printed on their own, then whether the plan passed
+fn print_plan(report: &plan_helpers::PlanReport) {
+    use plan_helpers::StepOutcome;
+
+    for step in &report.steps {
+        println!("== {} ==", step.name);
+        let (failures, limits, golden, error) = match &step.report {
+            StepOutcome::Thdn(report) => {
+                report.result.iter().for_each(print_thdn);
+                (&report.failures, &report.limits, &report.golden, &report.error)
+            },
+            StepOutcome::Response(report) => {
+                report.result.iter().for_each(|response| print_response(response));
+                (&report.failures, &report.limits, &report.golden, &report.error)
+            },
+            StepOutcome::Crosstalk(report) => {
+                report.result.iter().for_each(|crosstalk| print_crosstalk(crosstalk));
+                (&report.failures, &report.limits, &report.golden, &report.error)
+            },
+            StepOutcome::Noise(report) => {
+                report.result.iter().for_each(print_noise);
+                (&report.failures, &report.limits, &report.golden, &report.error)
+            },
+        };
+        print_verdict(failures, limits, golden);
+        if let Some(e) = error {
+            println!("ERROR: {}", e);
+        }
+    }
+    if !report.completed {
+        println!("Stopped after {} step(s)", report.steps.len());
+    }
+    println!("{}", if report.passed { "PASSED" } else { "FAILED" });
+}
+
+fn print_analyses(analyses: &[measurement_helpers::FileAnalysis]) {
+    for analysis in analyses {
+        println!("{} ({} Hz):", analysis.file, analysis.sample_rate);
+        for (index, channel) in analysis.channels.iter().enumerate() {
+            print!("  Channel {}: level {:.2} dBFS, peak level {:.2} dBFS", index + 1, channel.level_dbfs, channel.peak_dbfs);
+            match (channel.peak_frequency, channel.thd) {
+                (Some(peak_frequency), Some(thd)) => println!(", THD+N {:.4} %, peak {:.0} Hz", thd, peak_frequency),
+                _ => println!(", no tone found"),
+            }
+        }
+    }
+}
+
+fn print_devices(hosts: &[device_helpers::HostInfo]) {
+    for host in hosts {
+        println!("Host: {}{}", host.name, if host.selected { " (selected)" } else { "" });
+        for device in &host.devices {
+            let default = match (device.is_default_input, device.is_default_output) {
+                (true, true) => " (default input and output)",
+                (true, false) => " (default input)",
+                (false, true) => " (default output)",
+                (false, false) => "",
+            };
+            match device.index {
+                Some(index) => println!("  [{}] {}{}", index, device.name, default),
+                None => println!("  {}{}", device.name, default),
+            }
+            println!("      Inputs: {} channels, Outputs: {} channels",
+                device.max_channels(Direction::Input), device.max_channels(Direction::Output));
+            for (direction, formats) in [("Input", &device.input_formats), ("Output", &device.output_formats)].iter() {
+                for format in formats.iter() {
+                    let buffer_size = match (format.min_buffer_size, format.max_buffer_size) {
+                        (Some(min), Some(max)) => format!(", {} - {} frame buffers", min, max),
+                        _ => String::new(),
+                    };
+                    println!("      {} format: {} channels, {} - {} Hz, {}{}",
+                        direction, format.channels, format.min_sample_rate, format.max_sample_rate, format.sample_format, buffer_size);
+                }
+            }
+        }
+    }
+}
+
+// Printed for --help
+const USAGE: &str = "\
+Usage: rust-audio-analyser [command] [options]
+
+Commands:
+  thdn                             Measure the gain and THD+N of a single tone (the default)
+  devices                          List the hosts and devices, with their supported formats
+  response                         Measure the frequency response at --frequencies
+  crosstalk                        Play the tone on each of --outputs in turn and measure how much reaches the other inputs
+  noise                            Measure the noise floor of each input with the generator silent
+  analyse <files>                  Analyse WAV files offline, sized for --frequency if it's given
+  generate [file]                  Play the stimulus without recording, or render it to the WAV file
+                                   (at --sample-rate, on --outputs of --output-channels)
+  plan <file>                      Run the measurements of a TOML or YAML test plan in order, with a combined report
+
+Supported arguments:
+  --json                           Print the results as JSON, with progress messages kept on stderr
+  --report <file>                  Also write the measurement's JSON report to this file
+  --devices                        The same as the devices command
+  --backend <backend>              cpal for real devices, or simulated for the virtual DUT set up in the config file
+  --host <host>                    Host to find the devices on, e.g. alsa or jack (defaults to the platform's default)
+  --jack-outputs <ports>           JACK ports to connect the outputs to, in order, e.g. system:playback_1,system:playback_2
+  --jack-inputs <ports>            JACK ports to connect the inputs to, in order
+  --input-device <device>          Select the input device by index, name or part of the name
+  --output-device <device>         Select the output device by index, name or part of the name
+  --sample-rate <Hz>               Sample rate for both directions (must be supported by both devices)
+  --sample-format <format>         Sample format for both directions: u16, i16, i32 or f32
+  --input-channels <n>             Number of input channels to open
+  --output-channels <n>            Number of output channels to open
+  --buffer-size <frames>           Fixed buffer size for both directions
+  --outputs <list>                 Output channels to play on, e.g. 3 or 1,2 (numbered from 1)
+  --inputs <list>                  Input channels to record, e.g. 5,6 (numbered from 1)
+  --reference <n>                  Input channel with an output looped straight back to it
+  --duration <s>                   How long to play the stimulus for (defaults to 4 seconds, and has to be over 1.5 seconds)
+  --tail <ms>                      How long to keep recording after the stimulus (defaults to 100 ms)
+  --window <window>                Window applied before the FFT: rectangular (the default), hann or blackman-harris
+  --output-dir <dir>               Where to put each run's directory of WAV, CSV and SVG files (defaults to runs)
+  --run-id <id>                    Name of this run's directory (defaults to the time it started, e.g. 20240131-142530.123)
+  --no-artifacts                   Keep the captures in memory and don't write any WAV, CSV or SVG files
+  --on-clip <action>               What to do when a signal clips: warn (the default), fail or back-off
+  --clip-back-off <dB>             How far to turn the generator down each time it backs off (defaults to 6 dB)
+  --capture-only                   Only record, the stimulus comes from an external generator at --frequency and --level
+  --stimulus-wav <file>            Only record, with the external generator playing this file
+  --play                           The same as the generate command
+  --render <file>                  The same as generate <file>
+  --bit-depth <depth>              Bit depth of the rendered file: 16, 24, 32 or 32f (the default)
+  --length <s>                     The same as --duration
+  --auto-range                     Set the generator level with a probe tone before measuring
+  --headroom <dB>                  How far below full scale auto-ranging aims the recorded peak (defaults to 6 dB)
+  --min-level <dBFS>               The lowest level auto-ranging can set (defaults to -60 dBFS)
+  --max-level <dBFS>               The highest level auto-ranging can set (defaults to 0 dBFS)
+  --save-config                    Store every setting given (even --no-artifacts, --golden and --capture-only) in the config file for later runs
+  --frequency <Hz>                 Test tone frequency
+  --frequencies <list>             Frequencies to measure the response at, e.g. 100,1000,10000 (defaults to third octaves)
+  --output-full-scale <dBV>        Output voltage for a full scale sine wave (overrides the calibration file)
+  --level <level>                  Generator level, e.g. -6dBFS, -10dBV or 4dBu
+  --calibrate-input <Vrms>         Calibrate the inputs against a reference of this voltage
+  --calibrate-output <Vrms>        Calibrate the output, given the voltage measured while playing at --level
+  --channel <n>                    The output channel to calibrate (defaults to the first output)
+  --capture-loopback               Measure the soundcard's own response, with its output patched to its input
+  --loopback                       Take the captured loopback response off the results
+  --save-golden                    Save the THD+N and response results as the golden unit's, if they pass
+  --golden                         Compare the THD+N and response results with the golden unit's
+  --golden-file <file>             Where the golden unit's results are kept (defaults to golden.toml)
+  --response                       The same as the response command
+  --help                           Print this and exit
+";
+
+fn parse_args() -> Result<Args> {
+    let mut args = std::env::args().skip(1);
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| Error::Format(format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--help" | "-h" => parsed.help = true,
+            "--json" => parsed.json = true,
+            "--report" => parsed.report = Some(value()?),
+            "--devices" => parsed.set_command(Command::Devices)?,
+            "--backend" => parsed.backend = Some(value()?.parse()?),
+            "--host" => parsed.host = Some(value()?),
+            "--jack-outputs" => parsed.jack_outputs = Some(parse_ports(&value()?)),
+            "--jack-inputs" => parsed.jack_inputs = Some(parse_ports(&value()?)),
+            "--input-device" => parsed.input_device = Some(value()?),
+            "--output-device" => parsed.output_device = Some(value()?),
+            "--sample-rate" => parsed.stream.sample_rate = Some(value()?.parse()?),
+            "--sample-format" => parsed.stream.sample_format = Some(value()?.parse()?),
+            "--input-channels" => parsed.stream.input_channels = Some(value()?.parse()?),
+            "--output-channels" => parsed.stream.output_channels = Some(value()?.parse()?),
+            "--buffer-size" => parsed.stream.buffer_size = Some(value()?.parse()?),
+            "--outputs" => parsed.outputs = Some(parse_channels(&value()?)?),
+            "--inputs" => parsed.inputs = Some(parse_channels(&value()?)?),
+            "--reference" => parsed.reference = Some(value()?.parse()?),
+            "--duration" | "--length" => parsed.duration_s = Some(audio_helpers::check_duration(value()?.parse()?)?),
+            "--tail" => parsed.tail_ms = Some(value()?.parse()?),
+            "--window" => parsed.window = Some(value()?.parse()?),
+            "--output-dir" => parsed.output_dir = Some(value()?),
+            "--run-id" => parsed.run_id = Some(value()?),
+            "--no-artifacts" => parsed.no_artifacts = true,
+            "--on-clip" => parsed.on_clip = Some(value()?.parse()?),
+            "--clip-back-off" => parsed.clip_back_off_db = Some(value()?.parse()?),
+            "--capture-only" => parsed.capture_only = true,
+            "--stimulus-wav" => parsed.stimulus_wav = Some(value()?),
+            "--play" => parsed.set_command(Command::Generate(None))?,
+            "--render" => {
+                let path = value()?;
+                parsed.set_command(Command::Generate(Some(path)))?
+            },
+            "--bit-depth" => parsed.bit_depth = Some(value()?.parse()?),
+            "--auto-range" => parsed.auto_range = true,
+            "--headroom" => parsed.headroom_db = Some(value()?.parse()?),
+            "--min-level" => parsed.min_level_dbfs = Some(value()?.parse()?),
+            "--max-level" => parsed.max_level_dbfs = Some(value()?.parse()?),
+            "--save-config" => parsed.save_config = true,
+            "--frequency" => {
+                let frequency = value()?.parse()?;
+                FREQUENCY.store(frequency, Ordering::SeqCst);
+                parsed.frequency = Some(frequency);
+            },
+            "--frequencies" => parsed.frequencies = Some(parse_frequencies(&value()?)?),
+            "--output-full-scale" => parsed.output_full_scale = Some(value()?.parse()?),
+            "--level" => parsed.level = Some(value()?.parse()?),
+            "--calibrate-input" => parsed.calibrate_input = Some(value()?.parse()?),
+            "--calibrate-output" => parsed.calibrate_output = Some(value()?.parse()?),
+            "--channel" => parsed.channel = Some(value()?.parse()?),
+            "--capture-loopback" => parsed.capture_loopback = true,
+            "--loopback" => parsed.loopback = true,
+            "--save-golden" => parsed.save_golden = true,
+            "--golden" => parsed.golden = true,
+            "--golden-file" => parsed.golden_file = Some(value()?),
+            "--response" => parsed.set_command(Command::Response)?,
+            _ if arg.starts_with("--") => fail!(Format, "Unknown argument: {}", arg),
+            // Anything else is the command, or what the command works on
+            _ => match &mut parsed.command {
+                None => parsed.command = Some(arg.parse()?),
+                Some(Command::Analyse(files)) => files.push(arg),
+                Some(Command::Generate(path @ None)) | Some(Command::Plan(path @ None)) => *path = Some(arg),
+                Some(_) => fail!(Format, "Unexpected argument: {}", arg),
+            },
+        }
+    }
+    Ok(parsed)
+}
+
+impl Args {
+    fn set_command(&mut self, command: Command) -> Result<()> {
+        if self.command.is_some() {
+            fail!(Format, "Only one command can be given at a time");
+        }
+        self.command = Some(command);
+        Ok(())
+    }
+}
+
+// A comma separated list of channel numbers, e.g. "5,6"
+fn parse_channels(channels: &str) -> Result<Vec<u16>> {
+    channels.split(',').map(|channel| Ok(channel.trim().parse()?)).collect()
+}
+
+// A comma separated list of JACK port names
+fn parse_ports(ports: &str) -> Vec<String> {
+    ports.split(',').map(|port| port.trim().to_owned()).collect()
+}
 
-fn main() -> Result<(), failure::Error> {
-    audio_helpers::record_audio();
-    wav_helpers::calculate_rms();
-    fft_helpers::calculate_peak_frequency();
-
-    println!("Gain is {:.2} dB", f64::from_bits(RMS_GAIN.load(Ordering::Relaxed)));
-    println!("Generated THD+N {:.4} %", f64::from_bits(GENERATED_THD.load(Ordering::Relaxed)));
-    println!("Generated Peak is {:.0} Hz", f32::from_bits(GENERATED_PEAK_FREQUENCY.load(Ordering::Relaxed)));
-    println!("Recorded THD+N {:.4} %", f64::from_bits(RECORDED_THD.load(Ordering::Relaxed)));
-    println!("Recorded Peak is {:.0} Hz", f32::from_bits(RECORDED_PEAK_FREQUENCY.load(Ordering::Relaxed)));
-    Ok(())
+// A comma separated list of frequencies in Hz
+fn parse_frequencies(frequencies: &str) -> Result<Vec<usize>> {
+    frequencies.split(',').map(|frequency| Ok(frequency.trim().parse()?)).collect()
 }
@@ -0,0 +1,122 @@
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use crate::audio_helpers::CaptureReport;
+use crate::calibration_helpers::{self, Calibration};
+use crate::clip_helpers::{self, ClipCheck};
+use crate::config_helpers::Window;
+use crate::error_helpers::Result;
+use crate::level_helpers;
+use crate::loopback_helpers::LoopbackProfile;
+use crate::range_helpers::{self, RangingResult};
+use crate::response_helpers::ResponsePoint;
+use crate::result_helpers::{self, ChannelResult};
+use crate::{fft_helpers, wav_helpers};
+
+// Everything a single tone THD+N measurement found
+// It's all collected before the measurement returns, so nothing has to be read back from the shared results afterwards
+// (another measurement may have replaced them by then)
+#[derive(Debug, Clone, Serialize)]
+pub struct ThdnResult {
+    pub frequency: usize,
+    pub level_dbfs: f64,
+    pub level_dbv: Option<f64>,
+    pub gain_db: f64,
+    pub thd: f64,
+    pub peak_frequency: f32,
+    pub generated_thd: f64,
+    pub generated_peak_frequency: f32,
+    pub generated_clipping: Option<ClipCheck>,
+    pub channels: Vec<ChannelResult>,
+    // In the same order as the channels, None for inputs without a calibration
+    pub levels_dbv: Vec<Option<f64>>,
+    // The THD+N residual of each input in µV, the same way
+    pub residuals_uv: Vec<Option<f64>>,
+    pub capture: CaptureReport,
+    pub ranging: Option<RangingResult>,
+    pub loopback_corrected: Option<ResponsePoint>,
+}
+
+// A single tone THD+N measurement
+// - Auto-range first and back off on clipping, if those are set
+// - Record, then work out the gain, levels, THD+N and peaks
+// - Take the loopback profile off the gain and THD+N, if one is given
+pub fn measure_thdn(loopback: Option<&LoopbackProfile>) -> Result<ThdnResult> {
+    let ranging = range_helpers::auto_range_if_enabled()?;
+    let capture = clip_helpers::record_checked()?;
+    wav_helpers::calculate_rms()?;
+    wav_helpers::calculate_levels()?;
+    fft_helpers::calculate_peak_frequency()?;
+
+    let calibration = Calibration::load(crate::CALIBRATION_PATH)?;
+    let channels = result_helpers::channel_results();
+    let levels_dbv = calibration_helpers::input_levels_dbv(&calibration, &channels)?;
+    let residuals_uv = channels.iter().zip(&levels_dbv)
+        .map(|(channel, level_dbv)| level_dbv.map(|level_dbv| level_helpers::residual_microvolts(level_dbv, channel.thd)))
+        .collect();
+    let frequency = crate::FREQUENCY.load(Ordering::Relaxed);
+    let level_dbfs = level_helpers::level_dbfs();
+    let gain_db = f64::from_bits(crate::RMS_GAIN.load(Ordering::Relaxed));
+    let thd = f64::from_bits(crate::RECORDED_THD.load(Ordering::Relaxed));
+    let loopback_corrected = loopback.map(|profile| profile.correct(&ResponsePoint { frequency: frequency as f64, gain_db, thd }));
+
+    Ok(ThdnResult {
+        frequency,
+        level_dbfs,
+        level_dbv: level_helpers::output_full_scale_dbv().map(|full_scale_dbv| level_dbfs + full_scale_dbv),
+        gain_db,
+        thd,
+        peak_frequency: f32::from_bits(crate::RECORDED_PEAK_FREQUENCY.load(Ordering::Relaxed)),
+        generated_thd: f64::from_bits(crate::GENERATED_THD.load(Ordering::Relaxed)),
+        generated_peak_frequency: f32::from_bits(crate::GENERATED_PEAK_FREQUENCY.load(Ordering::Relaxed)),
+        generated_clipping: clip_helpers::last_check().map(|check| check.generated),
+        channels,
+        levels_dbv,
+        residuals_uv,
+        capture,
+        ranging,
+        loopback_corrected,
+    })
+}
+
+// What the offline analysis found in one channel of a signal
+// The peak frequency, THD+N and harmonics are None if there's no fundamental to find
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelAnalysis {
+    pub level_dbfs: f64,
+    pub peak_dbfs: f64,
+    pub peak_frequency: Option<f32>,
+    pub thd: Option<f64>,
+    pub harmonics: Option<Vec<f64>>,
+}
+
+// Every channel of a WAV file, in the file's order
+#[derive(Debug, Clone, Serialize)]
+pub struct FileAnalysis {
+    pub file: String,
+    pub sample_rate: u32,
+    pub channels: Vec<ChannelAnalysis>,
+}
+
+// Analyse a signal the same way as a recording, without touching the devices or the measurement's files
+// The THD+N window is sized for the given frequency, or for the fundamental that's found if none is given
+pub fn analyse_channel(samples: &[f64], sample_rate: u32, frequency: Option<usize>, window: Window) -> Result<ChannelAnalysis> {
+    let peak = fft_helpers::analyse_signal(samples, sample_rate, frequency, window)?;
+    Ok(ChannelAnalysis {
+        level_dbfs: wav_helpers::level_dbfs(samples),
+        peak_dbfs: clip_helpers::check_signal(samples).peak_dbfs,
+        peak_frequency: peak.as_ref().map(|peak| peak.frequency),
+        thd: peak.as_ref().map(|peak| peak.thd),
+        harmonics: peak.map(|peak| peak.harmonics),
+    })
+}
+
+pub fn analyse_file(path: &str, frequency: Option<usize>, window: Window) -> Result<FileAnalysis> {
+    let (channels, spec) = wav_helpers::read_channels(path)?;
+    Ok(FileAnalysis {
+        file: path.to_owned(),
+        sample_rate: spec.sample_rate,
+        channels: channels.iter().map(|channel| analyse_channel(channel, spec.sample_rate, frequency, window)).collect::<Result<_>>()?,
+    })
+}
@@ -0,0 +1,62 @@
+use serde::Serialize;
+
+use crate::audio_helpers::{self, CaptureReport};
+use crate::calibration_helpers::{self, Calibration};
+use crate::error_helpers::Result;
+use crate::fft_helpers::{self, Spectrum};
+use crate::level_helpers::{self, Level, LevelUnit};
+use crate::{result_helpers, wav_helpers};
+
+// The noise floor of each input, in dBFS and in dBV if the input has been calibrated
+// Noise is given in dBFS the same way as a sine, so noise at 0 dBFS has the RMS of a full scale sine
+// The SNR is relative to a full scale sine, and the spectrum (for the limits) is left out of reports
+#[derive(Debug, Clone, Serialize)]
+pub struct NoiseResult {
+    pub channels: Vec<NoiseChannel>,
+    pub capture: CaptureReport,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NoiseChannel {
+    pub input: u16,
+    pub level_dbfs: f64,
+    pub level_dbv: Option<f64>,
+    pub level_uv: Option<f64>,
+    pub snr_db: f64,
+    #[serde(skip)]
+    pub spectrum: Spectrum,
+}
+
+// Measure the noise floor
+// - Record with the generator silent (in capture-only mode the external generator should be silent too)
+// - Put the generator level back, whether or not the recording worked
+// - Find the RMS level of each recorded input over the stimulus section
+pub fn measure_noise() -> Result<NoiseResult> {
+    let level_dbfs = level_helpers::level_dbfs();
+    level_helpers::set_level(Level::new(f64::NEG_INFINITY, LevelUnit::Dbfs))?;
+    let capture = audio_helpers::record_audio();
+    level_helpers::set_level(Level::new(level_dbfs, LevelUnit::Dbfs))?;
+    let capture = capture?;
+
+    wav_helpers::calculate_levels()?;
+    let results = result_helpers::channel_results();
+    let calibration = Calibration::load(crate::CALIBRATION_PATH)?;
+    let levels_dbv = calibration_helpers::input_levels_dbv(&calibration, &results)?;
+    let recorded = wav_helpers::read_stimulus_section(crate::RECORD_PATH)?;
+    let spectra = recorded.iter()
+        .map(|channel| fft_helpers::signal_spectrum(channel, capture.sample_rate))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(NoiseResult {
+        channels: results.iter().zip(levels_dbv).zip(spectra)
+            .map(|((result, level_dbv), spectrum)| NoiseChannel {
+                input: result.input,
+                level_dbfs: result.level_dbfs,
+                level_dbv,
+                level_uv: level_dbv.map(level_helpers::dbv_to_microvolts),
+                snr_db: -result.level_dbfs,
+                spectrum,
+            })
+            .collect(),
+        capture,
+    })
+}
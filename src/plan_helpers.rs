@@ -0,0 +1,388 @@
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use cpal::traits::DeviceTrait;
+use serde::{Deserialize, Serialize};
+
+use crate::{artifact_helpers, audio_helpers};
+use crate::config_helpers::{self, Backend, Config, LimitSettings};
+use crate::crosstalk_helpers::{self, Crosstalk};
+use crate::device_helpers::{self, Direction};
+use crate::error_helpers::{Error, Result};
+use crate::level_helpers::{self, Level, LevelUnit};
+use crate::measurement_helpers::{self, ThdnResult};
+use crate::noise_helpers::{self, NoiseResult};
+use crate::report_helpers::{self, Report};
+use crate::response_helpers::{self, ResponsePoint};
+
+const SCHEMA: &str = "rust-audio-analyser/test-plan";
+
+// A fixed sequence of measurements, with the devices and channels to make them on, e.g. in TOML:
+//   name = "Production test"
+//   output_device = "scarlett"
+//   outputs = [1, 2]
+//   inputs = [1, 2]
+//   level = "-10 dBV"
+//
+//   [[steps]]
+//   measurement = "thdn"
+//   frequency = 1000
+// YAML plans have the same fields. Anything the plan doesn't set comes from the config file,
+// and anything a step doesn't set comes from the plan
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestPlan {
+    pub name: Option<String>,
+    pub backend: Option<Backend>,
+    pub host: Option<String>,
+    // Selected by index, name or part of the name, the same as on the command line (but not stored in the config file)
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    pub outputs: Option<Vec<u16>>,
+    pub inputs: Option<Vec<u16>>,
+    pub reference: Option<u16>,
+    pub frequency: Option<usize>,
+    pub level: Option<String>,
+    pub duration_s: Option<f64>,
+    // Stop at the first step that fails, rather than running the rest anyway
+    #[serde(default)]
+    pub stop_on_failure: bool,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub measurement: StepKind,
+    pub name: Option<String>,
+    pub frequency: Option<usize>,
+    // Response steps only, third octaves if they aren't given
+    pub frequencies: Option<Vec<usize>>,
+    pub level: Option<String>,
+    pub outputs: Option<Vec<u16>>,
+    pub inputs: Option<Vec<u16>>,
+    pub duration_s: Option<f64>,
+    // In place of the config file's limits, for this step only
+    pub limits: Option<LimitSettings>,
+}
+
+// A level check is a single tone measurement, for the levels of each input (its THD+N comes with it)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepKind {
+    Level,
+    Thdn,
+    Response,
+    Crosstalk,
+    Noise,
+}
+
+// Every step's report, in the order they ran, under one report for the whole plan
+#[derive(Debug, Serialize)]
+pub struct PlanReport {
+    pub schema: &'static str,
+    pub schema_version: u32,
+    pub analyser_version: &'static str,
+    pub plan: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_s: f64,
+    pub passed: bool,
+    // False if it stopped before the last step, on a failure or because it was cancelled
+    pub completed: bool,
+    pub steps: Vec<StepReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepReport {
+    pub name: String,
+    pub kind: StepKind,
+    pub report: StepOutcome,
+}
+
+// Each step's report is the same as the report of the measurement made on its own
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum StepOutcome {
+    Thdn(Report<ThdnResult>),
+    Response(Report<Vec<ResponsePoint>>),
+    Crosstalk(Report<Vec<Crosstalk>>),
+    Noise(Report<NoiseResult>),
+}
+
+impl StepKind {
+    // As it's written in a plan
+    pub fn name(self) -> &'static str {
+        match self {
+            StepKind::Level => "level",
+            StepKind::Thdn => "thdn",
+            StepKind::Response => "response",
+            StepKind::Crosstalk => "crosstalk",
+            StepKind::Noise => "noise",
+        }
+    }
+}
+
+impl StepOutcome {
+    pub fn passed(&self) -> bool {
+        match self {
+            StepOutcome::Thdn(report) => report.passed,
+            StepOutcome::Response(report) => report.passed,
+            StepOutcome::Crosstalk(report) => report.passed,
+            StepOutcome::Noise(report) => report.passed,
+        }
+    }
+
+    pub fn error(&self) -> Option<&Error> {
+        match self {
+            StepOutcome::Thdn(report) => report.error.as_ref(),
+            StepOutcome::Response(report) => report.error.as_ref(),
+            StepOutcome::Crosstalk(report) => report.error.as_ref(),
+            StepOutcome::Noise(report) => report.error.as_ref(),
+        }
+    }
+}
+
+impl TestPlan {
+    // YAML if the file ends in .yaml or .yml, TOML otherwise
+    // The levels and durations are checked up front, so a typo doesn't stop the plan half way through
+    pub fn load(path: &str) -> Result<TestPlan> {
+        let contents = std::fs::read_to_string(path)?;
+        let lower = path.to_lowercase();
+        let plan: TestPlan = if lower.ends_with(".yaml") || lower.ends_with(".yml") {
+            serde_yaml::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        if plan.steps.is_empty() {
+            fail!(Format, "The test plan {} has no steps", path);
+        }
+        parse_level(&plan.level)?;
+        plan.duration_s.map(audio_helpers::check_duration).transpose()?;
+        for step in &plan.steps {
+            parse_level(&step.level)?;
+            step.duration_s.map(audio_helpers::check_duration).transpose()?;
+            if step.frequencies.is_some() && step.measurement != StepKind::Response {
+                fail!(Format, "Only response steps can have frequencies, give the others a frequency");
+            }
+        }
+        Ok(plan)
+    }
+}
+
+impl Step {
+    // The step's name if it has one, otherwise what it measures, e.g. "thdn 1000 Hz"
+    pub fn title(&self, plan: &TestPlan) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        match (self.measurement, self.frequency.or(plan.frequency)) {
+            (StepKind::Response, _) | (StepKind::Noise, _) | (_, None) => self.measurement.name().to_owned(),
+            (kind, Some(frequency)) => format!("{} {} Hz", kind.name(), frequency),
+        }
+    }
+}
+
+// Run each step of a test plan in order
+// - Put the plan's devices and channels on top of the config, then each step's settings on top of those
+// - Each step writes its files to its own directory inside the run's, e.g. runs/<run id>/02-thdn-1000-hz
+// - A step that fails is reported and the rest still run, unless the plan stops on failure (a cancel always stops it)
+// - Each step starts from the test frequency and generator level the plan was started with, so nothing carries over
+//      from the step before
+// - The config, test frequency and generator level are put back afterwards
+pub fn run_plan(plan: &TestPlan) -> Result<PlanReport> {
+    let original = config_helpers::current()?;
+    let frequency = crate::FREQUENCY.load(Ordering::SeqCst);
+    let level_dbfs = level_helpers::level_dbfs();
+
+    let started_at = Utc::now();
+    let start = Instant::now();
+    let original_level = Level::new(level_dbfs, LevelUnit::Dbfs);
+    let steps = plan_config(plan, &original).and_then(|config| run_steps(plan, &config, frequency, original_level));
+
+    config_helpers::replace(original);
+    crate::FREQUENCY.store(frequency, Ordering::SeqCst);
+    level_helpers::set_level(Level::new(level_dbfs, LevelUnit::Dbfs))?;
+    let steps = steps?;
+
+    Ok(PlanReport {
+        schema: SCHEMA,
+        schema_version: report_helpers::SCHEMA_VERSION,
+        analyser_version: env!("CARGO_PKG_VERSION"),
+        plan: plan.name.clone(),
+        started_at,
+        finished_at: Utc::now(),
+        duration_s: start.elapsed().as_secs_f64(),
+        passed: steps.len() == plan.steps.len() && steps.iter().all(|step| step.report.passed()),
+        completed: steps.len() == plan.steps.len(),
+        steps,
+    })
+}
+
+fn plan_config(plan: &TestPlan, config: &Config) -> Result<Config> {
+    let mut config = config.clone();
+    config.backend = plan.backend.unwrap_or(config.backend);
+    config.host = plan.host.clone().or(config.host);
+    for (selector, direction) in [(&plan.input_device, Direction::Input), (&plan.output_device, Direction::Output)].iter() {
+        if let Some(selector) = selector {
+            let host = device_helpers::host(&config)?;
+            let name = device_helpers::find_device(&host, selector, *direction)?.name()?;
+            match direction {
+                Direction::Input => config.input_device = Some(name),
+                Direction::Output => config.output_device = Some(name),
+            }
+        }
+    }
+    config.channels.reference = plan.reference.or(config.channels.reference);
+    Ok(config)
+}
+
+fn run_steps(plan: &TestPlan, config: &Config, frequency: usize, level: Level) -> Result<Vec<StepReport>> {
+    // The steps' directories go in the run's, so a plan without a run ID gets one
+    let run_id = config.output.current_run_id().cloned().unwrap_or_else(artifact_helpers::new_run_id);
+    let mut reports = Vec::new();
+    for (index, step) in plan.steps.iter().enumerate() {
+        let name = step.title(plan);
+        let mut step_config = config.clone();
+        if let Some(outputs) = step.outputs.as_ref().or(plan.outputs.as_ref()) {
+            step_config.channels.outputs = outputs.clone();
+        }
+        if let Some(inputs) = step.inputs.as_ref().or(plan.inputs.as_ref()) {
+            step_config.channels.inputs = inputs.clone();
+        }
+        step_config.capture.duration_s = step.duration_s.or(plan.duration_s).or(step_config.capture.duration_s);
+        if let Some(limits) = &step.limits {
+            step_config.limits = limits.clone();
+        }
+        let step_directory = format!("{:02}-{}", index + 1, slug(&name));
+        step_config.output.run_id = Some(format!("{}/{}", run_id, step_directory));
+        config_helpers::replace(step_config);
+        crate::FREQUENCY.store(step.frequency.or(plan.frequency).unwrap_or(frequency), Ordering::SeqCst);
+        let step_level = parse_level(&step.level)?.or(parse_level(&plan.level)?).unwrap_or(level);
+
+        eprintln!("Step {} of {}: {}", index + 1, plan.steps.len(), name);
+        let report = run_step(step, step_level)?;
+        let passed = report.passed();
+        let cancelled = matches!(report.error(), Some(Error::Cancelled(_)));
+        reports.push(StepReport { name, kind: step.measurement, report });
+        if cancelled || (!passed && plan.stop_on_failure) {
+            break;
+        }
+    }
+    Ok(reports)
+}
+
+// The level is set as part of the measurement, so a level that can't be set is reported as the step's error
+fn run_step(step: &Step, level: Level) -> Result<StepOutcome> {
+    let set_level = || level_helpers::set_level(level);
+    Ok(match step.measurement {
+        StepKind::Level | StepKind::Thdn => StepOutcome::Thdn(report_helpers::run(|| {
+            set_level()?;
+            measurement_helpers::measure_thdn(None)
+        })?),
+        StepKind::Response => {
+            let frequencies = step.frequencies.clone().unwrap_or_else(|| response_helpers::THIRD_OCTAVE_FREQUENCIES.to_vec());
+            StepOutcome::Response(report_helpers::run(|| {
+                set_level()?;
+                response_helpers::measure_response(&frequencies)
+            })?)
+        },
+        StepKind::Crosstalk => StepOutcome::Crosstalk(report_helpers::run(|| {
+            set_level()?;
+            crosstalk_helpers::measure_crosstalk()
+        })?),
+        StepKind::Noise => StepOutcome::Noise(report_helpers::run(|| {
+            set_level()?;
+            noise_helpers::measure_noise()
+        })?),
+    })
+}
+
+fn parse_level(level: &Option<String>) -> Result<Option<Level>> {
+    level.as_ref().map(|level| level.parse()).transpose()
+}
+
+// A step's name as a directory name, e.g. "THD+N at 1 kHz" becomes thd-n-at-1-khz
+fn slug(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_helpers::SimulationSettings;
+    use crate::simulation_helpers::tests::use_dut;
+
+    fn thdn_results(report: &PlanReport) -> Vec<Option<&ThdnResult>> {
+        report.steps.iter()
+            .map(|step| match &step.report {
+                StepOutcome::Thdn(report) => report.result.as_ref(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steps_start_from_the_plans_frequency_and_level() {
+        let _dut = use_dut(SimulationSettings::default());
+        let plan: TestPlan = serde_yaml::from_str("
+            duration_s: 2.0
+            steps:
+              - measurement: thdn
+                frequency: 100
+              - measurement: thdn
+                frequency: 10000
+                level: -20 dBFS
+              - measurement: level
+        ").unwrap();
+        let report = run_plan(&plan).unwrap();
+        assert!(report.completed);
+        let results = thdn_results(&report);
+        let (frequencies, levels): (Vec<usize>, Vec<f64>) = results.iter()
+            .map(|result| result.map(|result| (result.frequency, result.level_dbfs.round())).unwrap())
+            .unzip();
+        assert_eq!(frequencies, vec![100, 10000, 1000]);
+        assert_eq!(levels, vec![-6.0, -20.0, -6.0]);
+        assert_eq!(crate::FREQUENCY.load(Ordering::SeqCst), 1000);
+        assert!((level_helpers::level_dbfs() + 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_level_that_cant_be_set_fails_only_its_step() {
+        let _dut = use_dut(SimulationSettings::default());
+        let plan: TestPlan = serde_yaml::from_str("
+            duration_s: 2.0
+            steps:
+              - measurement: thdn
+                level: 3 dBFS
+              - measurement: thdn
+        ").unwrap();
+        let report = run_plan(&plan).unwrap();
+        assert!(report.completed);
+        assert!(!report.passed);
+        assert!(matches!(report.steps[0].report.error(), Some(Error::Format(_))));
+        assert!(report.steps[1].report.passed());
+    }
+
+    #[test]
+    fn a_plan_is_checked_before_anything_runs() {
+        let path = std::env::temp_dir().join(format!("audio_analyser_plan_{}.toml", std::process::id()));
+        let load = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            let plan = TestPlan::load(path.to_str().unwrap());
+            std::fs::remove_file(&path).unwrap();
+            plan
+        };
+        let plan = load("level = \"-10 dBFS\"\n\n[[steps]]\nmeasurement = \"thdn\"\n\n[[steps]]\nmeasurement = \"noise\"\n").unwrap();
+        assert_eq!(plan.steps.len(), 2);
+        assert!(load("steps = []\n").is_err());
+        assert!(load("[[steps]]\nmeasurement = \"thdn\"\nlevel = \"loud\"\n").is_err());
+        assert!(load("[[steps]]\nmeasurement = \"thdn\"\nduration_s = 1.0\n").is_err());
+        assert!(load("[[steps]]\nmeasurement = \"thdn\"\nfrequencies = [100, 1000]\n").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error_helpers::Result;
+use crate::level_helpers::{self, Level, LevelUnit};
+use crate::{audio_helpers, clip_helpers, config_helpers, wav_helpers};
+
+// Unless the config says otherwise, aim for the recording to peak 6 dB below full scale,
+// with the generator anywhere between -60 dBFS and full scale
+const DEFAULT_TARGET_HEADROOM_DB: f64 = 6.0;
+const DEFAULT_MIN_LEVEL_DBFS: f64 = -60.0;
+const DEFAULT_MAX_LEVEL_DBFS: f64 = 0.0;
+// The probe tone only needs to be long enough to get past the DUT's latency and settle
+const PROBE_LENGTH: Duration = Duration::from_millis(300);
+// Close enough to the target to stop adjusting
+const RANGING_TOLERANCE_DB: f64 = 1.0;
+// Each step corrects for the measured gain, so this is only needed when the DUT is non-linear or clipping
+const MAX_RANGING_STEPS: usize = 5;
+// A recording this quiet (in dBFS) means the output isn't reaching the input
+const MINIMUM_PROBE_PEAK_DBFS: f64 = -90.0;
+
+// The result of the last auto-range
+static LAST_RANGING: Mutex<Option<RangingResult>> = Mutex::new(None);
+
+// The generator level that was settled on, and the recorded peak it gave
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RangingResult {
+    pub level_dbfs: f64,
+    pub peak_dbfs: f64,
+    pub steps: usize,
+}
+
+// Only Python asks for it afterwards, the command line reports the ranging with the measurement
+#[allow(dead_code)]
+pub fn last_ranging() -> Option<RangingResult> {
+    *LAST_RANGING.lock().unwrap()
+}
+
+// Auto-range before a measurement, if it's turned on in the config
+pub fn auto_range_if_enabled() -> Result<Option<RangingResult>> {
+    let config = config_helpers::current()?;
+    if config.ranging.enabled && config.capture.external {
+        fail!(Format, "Auto-ranging sets the analyser's own generator, it can't be used in capture-only mode");
+    }
+    if config.ranging.enabled {
+        Ok(Some(auto_range()?))
+    } else {
+        Ok(None)
+    }
+}
+
+// Find the generator level that puts the recording at the target headroom
+// - Play a short probe tone at the current level and find the highest recorded peak across the inputs
+// - The difference between the two is the gain through the DUT, use it to work out the level that hits the target
+// - Keep the level within the configured limits, and repeat until the peak is close enough (or the level stops moving)
+// The generator is left at the final level, ready for the measurement
+pub fn auto_range() -> Result<RangingResult> {
+    let settings = config_helpers::current()?.ranging;
+    let target_dbfs = -settings.target_headroom_db.unwrap_or(DEFAULT_TARGET_HEADROOM_DB);
+    let min_level_dbfs = settings.min_level_dbfs.unwrap_or(DEFAULT_MIN_LEVEL_DBFS);
+    let max_level_dbfs = settings.max_level_dbfs.unwrap_or(DEFAULT_MAX_LEVEL_DBFS).min(0.0);
+    if min_level_dbfs > max_level_dbfs {
+        fail!(Format, "The minimum generator level ({:.2} dBFS) is above the maximum ({:.2} dBFS)", min_level_dbfs, max_level_dbfs);
+    }
+
+    let mut level_dbfs = level_helpers::level_dbfs().max(min_level_dbfs).min(max_level_dbfs);
+    let mut steps = 0;
+    loop {
+        level_helpers::set_level(Level::new(level_dbfs, LevelUnit::Dbfs))?;
+        audio_helpers::record_stimulus(PROBE_LENGTH)?;
+        steps += 1;
+
+        let peak_dbfs = probe_peak()?;
+        if peak_dbfs < MINIMUM_PROBE_PEAK_DBFS {
+            fail!(Analysis, "The probe tone wasn't recorded on any input, check the connections");
+        }
+        let next_level_dbfs = (target_dbfs - (peak_dbfs - level_dbfs)).max(min_level_dbfs).min(max_level_dbfs);
+        eprintln!("Auto-range: generator at {:.2} dBFS recorded a peak of {:.2} dBFS", level_dbfs, peak_dbfs);
+
+        let settled = (peak_dbfs - target_dbfs).abs() <= RANGING_TOLERANCE_DB || (next_level_dbfs - level_dbfs).abs() < 0.01;
+        if settled || steps >= MAX_RANGING_STEPS {
+            let result = RangingResult { level_dbfs, peak_dbfs, steps };
+            *LAST_RANGING.lock().unwrap() = Some(result);
+            return Ok(result);
+        }
+        level_dbfs = next_level_dbfs;
+    }
+}
+
+// The highest peak across the recorded channels, in dBFS
+fn probe_peak() -> Result<f64> {
+    let (recorded, _) = wav_helpers::read_artifact(crate::RECORD_PATH)?;
+    Ok(recorded.iter()
+        .map(|channel| clip_helpers::check_signal(channel).peak_dbfs)
+        .fold(f64::NEG_INFINITY, f64::max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_helpers::{RangingSettings, SimulationSettings};
+    use crate::simulation_helpers::tests::use_dut;
+
+    fn set_ranging(ranging: RangingSettings) {
+        config_helpers::update(|config| config.ranging = ranging).unwrap();
+    }
+
+    #[test]
+    fn the_recording_is_brought_to_the_target_headroom() {
+        // Through -10 dB, a peak 12 dB below full scale needs the generator at -2 dBFS
+        let _dut = use_dut(SimulationSettings { gain_db: -10.0, ..Default::default() });
+        set_ranging(RangingSettings { target_headroom_db: Some(12.0), ..Default::default() });
+        let result = auto_range().unwrap();
+        assert!((result.peak_dbfs + 12.0).abs() <= RANGING_TOLERANCE_DB, "{:?}", result);
+        assert!((result.level_dbfs + 2.0).abs() <= RANGING_TOLERANCE_DB, "{:?}", result);
+        assert!((level_helpers::level_dbfs() - result.level_dbfs).abs() < 1e-9);
+        assert_eq!(last_ranging().map(|last| last.steps), Some(result.steps));
+    }
+
+    #[test]
+    fn the_generator_stays_within_its_limits() {
+        // Too quiet to reach the target without going over the maximum
+        let _dut = use_dut(SimulationSettings { gain_db: -30.0, ..Default::default() });
+        set_ranging(RangingSettings { max_level_dbfs: Some(-10.0), ..Default::default() });
+        let result = auto_range().unwrap();
+        assert_eq!(result.level_dbfs, -10.0);
+        assert!((result.peak_dbfs + 40.0).abs() < 0.5, "{:?}", result);
+
+        // Too loud to reach it without going under the minimum
+        config_helpers::update(|config| config.simulation.gain_db = 10.0).unwrap();
+        set_ranging(RangingSettings { min_level_dbfs: Some(-12.0), ..Default::default() });
+        let result = auto_range().unwrap();
+        assert_eq!(result.level_dbfs, -12.0);
+        assert!((result.peak_dbfs + 2.0).abs() < 0.5, "{:?}", result);
+
+        set_ranging(RangingSettings { min_level_dbfs: Some(-10.0), max_level_dbfs: Some(-20.0), ..Default::default() });
+        assert!(auto_range().is_err());
+    }
+
+    #[test]
+    fn a_disconnected_input_cant_be_ranged() {
+        let _dut = use_dut(SimulationSettings { crosstalk: vec![vec![0.0, 0.0], vec![0.0, 0.0]], ..Default::default() });
+        assert!(auto_range().is_err());
+    }
+
+    #[test]
+    fn an_external_generator_cant_be_ranged() {
+        let _dut = use_dut(SimulationSettings::default());
+        set_ranging(RangingSettings { enabled: true, ..Default::default() });
+        config_helpers::update(|config| config.capture.external = true).unwrap();
+        assert!(auto_range_if_enabled().is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use std::fs;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{artifact_helpers, audio_helpers};
+use crate::config_helpers::{self, Backend, Config, GoldenSettings, LimitSettings};
+use crate::crosstalk_helpers::Crosstalk;
+use crate::error_helpers::{Error, Result};
+use crate::golden_helpers::{self, GoldenSet};
+use crate::limit_helpers::{self, LimitCheck};
+use crate::loopback_helpers::LoopbackProfile;
+use crate::measurement_helpers::{FileAnalysis, ThdnResult};
+use crate::noise_helpers::NoiseResult;
+use crate::response_helpers::ResponsePoint;
+
+// Identifies the reports, so whatever ingests them can tell them apart from other JSON
+const SCHEMA: &str = "rust-audio-analyser/measurement";
+// Bumped when a field is renamed, removed or changes its meaning (fields can be added without bumping it)
+pub const SCHEMA_VERSION: u32 = 1;
+
+// The unit of every number in a result, by the end of its field name
+// Full scale is a sine's RMS, so a full scale sine is 0 dBFS
+const UNITS: [(&str, &str); 11] = [
+    ("*_dbfs", "dBFS"),
+    ("*_dbv", "dBV"),
+    ("*_db", "dB"),
+    ("thd", "%"),
+    ("harmonics", "dBc, from the 2nd harmonic up"),
+    ("frequency", "Hz"),
+    ("*_frequency", "Hz"),
+    ("sample_rate", "Hz"),
+    ("*_frames", "frames"),
+    ("*_samples", "samples"),
+    ("*_s", "s"),
+];
+
+// A result that can be reported: what its measurement is called and what makes it fail
+pub trait Reportable: Serialize {
+    const MEASUREMENT: &'static str;
+    // Measurements of files don't use the devices, so there's nothing to say about them
+    const USES_DEVICES: bool = true;
+
+    // Why the result fails, empty if it passes (not counting its limits)
+    fn failures(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn check_limits(&self, _limits: &LimitSettings) -> Vec<LimitCheck> {
+        Vec::new()
+    }
+
+    // Only THD+N measurements and responses are kept in a golden set, the rest are left out of it
+    // Returns whether there was anything to keep
+    fn record_golden(&self, _golden: &mut GoldenSet) -> bool {
+        false
+    }
+
+    fn compare_golden(&self, _golden: &GoldenSet, _tolerances: &GoldenSettings) -> Result<Vec<LimitCheck>> {
+        Ok(Vec::new())
+    }
+}
+
+// Everything a test database needs to store a measurement, in a versioned schema
+// The result is None if the measurement failed with an error, which is given instead
+#[derive(Debug, Serialize)]
+pub struct Report<T> {
+    pub schema: &'static str,
+    pub schema_version: u32,
+    pub analyser_version: &'static str,
+    pub measurement: &'static str,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_s: f64,
+    pub config: Config,
+    pub devices: Option<Devices>,
+    // The run directory the WAV, CSV and SVG files were written to, None if they were kept in memory
+    pub artifacts: Option<String>,
+    pub units: Vec<Unit>,
+    pub passed: bool,
+    pub failures: Vec<String>,
+    // Every limit the result was checked against, passed or not
+    pub limits: Vec<LimitCheck>,
+    // The result less the golden unit's, checked against the tolerances
+    pub golden: Vec<LimitCheck>,
+    pub error: Option<Error>,
+    pub result: Option<T>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Devices {
+    pub backend: Backend,
+    pub host: Option<String>,
+    pub input: String,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Unit {
+    pub field: &'static str,
+    pub unit: &'static str,
+}
+
+// Run a measurement and report it
+// - The config and devices are taken before it runs, as some measurements change the config while they run
+// - The result is checked against the config's limits, and any it's outside of are failures too
+// - Then it's saved as the golden unit's, if it passed, or compared with the golden unit's
+// - An error is reported rather than returned, so a measurement that couldn't finish still leaves a record
+//   (devices that can't be opened are the measurement's error, and it isn't run)
+pub fn run<T: Reportable, F: FnOnce() -> Result<T>>(measurement: F) -> Result<Report<T>> {
+    let config = config_helpers::current()?;
+    let devices = if T::USES_DEVICES { devices(&config).map(Some) } else { Ok(None) };
+    let artifacts = artifact_helpers::run_directory(&config.output).map(|directory| directory.to_string_lossy().into_owned());
+    let started_at = Utc::now();
+    let start = Instant::now();
+    let (devices, outcome) = match devices {
+        Ok(devices) => (devices, measurement()),
+        Err(e) => (None, Err(e)),
+    };
+
+    let (mut failures, limits) = match &outcome {
+        Ok(result) => (result.failures(), result.check_limits(&config.limits)),
+        Err(_) => (Vec::new(), Vec::new()),
+    };
+    failures.extend(limits.iter().filter(|check| !check.passed).map(|check| check.to_string()));
+    let golden = match &outcome {
+        Ok(result) => golden(result, &config.golden, failures.is_empty()),
+        Err(_) => Ok(Vec::new()),
+    };
+    // A golden set that can't be read, or doesn't have this measurement, means the DUT can't pass
+    let golden = golden.unwrap_or_else(|e| {
+        failures.push(e.to_string());
+        Vec::new()
+    });
+    failures.extend(golden.iter().filter(|check| !check.passed).map(|check| check.to_string()));
+    let (result, error) = match outcome {
+        Ok(result) => (Some(result), None),
+        Err(e) => (None, Some(e)),
+    };
+    Ok(Report {
+        schema: SCHEMA,
+        schema_version: SCHEMA_VERSION,
+        analyser_version: env!("CARGO_PKG_VERSION"),
+        measurement: T::MEASUREMENT,
+        started_at,
+        finished_at: Utc::now(),
+        duration_s: start.elapsed().as_secs_f64(),
+        config,
+        devices,
+        artifacts,
+        units: UNITS.iter().map(|&(field, unit)| Unit { field, unit }).collect(),
+        passed: error.is_none() && failures.is_empty(),
+        failures,
+        limits,
+        golden,
+        error,
+        result,
+    })
+}
+
+fn golden<T: Reportable>(result: &T, settings: &GoldenSettings, passed: bool) -> Result<Vec<LimitCheck>> {
+    if settings.save {
+        if !passed {
+            fail!(Analysis, "A result that fails isn't saved to the golden set");
+        }
+        let mut golden = GoldenSet::load_or_new(settings.path())?;
+        if result.record_golden(&mut golden) {
+            golden.save(settings.path())?;
+        }
+        Ok(Vec::new())
+    } else if settings.compare {
+        result.compare_golden(&GoldenSet::load(settings.path())?, settings)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+fn devices(config: &Config) -> Result<Devices> {
+    let (input, output) = audio_helpers::device_names()?;
+    Ok(Devices { backend: config.backend, host: config.host.clone(), input, output })
+}
+
+impl<T: Serialize> Report<T> {
+    pub fn write(&self, path: Option<&str>) -> Result<()> {
+        write(self, path)
+    }
+}
+
+// Write a report (or any other JSON) to a file, or to stdout if there isn't one
+pub fn write<T: Serialize>(report: &T, path: Option<&str>) -> Result<()> {
+    let json = serde_json::to_string_pretty(report)?;
+    match path {
+        Some(path) => fs::write(path, json + "\n")?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+// Clipping only fails the measurement itself if it's set to, otherwise it's let through with a warning
+impl Reportable for ThdnResult {
+    const MEASUREMENT: &'static str = "thdn";
+
+    fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        if self.generated_clipping.as_ref().is_some_and(|check| check.clipped()) {
+            failures.push(String::from("The generated signal clipped"));
+        }
+        failures.extend(self.channels.iter()
+            .filter(|channel| channel.clipped)
+            .map(|channel| format!("Input {} clipped", channel.input)));
+        failures
+    }
+
+    fn check_limits(&self, limits: &LimitSettings) -> Vec<LimitCheck> {
+        limit_helpers::check_thdn(self, limits)
+    }
+
+    fn record_golden(&self, golden: &mut GoldenSet) -> bool {
+        golden.record_thdn(self);
+        true
+    }
+
+    fn compare_golden(&self, golden: &GoldenSet, tolerances: &GoldenSettings) -> Result<Vec<LimitCheck>> {
+        golden_helpers::compare_thdn(self, golden, tolerances)
+    }
+}
+
+impl Reportable for Vec<ResponsePoint> {
+    const MEASUREMENT: &'static str = "response";
+
+    fn check_limits(&self, limits: &LimitSettings) -> Vec<LimitCheck> {
+        limit_helpers::check_response(self, limits)
+    }
+
+    fn record_golden(&self, golden: &mut GoldenSet) -> bool {
+        golden.record_response(self);
+        true
+    }
+
+    fn compare_golden(&self, golden: &GoldenSet, tolerances: &GoldenSettings) -> Result<Vec<LimitCheck>> {
+        golden_helpers::compare_response(self, golden, tolerances)
+    }
+}
+
+impl Reportable for Vec<Crosstalk> {
+    const MEASUREMENT: &'static str = "crosstalk";
+
+    fn check_limits(&self, limits: &LimitSettings) -> Vec<LimitCheck> {
+        limit_helpers::check_crosstalk(self, limits)
+    }
+}
+
+impl Reportable for NoiseResult {
+    const MEASUREMENT: &'static str = "noise";
+
+    fn check_limits(&self, limits: &LimitSettings) -> Vec<LimitCheck> {
+        limit_helpers::check_noise(self, limits)
+    }
+}
+
+impl Reportable for LoopbackProfile {
+    const MEASUREMENT: &'static str = "capture-loopback";
+}
+
+impl Reportable for Vec<FileAnalysis> {
+    const MEASUREMENT: &'static str = "analyse";
+    const USES_DEVICES: bool = false;
+
+    fn failures(&self) -> Vec<String> {
+        self.iter()
+            .flat_map(|analysis| analysis.channels.iter().enumerate()
+                .filter(|(_, channel)| channel.peak_frequency.is_none())
+                .map(move |(index, _)| format!("No tone found in channel {} of {}", index + 1, analysis.file)))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_measurement_that_fails_still_leaves_a_report_with_its_error() {
+        let report = run(|| -> Result<Vec<FileAnalysis>> { fail!(File, "recorded.wav is missing") }).unwrap();
+        assert!(!report.passed);
+        assert!(report.devices.is_none());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["measurement"], "analyse");
+        assert_eq!(json["error"]["kind"], "file");
+        assert_eq!(json["error"]["message"], "recorded.wav is missing");
+        assert!(json["result"].is_null());
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::atomic::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error_helpers::Result;
+
+// ISO 1/3 octave centre frequencies across the audio band (31.5 Hz rounded down, as the generator works in whole Hz)
+pub const THIRD_OCTAVE_FREQUENCIES: [usize; 31] = [
+    20, 25, 31, 40, 50, 63, 80, 100, 125, 160, 200, 250, 315, 400, 500, 630,
+    800, 1000, 1250, 1600, 2000, 2500, 3150, 4000, 5000, 6300, 8000, 10000, 12500, 16000, 20000,
+];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ResponsePoint {
+    pub frequency: f64,
+    pub gain_db: f64,
+    pub thd: f64,
+}
+
+// Run a single tone measurement and collect the gain and recorded THD+N
+pub fn measure_at(frequency: usize) -> Result<ResponsePoint> {
+    crate::FREQUENCY.store(frequency, Ordering::SeqCst);
+    crate::clip_helpers::record_checked()?;
+    crate::wav_helpers::calculate_rms()?;
+    crate::fft_helpers::calculate_peak_frequency()?;
+
+    Ok(ResponsePoint {
+        frequency: frequency as f64,
+        gain_db: f64::from_bits(crate::RMS_GAIN.load(Ordering::Relaxed)),
+        thd: f64::from_bits(crate::RECORDED_THD.load(Ordering::Relaxed)),
+    })
+}
+
+// A stepped sine frequency response
+// - Play and record a tone at each frequency in turn
+// - The gain at each step gives the response, the THD+N shows how distortion varies across the band
+// The test frequency is put back afterwards, even if a step fails
+// Auto-ranging (if it's on) is done once at the test frequency, so every step uses the same level
+pub fn measure_response(frequencies: &[usize]) -> Result<Vec<ResponsePoint>> {
+    if crate::config_helpers::current()?.capture.external {
+        fail!(Format, "The frequency response steps the analyser's own generator, it can't be measured in capture-only mode");
+    }
+    crate::range_helpers::auto_range_if_enabled()?;
+    let test_frequency = crate::FREQUENCY.load(Ordering::Relaxed);
+    let response = frequencies.iter().map(|&frequency| measure_at(frequency)).collect();
+    crate::FREQUENCY.store(test_frequency, Ordering::SeqCst);
+    response
+}
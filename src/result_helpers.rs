@@ -0,0 +1,43 @@
+use serde::Serialize;
+
+use crate::error_helpers::Result;
+
+// The results for a single recorded input, labelled with its physical channel number
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChannelResult {
+    pub input: u16,
+    pub level_dbfs: f64,
+    pub gain_db: f64,
+    pub peak_frequency: f32,
+    pub thd: f64,
+    // The 2nd harmonic up, relative to the fundamental (dBc)
+    pub harmonics: Vec<f64>,
+    pub peak_dbfs: f64,
+    pub full_scale_run: usize,
+    pub clipped: bool,
+}
+
+// A new recording makes the previous results meaningless
+pub fn clear_channel_results() {
+    crate::CHANNEL_RESULTS.lock().unwrap().clear();
+}
+
+// Update the results for one recorded channel, adding entries for it (and any before it) as needed
+pub fn update_channel<F: FnOnce(&mut ChannelResult)>(index: usize, input: u16, change: F) {
+    let mut results = crate::CHANNEL_RESULTS.lock().unwrap();
+    if results.len() <= index {
+        results.resize(index + 1, ChannelResult::default());
+    }
+    results[index].input = input;
+    change(&mut results[index]);
+}
+
+pub fn channel_results() -> Vec<ChannelResult> {
+    crate::CHANNEL_RESULTS.lock().unwrap().clone()
+}
+
+// The physical input numbers of the channels in the recording
+pub fn input_labels(recorded_channels: usize) -> Result<Vec<u16>> {
+    let config = crate::config_helpers::current()?;
+    Ok(config.channels.input_labels(recorded_channels))
+}
@@ -0,0 +1,200 @@
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config_helpers::Config;
+use crate::error_helpers::{Error, Result};
+
+// Stimulus files are rendered at this rate unless the stream settings say otherwise
+const DEFAULT_RENDER_SAMPLE_RATE: u32 = 48000;
+
+// A stimulus given as samples (e.g. a NumPy array), played in place of the generator until it's cleared
+static CUSTOM_STIMULUS: Mutex<Option<CustomStimulus>> = Mutex::new(None);
+
+// Anything the generator can play, one sample at a time (full scale is ±1.0)
+// - A source knows how many samples it has left, so players know when it's finished
+// - Sources are pulled from the audio thread, so producing a sample mustn't allocate, lock or block
+pub trait SignalSource: ExactSizeIterator<Item = f32> + Send {}
+
+impl<T: ExactSizeIterator<Item = f32> + Send> SignalSource for T {}
+
+// A sine wave of a fixed length
+pub struct Sine {
+    phase_step: f64,
+    amplitude: f64,
+    position: usize,
+    frames: usize,
+}
+
+impl Sine {
+    pub fn new(frequency: f64, amplitude: f64, sample_rate: u32, length: Duration) -> Sine {
+        Sine {
+            phase_step: frequency * 2.0 * std::f64::consts::PI / sample_rate as f64,
+            amplitude,
+            position: 0,
+            frames: (length.as_secs_f64() * sample_rate as f64).round() as usize,
+        }
+    }
+}
+
+impl Iterator for Sine {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.frames {
+            return None;
+        }
+        let value = (self.position as f64 * self.phase_step).sin() * self.amplitude;
+        self.position += 1;
+        Some(value as f32)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.frames - self.position;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for Sine {}
+
+// The generator's stimulus: a sine at the test frequency and generator level, exactly the given length
+pub fn generator(sample_rate: u32, length: Duration) -> Sine {
+    let frequency = crate::FREQUENCY.load(Ordering::Relaxed) as f64;
+    Sine::new(frequency, crate::level_helpers::amplitude(), sample_rate, length)
+}
+
+// Samples to play as they are (full scale is ±1.0), at the sample rate they were made for
+// The generator level and stimulus length don't apply, the samples are played exactly as given
+#[derive(Debug, Clone)]
+pub struct CustomStimulus {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+// Only Python hands over its own samples to play, the binary always plays the generator's sine
+#[allow(dead_code)]
+pub fn set_custom_stimulus(stimulus: Option<CustomStimulus>) {
+    *CUSTOM_STIMULUS.lock().unwrap() = stimulus;
+}
+
+// The stimulus to play: the custom stimulus if one is set, otherwise the generator's sine
+// A custom stimulus can't be resampled, so it has to be at the stream's sample rate
+pub fn stimulus(sample_rate: u32, length: Duration) -> Result<Box<dyn SignalSource>> {
+    match CUSTOM_STIMULUS.lock().unwrap().as_ref() {
+        Some(custom) if custom.sample_rate != sample_rate => {
+            fail!(Format, "The custom stimulus is at {} Hz, but the stream is at {} Hz", custom.sample_rate, sample_rate)
+        },
+        Some(custom) => Ok(Box::new(custom.samples.clone().into_iter())),
+        None => Ok(Box::new(generator(sample_rate, length))),
+    }
+}
+
+// The sample formats a stimulus file can be written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+impl BitDepth {
+    fn spec(self, channels: u16, sample_rate: u32) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            BitDepth::Int16 => (16, hound::SampleFormat::Int),
+            BitDepth::Int24 => (24, hound::SampleFormat::Int),
+            BitDepth::Int32 => (32, hound::SampleFormat::Int),
+            BitDepth::Float32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec { channels, sample_rate, bits_per_sample, sample_format }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "16" => Ok(BitDepth::Int16),
+            "24" => Ok(BitDepth::Int24),
+            "32" => Ok(BitDepth::Int32),
+            "32f" | "float" => Ok(BitDepth::Float32),
+            _ => fail!(Format, "Unknown bit depth '{}', expected 16, 24, 32 or 32f", s),
+        }
+    }
+}
+
+// Render the stimulus to a WAV file, to be played by something that can't be driven directly
+// - The sample rate comes from the stream settings (48 kHz if none is set)
+// - The file has as many channels as the output stream (mono if none is set),
+//      with the stimulus on the mapped outputs and silence on the rest, the same as it would be played
+// - Integer samples are rounded and kept within full scale
+// Returns the number of frames written
+pub fn render_file(path: &str, config: &Config, bit_depth: BitDepth, length: Duration) -> Result<usize> {
+    let sample_rate = config.stream.sample_rate.unwrap_or(DEFAULT_RENDER_SAMPLE_RATE);
+    let channels = config.stream.output_channels.unwrap_or(1);
+    let outputs = config.channels.output_indices(channels)?;
+    let spec = bit_depth.spec(channels, sample_rate);
+
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    let source = stimulus(sample_rate, length)?;
+    let frames = source.len();
+    for value in source {
+        for channel in 0..channels as usize {
+            let value = if outputs.contains(&channel) { value } else { 0.0 };
+            match bit_depth {
+                BitDepth::Float32 => writer.write_sample(value)?,
+                _ => {
+                    let full_scale = 2f64.powi(spec.bits_per_sample as i32 - 1);
+                    let sample = (value as f64 * full_scale).round().clamp(-full_scale, full_scale - 1.0);
+                    writer.write_sample(sample as i32)?
+                },
+            }
+        }
+    }
+    writer.finalize()?;
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_sine_knows_how_much_is_left() {
+        let mut sine = Sine::new(12000.0, 0.5, 48000, Duration::from_millis(1));
+        assert_eq!(sine.len(), 48);
+        assert_eq!(sine.next(), Some(0.0));
+        assert!((sine.next().unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(sine.len(), 46);
+        assert_eq!(sine.count(), 46);
+    }
+
+    #[test]
+    fn bit_depths_are_parsed() {
+        assert_eq!("24".parse::<BitDepth>().unwrap(), BitDepth::Int24);
+        assert_eq!("Float".parse::<BitDepth>().unwrap(), BitDepth::Float32);
+        assert!("8".parse::<BitDepth>().is_err());
+    }
+
+    #[test]
+    fn a_file_has_the_stimulus_on_the_mapped_outputs_only() {
+        let path = std::env::temp_dir().join(format!("audio_analyser_render_{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut config = Config::default();
+        config.stream.sample_rate = Some(8000);
+        config.stream.output_channels = Some(2);
+        config.channels.outputs = vec![2];
+        let frames = render_file(path, &config, BitDepth::Int16, Duration::from_millis(100)).unwrap();
+
+        let mut reader = hound::WavReader::open(path).unwrap();
+        let spec = reader.spec();
+        let samples: Vec<i32> = reader.samples::<i32>().map(Result::unwrap).collect();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!((spec.channels, spec.sample_rate, spec.bits_per_sample), (2, 8000, 16));
+        assert_eq!(frames, 800);
+        assert_eq!(samples.len(), 1600);
+        assert!(samples.iter().step_by(2).all(|&sample| sample == 0));
+    }
+}
@@ -0,0 +1,349 @@
+use std::time::Duration;
+
+use crate::artifact_helpers;
+use crate::audio_helpers::{self, CaptureReport};
+use crate::backend_helpers::AudioBackend;
+use crate::config_helpers::{Config, SimulationSettings};
+use crate::error_helpers::Result;
+use crate::level_helpers;
+use crate::signal_helpers;
+
+// Used when the stream settings don't say otherwise
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+const DEFAULT_CHANNELS: u16 = 2;
+
+// Plays the stimulus through a virtual DUT instead of real devices, so measurements can be run (and checked) without hardware
+// The reference input, if one is mapped, is looped straight back from the first output without going through the DUT
+// In capture-only mode an external generator is simulated instead, playing the expected stimulus into the DUT
+pub struct SimulatedBackend;
+
+impl AudioBackend for SimulatedBackend {
+    fn device_names(&self, _config: &Config) -> Result<(String, String)> {
+        Ok((String::from("Simulated input"), String::from("Simulated output")))
+    }
+
+    fn capture(&self, config: &Config, length: Duration) -> Result<CaptureReport> {
+        simulate(config, length, false)
+    }
+
+    fn record(&self, config: &Config, length: Duration) -> Result<CaptureReport> {
+        simulate(config, length, true)
+    }
+
+    // Nothing listens to the simulated output, so playing it is just generating it
+    fn play(&self, config: &Config, length: Duration) -> Result<CaptureReport> {
+        let sample_rate = config.stream.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        let stimulus_frames = signal_helpers::stimulus(sample_rate, length)?.len();
+        Ok(CaptureReport { sample_rate, stimulus_frames, played_frames: stimulus_frames, ..CaptureReport::default() })
+    }
+}
+
+// Run the stimulus through the DUT and write the WAV files, as a capture would
+fn simulate(config: &Config, length: Duration, external: bool) -> Result<CaptureReport> {
+    let sample_rate = config.stream.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let output_channels = config.stream.output_channels.unwrap_or(DEFAULT_CHANNELS);
+    let input_channels = config.stream.input_channels.unwrap_or(DEFAULT_CHANNELS);
+    let outputs = config.channels.output_indices(output_channels)?;
+    let inputs = config.channels.input_indices(input_channels)?;
+    let reference = config.channels.reference_index(input_channels)?;
+    eprintln!("Simulated DUT: {} Hz, {} outputs, {} inputs", sample_rate, output_channels, input_channels);
+
+    let stimulus = if external {
+        audio_helpers::expected_stimulus(config, sample_rate, length)?
+    } else {
+        audio_helpers::render_stimulus(sample_rate, length)?
+    };
+    let stimulus_frames = stimulus.len();
+    let tail_frames = audio_helpers::tail_frames(config, sample_rate);
+    let frames = stimulus_frames + tail_frames;
+
+    // What each output channel plays: the stimulus then silence on the mapped outputs, silence on the rest
+    let played: Vec<Vec<f64>> = (0..output_channels as usize)
+        .map(|channel| {
+            let mut signal = vec![0.0; frames];
+            if outputs.contains(&channel) {
+                for (out, &value) in signal.iter_mut().zip(stimulus.iter()) {
+                    *out = value as f64;
+                }
+            }
+            signal
+        })
+        .collect();
+
+    let mut noise = Noise::new(config.simulation.seed);
+    let mut recorded: Vec<Vec<f64>> = (0..input_channels as usize)
+        .map(|channel| run_dut(&config.simulation, &played, channel, sample_rate, &mut noise))
+        .collect();
+    if let (Some(reference), Some(&output)) = (reference, outputs.first()) {
+        recorded[reference] = played[output].clone();
+    }
+
+    // Written as floats, the same as a device recording in f32 would be
+    let spec = |channels| hound::WavSpec { channels, sample_rate, bits_per_sample: 32, sample_format: hound::SampleFormat::Float };
+    let mut writer = artifact_helpers::create_wav(crate::GENERATE_PATH, spec(1))?;
+    for &value in stimulus.iter() {
+        writer.write_sample(value)?;
+    }
+    writer.finalize()?;
+
+    let mut writer = artifact_helpers::create_wav(crate::RECORD_PATH, spec(inputs.len() as u16))?;
+    let interleaved = (0..frames).flat_map(|frame| inputs.iter().map(move |&input| (input, frame)));
+    for (input, frame) in interleaved {
+        writer.write_sample(recorded[input][frame] as f32)?;
+    }
+    writer.finalize()?;
+
+    if let Some(reference) = reference {
+        let mut writer = artifact_helpers::create_wav(crate::REFERENCE_PATH, spec(1))?;
+        for &value in recorded[reference].iter() {
+            writer.write_sample(value as f32)?;
+        }
+        writer.finalize()?;
+    }
+
+    Ok(CaptureReport {
+        sample_rate,
+        stimulus_frames,
+        tail_frames,
+        played_frames: if external { 0 } else { stimulus_frames },
+        recorded_frames: frames,
+        ..CaptureReport::default()
+    })
+}
+
+// Work out what one input channel records, in the order set out in SimulationSettings
+fn run_dut(dut: &SimulationSettings, played: &[Vec<f64>], input: usize, sample_rate: u32, noise: &mut Noise) -> Vec<f64> {
+    let frames = played.first().map_or(0, |signal| signal.len());
+    let crosstalk = |output: usize| match dut.crosstalk.get(input) {
+        Some(row) => row.get(output).copied().unwrap_or(0.0),
+        None if dut.crosstalk.is_empty() && output == input => 1.0,
+        None => 0.0,
+    };
+    let mut signal = vec![0.0; frames];
+    for (output, played) in played.iter().enumerate() {
+        let mix = crosstalk(output);
+        if mix != 0.0 {
+            for (out, value) in signal.iter_mut().zip(played.iter()) {
+                *out += mix * value;
+            }
+        }
+    }
+
+    let gain = level_helpers::dbfs_to_amplitude(dut.gain_db);
+    for value in signal.iter_mut() {
+        *value = polynomial(&dut.polynomial, *value * gain);
+    }
+
+    // First order (RC) filters
+    let dt = 1.0 / sample_rate as f64;
+    if let Some(cutoff) = dut.lowpass_hz {
+        let alpha = dt / (dt + 1.0 / (2.0 * std::f64::consts::PI * cutoff));
+        let mut last = 0.0;
+        for value in signal.iter_mut() {
+            last += alpha * (*value - last);
+            *value = last;
+        }
+    }
+    if let Some(cutoff) = dut.highpass_hz {
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * cutoff);
+        let alpha = rc / (rc + dt);
+        let (mut last_in, mut last_out) = (0.0, 0.0);
+        for value in signal.iter_mut() {
+            let out = alpha * (last_out + *value - last_in);
+            last_in = *value;
+            last_out = out;
+            *value = out;
+        }
+    }
+
+    let delay = (dut.delay_ms * sample_rate as f64 / 1000.0).round() as usize;
+    let mut signal: Vec<f64> = std::iter::repeat_n(0.0, delay.min(frames)).chain(signal).take(frames).collect();
+
+    // The noise level is RMS relative to a full scale sine, the same as the recorded levels
+    let noise_rms = dut.noise_dbfs.map_or(0.0, |noise_dbfs| level_helpers::dbfs_to_amplitude(noise_dbfs) / std::f64::consts::SQRT_2);
+    for value in signal.iter_mut() {
+        *value = (*value + noise_rms * noise.next_gaussian()).clamp(-1.0, 1.0);
+    }
+    signal
+}
+
+// y = c₁x + c₂x² + c₃x³ + ...
+fn polynomial(coefficients: &[f64], x: f64) -> f64 {
+    if coefficients.is_empty() {
+        return x;
+    }
+    coefficients.iter().rev().fold(0.0, |sum, c| (sum + c) * x)
+}
+
+// A small seeded random number generator (SplitMix64), with Gaussian samples from the Box-Muller transform
+struct Noise {
+    state: u64,
+}
+
+impl Noise {
+    fn new(seed: u64) -> Noise {
+        Noise { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in (0, 1]
+    fn next_uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    fn next_gaussian(&mut self) -> f64 {
+        let (u1, u2) = (self.next_uniform(), self.next_uniform());
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::{Mutex, MutexGuard};
+
+    use super::*;
+    use crate::config_helpers::{self, Backend, OutputSettings};
+    use crate::level_helpers::{Level, LevelUnit};
+    use crate::{crosstalk_helpers, measurement_helpers, noise_helpers, response_helpers};
+
+    // The measurements share the config, test frequency and generator level, so the tests take turns
+    static MEASURING: Mutex<()> = Mutex::new(());
+
+    // A stereo DUT with the given settings, measured at 1 kHz and -6 dBFS, keeping the captures in memory
+    // Hold on to the guard until the measurements are done
+    pub(crate) fn use_dut(simulation: SimulationSettings) -> MutexGuard<'static, ()> {
+        let guard = MEASURING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        config_helpers::replace(Config {
+            backend: Backend::Simulated,
+            simulation,
+            output: OutputSettings { in_memory: true, ..Default::default() },
+            ..Default::default()
+        });
+        crate::FREQUENCY.store(1000, Ordering::SeqCst);
+        level_helpers::set_level(Level::new(-6.0, LevelUnit::Dbfs)).unwrap();
+        guard
+    }
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn sine(amplitude: f64, frequency: f64) -> Vec<f64> {
+        (0..SAMPLE_RATE as usize)
+            .map(|frame| amplitude * (2.0 * std::f64::consts::PI * frequency * frame as f64 / SAMPLE_RATE as f64).sin())
+            .collect()
+    }
+
+    fn record(dut: &SimulationSettings, played: &[Vec<f64>], input: usize) -> Vec<f64> {
+        run_dut(dut, played, input, SAMPLE_RATE, &mut Noise::new(dut.seed))
+    }
+
+    fn rms(signal: &[f64]) -> f64 {
+        (signal.iter().map(|value| value * value).sum::<f64>() / signal.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn each_input_hears_its_own_output_unless_there_is_a_crosstalk_matrix() {
+        let played = vec![sine(0.5, 1000.0), sine(0.25, 1000.0)];
+        assert_eq!(record(&SimulationSettings::default(), &played, 1), played[1]);
+
+        let dut = SimulationSettings { crosstalk: vec![vec![1.0, 0.1]], ..Default::default() };
+        let mixed = record(&dut, &played, 0);
+        assert!((mixed[12] - (played[0][12] + 0.1 * played[1][12])).abs() < 1e-12);
+        // Inputs past the end of the matrix don't hear anything
+        assert!(record(&dut, &played, 1).iter().all(|&value| value == 0.0));
+    }
+
+    #[test]
+    fn the_gain_delay_and_polynomial_are_applied_and_the_input_clips() {
+        let played = vec![sine(0.8, 1000.0)];
+        let dut = SimulationSettings { gain_db: 6.0, delay_ms: 1.0, ..Default::default() };
+        let recorded = record(&dut, &played, 0);
+        assert!(recorded[..48].iter().all(|&value| value == 0.0));
+        let gain = level_helpers::dbfs_to_amplitude(6.0);
+        assert!((recorded[52] - gain * played[0][4]).abs() < 1e-12);
+        assert_eq!(recorded.iter().fold(0f64, |peak, value| peak.max(value.abs())), 1.0);
+
+        assert_eq!(polynomial(&[2.0, 0.0, 0.5], 0.5), 2.0 * 0.5 + 0.5 * 0.125);
+    }
+
+    #[test]
+    fn a_low_pass_is_3_db_down_at_its_cutoff() {
+        let dut = SimulationSettings { lowpass_hz: Some(1000.0), ..Default::default() };
+        let recorded = record(&dut, &[sine(0.5, 1000.0)], 0);
+        let gain_db = level_helpers::amplitude_to_dbfs(rms(&recorded[4800..]) / rms(&sine(0.5, 1000.0)));
+        assert!((gain_db + 3.0).abs() < 0.3, "{} dB", gain_db);
+    }
+
+    #[test]
+    fn the_noise_is_at_its_level_and_repeats_with_the_seed() {
+        let dut = SimulationSettings { noise_dbfs: Some(-60.0), seed: 7, ..Default::default() };
+        let silence = vec![vec![0.0; SAMPLE_RATE as usize]];
+        let noise = record(&dut, &silence, 0);
+        let level_dbfs = level_helpers::amplitude_to_dbfs(rms(&noise) * std::f64::consts::SQRT_2);
+        assert!((level_dbfs + 60.0).abs() < 0.2, "{} dBFS", level_dbfs);
+        assert_eq!(record(&dut, &silence, 0), noise);
+    }
+
+    #[test]
+    fn thdn_finds_the_gain_and_distortion() {
+        // A cubic term of c gives a 3rd harmonic of c·A²/4 relative to the fundamental, for a sine of peak A
+        let _dut = use_dut(SimulationSettings { gain_db: -3.0, polynomial: vec![1.0, 0.0, 0.1], ..Default::default() });
+        let result = measurement_helpers::measure_thdn(None).unwrap();
+        let peak = level_helpers::dbfs_to_amplitude(-9.0);
+        let third_harmonic_dbc = level_helpers::amplitude_to_dbfs(0.1 * peak * peak / 4.0);
+        assert_eq!(result.channels.len(), 2);
+        for channel in &result.channels {
+            assert!((channel.gain_db + 3.0).abs() < 0.1, "gain {} dB", channel.gain_db);
+            assert!((channel.level_dbfs + 9.0).abs() < 0.1, "level {} dBFS", channel.level_dbfs);
+            assert!((channel.harmonics[1] - third_harmonic_dbc).abs() < 0.5, "harmonics {:?} dBc", channel.harmonics);
+            assert!(channel.harmonics[0] < third_harmonic_dbc - 20.0, "harmonics {:?} dBc", channel.harmonics);
+            assert!(channel.thd > 0.0 && channel.thd < 1.0, "THD+N {} %", channel.thd);
+            assert!((channel.peak_frequency - 1000.0).abs() < 5.0, "peak at {} Hz", channel.peak_frequency);
+        }
+    }
+
+    #[test]
+    fn response_follows_the_filter() {
+        let _dut = use_dut(SimulationSettings { lowpass_hz: Some(1000.0), ..Default::default() });
+        let response = response_helpers::measure_response(&[100, 1000, 10000]).unwrap();
+        let gains: Vec<f64> = response.iter().map(|point| point.gain_db).collect();
+        // A first order low-pass is 3 dB down at its cutoff and falls 20 dB a decade above it
+        assert!(gains[0].abs() < 0.1, "{:?}", gains);
+        assert!((gains[1] + 3.0).abs() < 0.3, "{:?}", gains);
+        assert!((gains[2] + 20.0).abs() < 1.0, "{:?}", gains);
+        assert_eq!(crate::FREQUENCY.load(Ordering::SeqCst), 1000);
+    }
+
+    #[test]
+    fn crosstalk_follows_the_matrix() {
+        // Input 1 picks up output 2 at -40 dB, input 2 picks up output 1 at -60 dB
+        let _dut = use_dut(SimulationSettings { crosstalk: vec![vec![1.0, 0.01], vec![0.001, 1.0]], ..Default::default() });
+        let crosstalk = crosstalk_helpers::measure_crosstalk().unwrap();
+        assert_eq!(crosstalk.len(), 2);
+        assert_eq!((crosstalk[0].output, crosstalk[0].driven_input), (1, 1));
+        assert_eq!((crosstalk[1].output, crosstalk[1].driven_input), (2, 2));
+        assert!((crosstalk[0].channels[1].crosstalk_db + 60.0).abs() < 0.5, "{:?}", crosstalk[0]);
+        assert!((crosstalk[1].channels[0].crosstalk_db + 40.0).abs() < 0.5, "{:?}", crosstalk[1]);
+        assert_eq!(crosstalk[0].channels[0].crosstalk_db, 0.0);
+    }
+
+    #[test]
+    fn noise_is_measured_at_its_level() {
+        let _dut = use_dut(SimulationSettings { noise_dbfs: Some(-80.0), ..Default::default() });
+        let noise = noise_helpers::measure_noise().unwrap();
+        assert_eq!(noise.channels.len(), 2);
+        for channel in &noise.channels {
+            assert!((channel.level_dbfs + 80.0).abs() < 0.5, "noise {} dBFS", channel.level_dbfs);
+            assert!((channel.snr_db - 80.0).abs() < 0.5, "SNR {} dB", channel.snr_db);
+        }
+        // The generator level is put back afterwards
+        assert!((level_helpers::level_dbfs() + 6.0).abs() < 1e-9);
+    }
+}
@@ -1,40 +1,203 @@
+use std::io::Read;
 use std::sync::atomic::{Ordering};
 
+use crate::error_helpers::Result;
+use crate::{artifact_helpers, result_helpers};
+
+// Channels quieter than this (in dBFS) aren't checked for discontinuities
+const MINIMUM_DISCONTINUITY_LEVEL: f64 = -60.0;
+// How far past the largest step a clean sine can take a jump has to be to count as a discontinuity
+const DISCONTINUITY_HEADROOM: f64 = 2.0;
+
 // To find the RMS gain
-// - Calculate the RMS value of the generated audio
-// - Calculate the RMS value of the recorded audio
+// - Calculate the RMS value of the generated audio (or the reference channel, if one is mapped)
+// - Calculate the RMS value of each recorded channel
 // - Calculate the power between the signals, using the generated audio as the reference
 //      (positive value means amplification, negative means attenuation)
 // - We are interested in the voltage gain, not the power gain hence:
 //      L = 20 × log (voltage ratio V2 / V1) in dB   (V1 = Vin is the reference)
 //      See http://www.sengpielaudio.com/calculator-amplification.htm
-pub fn calculate_rms() {
-    if let Some(generated_rms) = find_rms_value(crate::GENERATE_PATH) {
-        if let Some(recorded_rms) = find_rms_value(crate::RECORD_PATH) {
-            let ratio = recorded_rms/generated_rms;
-            let gain = 20.0 * ratio.log10();
+// A reference channel has the output looped straight back, so it takes the output's own level out of the gain
+pub fn calculate_rms() -> Result<()> {
+    let config = crate::config_helpers::current()?;
+    let reference_path = if config.channels.reference.is_some() { crate::REFERENCE_PATH } else { crate::GENERATE_PATH };
+    let reference = read_stimulus_section(reference_path)?;
+    let reference_rms = find_rms_value(&reference[0]);
+
+    let recorded = read_stimulus_section(crate::RECORD_PATH)?;
+    let inputs = result_helpers::input_labels(recorded.len())?;
+    for (index, (channel, &input)) in recorded.iter().zip(inputs.iter()).enumerate() {
+        let ratio = find_rms_value(channel)/reference_rms;
+        let gain = 20.0 * ratio.log10();
+        // The first channel is also kept as the headline result
+        if index == 0 {
             crate::RMS_GAIN.store(f64::to_bits(gain), Ordering::SeqCst);
         }
+        result_helpers::update_channel(index, input, |result| result.gain_db = gain);
     }
+    Ok(())
 }
 
 // RMS = Root-Mean-Squared
 // - Sqaure each sample
 // - Sum them together
 // - Work out the mean of the final sum
-// - Take the square root 
-fn find_rms_value(filename: &str) -> Option<f64> {
-    let mut reader = hound::WavReader::open(filename).unwrap();
-    let sqr_sum = match reader.spec().sample_format {
-        hound::SampleFormat::Int => reader.samples::<i16>().fold(0.0, |sqr_sum, s| {
-                let sample = s.unwrap() as f64;
-                sqr_sum + sample * sample
-            }),
-        hound::SampleFormat::Float => reader.samples::<f32>().fold(0.0, |sqr_sum, s| {
-                let sample = s.unwrap() as f64;
-                sqr_sum + sample * sample
-            }),
+// - Take the square root
+fn find_rms_value(samples: &[f64]) -> f64 {
+    let sqr_sum = samples.iter().fold(0.0, |sqr_sum, sample| sqr_sum + sample * sample);
+    (sqr_sum / samples.len() as f64).sqrt()
+}
+
+// Store the level of each recorded channel
+pub fn calculate_levels() -> Result<()> {
+    let levels = find_channel_levels(crate::RECORD_PATH)?;
+    let inputs = result_helpers::input_labels(levels.len())?;
+    for (index, (level_dbfs, &input)) in levels.into_iter().zip(inputs.iter()).enumerate() {
+        result_helpers::update_channel(index, input, |result| result.level_dbfs = level_dbfs);
+    }
+    Ok(())
+}
+
+// The level of each channel of one of the capture's WAV files in dBFS
+// - Find the RMS value of each channel (the samples are already relative to full scale)
+// - A full scale sine wave is defined as 0 dBFS, so scale the RMS value up by √2 (the sine's crest factor)
+pub fn find_channel_levels(name: &str) -> Result<Vec<f64>> {
+    let channels = read_stimulus_section(name)?;
+    Ok(channels.iter().map(|channel| level_dbfs(channel)).collect())
+}
+
+pub fn level_dbfs(samples: &[f64]) -> f64 {
+    crate::level_helpers::amplitude_to_dbfs(find_rms_value(samples) * std::f64::consts::SQRT_2)
+}
+
+// A signal can only change so much from one sample to the next, so a bigger step means samples went missing
+// - The generated signal (or the one an external generator is expected to play) sets how big a step can be:
+//      its largest step relative to its peak, 2 × sin(π × f / fs) for a sine, more for sweeps, noise or multitones
+// - Skip channels that are too quiet to tell (they're probably not connected)
+// - Skip the silence before the signal arrives (the DUT's latency)
+// - Anything more than twice the generated signal's largest step (scaled to the recording's peak) is taken as a
+//      discontinuity (the headroom covers distortion and noise)
+// Returns the frames the jumps happen at, for each channel
+pub fn find_discontinuities(name: &str) -> Result<Vec<Vec<usize>>> {
+    let (generated, _) = read_artifact(crate::GENERATE_PATH)?;
+    let step_ratio = generated.first().map_or(0.0, |generated| largest_step_ratio(generated));
+    Ok(read_stimulus_section(name)?.iter().map(|channel| discontinuities(channel, step_ratio)).collect())
+}
+
+// The largest step between samples, relative to the peak (0 for silence)
+fn largest_step_ratio(signal: &[f64]) -> f64 {
+    let peak = signal.iter().fold(0f64, |peak, sample| peak.max(sample.abs()));
+    if peak == 0.0 {
+        return 0.0;
+    }
+    signal.windows(2).fold(0f64, |step, pair| step.max((pair[1] - pair[0]).abs())) / peak
+}
+
+// Nothing can be told from a silent stimulus, e.g. while measuring the noise floor
+fn discontinuities(channel: &[f64], step_ratio: f64) -> Vec<usize> {
+    let peak = channel.iter().fold(0f64, |peak, sample| peak.max(sample.abs()));
+    if step_ratio == 0.0 || crate::level_helpers::amplitude_to_dbfs(peak) < MINIMUM_DISCONTINUITY_LEVEL {
+        return Vec::new();
+    }
+    let threshold = DISCONTINUITY_HEADROOM * step_ratio * peak;
+    let start = channel.iter().position(|sample| sample.abs() > peak / 10.0).unwrap_or(0);
+    channel.windows(2)
+        .enumerate()
+        .skip(start)
+        .filter(|(_, pair)| (pair[1] - pair[0]).abs() > threshold)
+        .map(|(frame, _)| frame + 1)
+        .collect()
+}
+
+// Read one of the capture's WAV files, leaving out the tail after the stimulus so the silence doesn't pull the levels down
+pub fn read_stimulus_section(name: &str) -> Result<Vec<Vec<f64>>> {
+    let stimulus_frames = stimulus_frames()?;
+    let (mut channels, _) = read_artifact(name)?;
+    for channel in channels.iter_mut() {
+        channel.truncate(stimulus_frames);
+    }
+    Ok(channels)
+}
+
+// The generated audio is exactly the stimulus, so its length says where the tail of a recording starts
+pub fn stimulus_frames() -> Result<usize> {
+    Ok(artifact_helpers::open_wav(crate::GENERATE_PATH)?.duration() as usize)
+}
+
+// Read a WAV file and split the interleaved samples back into channels
+// Samples are scaled so that full scale is ±1.0, whatever the file's sample format
+pub fn read_channels(filename: &str) -> Result<(Vec<Vec<f64>>, hound::WavSpec)> {
+    read_wav(hound::WavReader::open(filename)?)
+}
+
+// The same for one of the capture's WAV files, e.g. read_artifact(RECORD_PATH), wherever it's kept
+pub fn read_artifact(name: &str) -> Result<(Vec<Vec<f64>>, hound::WavSpec)> {
+    read_wav(artifact_helpers::open_wav(name)?)
+}
+
+fn read_wav<R: Read>(mut reader: hound::WavReader<R>) -> Result<(Vec<Vec<f64>>, hound::WavSpec)> {
+    let spec = reader.spec();
+    let samples: Vec<f64> = match spec.sample_format {
+        hound::SampleFormat::Int => {
+            let full_scale = 2f64.powi(spec.bits_per_sample as i32 - 1);
+            reader.samples::<i32>().map(|s| Ok(s? as f64 / full_scale)).collect::<Result<_>>()?
+        },
+        hound::SampleFormat::Float => reader.samples::<f32>().map(|s| Ok(s? as f64)).collect::<Result<_>>()?,
     };
-    let rms_value = (sqr_sum / reader.len() as f64).sqrt();
-    Some(rms_value)
+
+    let channel_count = spec.channels as usize;
+    let mut channels = vec![Vec::with_capacity(samples.len() / channel_count); channel_count];
+    for (i, sample) in samples.into_iter().enumerate() {
+        channels[i % channel_count].push(sample);
+    }
+    Ok((channels, spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn sine(frequency: f64, frames: usize) -> Vec<f64> {
+        (0..frames).map(|frame| 0.5 * (2.0 * std::f64::consts::PI * frequency * frame as f64 / SAMPLE_RATE).sin()).collect()
+    }
+
+    // A log sweep from 20 Hz to 20 kHz
+    fn sweep(frames: usize) -> Vec<f64> {
+        let (start, end) = (20.0f64, 20000.0f64);
+        let duration = frames as f64 / SAMPLE_RATE;
+        let rate = (end / start).ln() / duration;
+        (0..frames)
+            .map(|frame| {
+                let t = frame as f64 / SAMPLE_RATE;
+                0.5 * (2.0 * std::f64::consts::PI * start * ((rate * t).exp() - 1.0) / rate).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn missing_samples_in_a_sine_are_found() {
+        let generated = sine(1000.0, 4800);
+        let mut recorded = generated.clone();
+        recorded.drain(1000..1010);
+        let found = discontinuities(&recorded, largest_step_ratio(&generated));
+        assert_eq!(found, vec![1000]);
+        assert!(discontinuities(&generated, largest_step_ratio(&generated)).is_empty());
+    }
+
+    #[test]
+    fn a_sweep_is_compared_with_itself_not_the_test_frequency() {
+        let generated = sweep(48000);
+        let recorded: Vec<f64> = generated.iter().map(|sample| 0.8 * sample).collect();
+        assert!(discontinuities(&recorded, largest_step_ratio(&generated)).is_empty());
+        // The same recording would be full of jumps against a 1 kHz sine
+        assert!(!discontinuities(&recorded, largest_step_ratio(&sine(1000.0, 4800))).is_empty());
+    }
+
+    #[test]
+    fn a_silent_stimulus_is_not_checked() {
+        assert_eq!(largest_step_ratio(&[0.0; 100]), 0.0);
+        assert!(discontinuities(&sine(1000.0, 4800), 0.0).is_empty());
+    }
 }